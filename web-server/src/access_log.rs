@@ -0,0 +1,165 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+/// A field that can be included in the access log
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogField {
+    Method,
+    Path,
+    Status,
+    Duration,
+    Bytes,
+    Peer,
+    RequestId,
+    UserAgent,
+    Referer,
+    RequestNumber,
+}
+
+impl LogField {
+    /// Every field, in the order they are logged by default
+    pub const ALL: [LogField; 10] = [
+        LogField::Method,
+        LogField::Path,
+        LogField::Status,
+        LogField::Duration,
+        LogField::Bytes,
+        LogField::Peer,
+        LogField::RequestId,
+        LogField::UserAgent,
+        LogField::Referer,
+        LogField::RequestNumber,
+    ];
+}
+
+/// The StructuredLogEntry struct
+///
+/// The StructuredLogEntry struct describes one answered request, logged as a JSON line
+///
+/// # Fields
+///
+/// * `method` - The request method
+/// * `path` - The request path
+/// * `status` - The response status code
+/// * `duration` - How long the request took, from reading its headers to writing the response
+/// * `bytes` - The number of body bytes sent
+/// * `peer` - The client address
+/// * `request_id` - The `X-Request-Id` sent by the client
+/// * `user_agent` - The `User-Agent` sent by the client
+/// * `referer` - The `Referer` sent by the client
+/// * `request_number` - Which request this is on its connection, counting from 1
+///
+pub struct StructuredLogEntry<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub status: u16,
+    pub duration: Duration,
+    pub bytes: u64,
+    pub peer: &'a str,
+    pub request_id: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub referer: Option<&'a str>,
+    pub request_number: usize,
+}
+
+impl StructuredLogEntry<'_> {
+    /// Serialize the chosen fields, in the order given, as a single line JSON object
+    ///
+    /// Headers the client didn't send are `null`
+    ///
+    pub fn to_json(&self, fields: &[LogField]) -> String {
+        let members: Vec<String> = fields
+            .iter()
+            .map(|field| {
+                let (name, value) = match field {
+                    LogField::Method => ("method", json_string(self.method)),
+                    LogField::Path => ("path", json_string(self.path)),
+                    LogField::Status => ("status", self.status.to_string()),
+                    LogField::Duration => (
+                        "duration_ms",
+                        format!("{:.3}", self.duration.as_secs_f64() * 1000.0),
+                    ),
+                    LogField::Bytes => ("bytes", self.bytes.to_string()),
+                    LogField::Peer => ("peer", json_string(self.peer)),
+                    LogField::RequestId => ("request_id", json_optional(self.request_id)),
+                    LogField::UserAgent => ("user_agent", json_optional(self.user_agent)),
+                    LogField::Referer => ("referer", json_optional(self.referer)),
+                    LogField::RequestNumber => ("request_number", self.request_number.to_string()),
+                };
+                format!("\"{}\":{}", name, value)
+            })
+            .collect();
+
+        format!("{{{}}}", members.join(","))
+    }
+}
+
+fn json_optional(value: Option<&str>) -> String {
+    value
+        .map(json_string)
+        .unwrap_or_else(|| String::from("null"))
+}
+
+/// Quote and escape a string for JSON
+pub fn json_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> StructuredLogEntry<'static> {
+        StructuredLogEntry {
+            method: "GET",
+            path: "/about",
+            status: 200,
+            duration: Duration::from_micros(1500),
+            bytes: 253,
+            peer: "127.0.0.1",
+            request_id: None,
+            user_agent: Some("curl/8.0 \"test\""),
+            referer: None,
+            request_number: 2,
+        }
+    }
+
+    #[test]
+    fn test_configured_fields_only() {
+        let json = entry().to_json(&[
+            LogField::Method,
+            LogField::Status,
+            LogField::Duration,
+            LogField::UserAgent,
+            LogField::RequestId,
+            LogField::RequestNumber,
+        ]);
+
+        assert_eq!(
+            json,
+            "{\"method\":\"GET\",\"status\":200,\"duration_ms\":1.500,\
+             \"user_agent\":\"curl/8.0 \\\"test\\\"\",\"request_id\":null,\"request_number\":2}"
+        );
+        assert!(!json.contains("path"));
+        assert!(!json.contains("peer"));
+    }
+
+    #[test]
+    fn test_json_string_escapes_control_characters() {
+        assert_eq!(json_string("a\u{1}b\\"), "\"a\\u0001b\\\\\"");
+    }
+}
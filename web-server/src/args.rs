@@ -0,0 +1,211 @@
+use std::{ffi::OsString, fmt, path::PathBuf, time::Duration};
+
+/// The address listened on without `--addr`
+const DEFAULT_ADDR: &str = "127.0.0.1:7878";
+
+/// The number of worker threads without `--workers`
+const DEFAULT_WORKERS: usize = 4;
+
+/// The Args struct
+///
+/// The Args struct holds the command line arguments:
+/// `web-server [--addr HOST:PORT] [--workers N] [--read-timeout SECONDS] [--set KEY=VALUE]... [CONFIG]`
+///
+/// # Fields
+///
+/// * `config` - The configuration file to load
+/// * `addr` - The address to listen on
+/// * `workers` - The number of worker threads serving connections
+/// * `read_timeout` - Replaces the configured `header_read_timeout`
+/// * `overrides` - Settings replacing the configured ones, see `ServerConfig::from_toml`
+///
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    pub addr: String,
+    pub workers: usize,
+    pub read_timeout: Option<Duration>,
+    pub overrides: Vec<(String, String)>,
+}
+
+/// The reasons the command line could not be understood
+#[derive(Debug, PartialEq)]
+pub enum ArgsError {
+    /// A flag was given without its value
+    MissingValue(String),
+    /// The `--addr` value isn't a host and port
+    InvalidAddr(String),
+    /// The `--workers` value isn't a positive number
+    InvalidWorkers(String),
+    /// The `--read-timeout` value isn't a positive number of seconds
+    InvalidTimeout(String),
+    /// The `--set` value isn't a key and a value
+    InvalidOverride(String),
+    /// An argument that isn't a known flag or the configuration file
+    Unexpected(String),
+}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgsError::MissingValue(flag) => write!(f, "{} needs a value", flag),
+            ArgsError::InvalidAddr(addr) => {
+                write!(f, "invalid address {:?}, expected HOST:PORT", addr)
+            }
+            ArgsError::InvalidWorkers(workers) => {
+                write!(f, "invalid worker count {:?}, expected at least 1", workers)
+            }
+            ArgsError::InvalidTimeout(timeout) => {
+                write!(f, "invalid timeout {:?}, expected seconds above 0", timeout)
+            }
+            ArgsError::InvalidOverride(setting) => {
+                write!(f, "invalid setting {:?}, expected KEY=VALUE", setting)
+            }
+            ArgsError::Unexpected(arg) => write!(f, "unexpected argument {:?}", arg),
+        }
+    }
+}
+
+impl Args {
+    /// Parse the arguments following the program name
+    pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Self, ArgsError> {
+        let mut parsed = Args {
+            config: None,
+            addr: String::from(DEFAULT_ADDR),
+            workers: DEFAULT_WORKERS,
+            read_timeout: None,
+            overrides: Vec::new(),
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let text = arg.to_string_lossy().into_owned();
+            let (flag, inline) = match text.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+                _ => (text.as_str(), None),
+            };
+            let mut value = || match &inline {
+                Some(value) => Ok(value.clone()),
+                None => args
+                    .next()
+                    .map(|value| value.to_string_lossy().into_owned())
+                    .ok_or_else(|| ArgsError::MissingValue(flag.to_string())),
+            };
+
+            match flag {
+                "--addr" => parsed.addr = parse_addr(value()?)?,
+                "--workers" => parsed.workers = parse_workers(value()?)?,
+                "--read-timeout" => parsed.read_timeout = Some(parse_timeout(value()?)?),
+                "--set" => parsed.overrides.push(parse_override(value()?)?),
+                _ if flag.starts_with("--") || parsed.config.is_some() => {
+                    return Err(ArgsError::Unexpected(text));
+                }
+                _ => parsed.config = Some(PathBuf::from(arg)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Check that an address ends in a valid port, the host is resolved when binding
+fn parse_addr(addr: String) -> Result<String, ArgsError> {
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(addr),
+        _ => Err(ArgsError::InvalidAddr(addr)),
+    }
+}
+
+fn parse_workers(workers: String) -> Result<usize, ArgsError> {
+    match workers.parse() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(ArgsError::InvalidWorkers(workers)),
+    }
+}
+
+/// Parse a number of seconds, fractions allowed as in the configuration file
+fn parse_timeout(timeout: String) -> Result<Duration, ArgsError> {
+    match timeout.parse::<f64>().map(Duration::try_from_secs_f64) {
+        Ok(Ok(duration)) if !duration.is_zero() => Ok(duration),
+        _ => Err(ArgsError::InvalidTimeout(timeout)),
+    }
+}
+
+fn parse_override(setting: String) -> Result<(String, String), ArgsError> {
+    match setting.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(ArgsError::InvalidOverride(setting)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, ArgsError> {
+        Args::parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn test_defaults() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.config, None);
+        assert_eq!(args.addr, "127.0.0.1:7878");
+        assert_eq!(args.workers, 4);
+        assert_eq!(args.read_timeout, None);
+        assert!(args.overrides.is_empty());
+    }
+
+    #[test]
+    fn test_flags_and_config() {
+        let args = parse(&["--addr", "0.0.0.0:8080", "--workers=16", "server.toml"]).unwrap();
+        assert_eq!(args.config, Some(PathBuf::from("server.toml")));
+        assert_eq!(args.addr, "0.0.0.0:8080");
+        assert_eq!(args.workers, 16);
+
+        let args = parse(&["--read-timeout", "2.5"]).unwrap();
+        assert_eq!(args.read_timeout, Some(Duration::from_millis(2500)));
+
+        let args = parse(&["--set", "keep_alive=false", "--set=api_key=a=b"]).unwrap();
+        assert_eq!(
+            args.overrides,
+            [("keep_alive", "false"), ("api_key", "a=b")]
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        assert_eq!(
+            parse(&["--addr", "0.0.0.0:99999"]),
+            Err(ArgsError::InvalidAddr(String::from("0.0.0.0:99999")))
+        );
+        assert_eq!(
+            parse(&["--addr", "8080"]),
+            Err(ArgsError::InvalidAddr(String::from("8080")))
+        );
+        assert_eq!(
+            parse(&["--workers", "0"]),
+            Err(ArgsError::InvalidWorkers(String::from("0")))
+        );
+        assert_eq!(
+            parse(&["--workers"]),
+            Err(ArgsError::MissingValue(String::from("--workers")))
+        );
+        for timeout in ["0", "-1", "soon"] {
+            assert_eq!(
+                parse(&["--read-timeout", timeout]),
+                Err(ArgsError::InvalidTimeout(String::from(timeout)))
+            );
+        }
+        for setting in ["keep_alive", "=false"] {
+            assert_eq!(
+                parse(&["--set", setting]),
+                Err(ArgsError::InvalidOverride(String::from(setting)))
+            );
+        }
+        assert_eq!(
+            parse(&["a.toml", "b.toml"]),
+            Err(ArgsError::Unexpected(String::from("b.toml")))
+        );
+    }
+}
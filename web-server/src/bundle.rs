@@ -0,0 +1,72 @@
+use std::{fs, io, path::Path};
+
+use crate::{config::BundleConfig, etag, mime, response::Response};
+
+/// Build the response for a bundle by concatenating its files in order
+///
+/// The `Content-Type` comes from the bundle's own path and the strong `ETag` is a
+/// hash of the combined contents
+///
+/// # Arguments
+///
+/// * `root` - The directory the bundle's files are relative to
+/// * `bundle` - The bundle to build
+///
+pub fn build(root: &Path, bundle: &BundleConfig) -> io::Result<Response> {
+    let mut contents = Vec::new();
+    for file in &bundle.files {
+        if !contents.is_empty() && !contents.ends_with(b"\n") {
+            contents.push(b'\n');
+        }
+        contents.extend(fs::read(root.join(file))?);
+    }
+
+    let content_type = mime::content_type(Path::new(&bundle.path), &contents, false);
+    Ok(Response::new()
+        .header("Content-Type", content_type)
+        .header("ETag", &etag::strong(&contents))
+        .body(contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_pages::TempPages;
+
+    #[test]
+    fn test_bundle_contains_parts_in_order() {
+        let root = TempPages::new("bundle");
+        root.write("css/reset.css", "* { margin: 0; }");
+        root.write("css/theme.css", "body { color: #333; }\n");
+        root.write("print.css", "@media print { nav { display: none; } }\n");
+
+        let bundle = BundleConfig {
+            path: String::from("/bundle.css"),
+            files: vec![
+                String::from("css/reset.css"),
+                String::from("css/theme.css"),
+                String::from("print.css"),
+            ],
+        };
+        let response = build(&root, &bundle).unwrap();
+        let reordered = build(
+            &root,
+            &BundleConfig {
+                files: bundle.files.iter().rev().cloned().collect(),
+                ..bundle.clone()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            response.get_body().unwrap(),
+            b"* { margin: 0; }\nbody { color: #333; }\n@media print { nav { display: none; } }\n"
+        );
+        assert_eq!(
+            response.get_header("Content-Type"),
+            Some("text/css; charset=utf-8")
+        );
+        assert_eq!(response.get_header("ETag").unwrap().len(), 42);
+        assert_ne!(response.get_header("ETag"), reordered.get_header("ETag"));
+    }
+}
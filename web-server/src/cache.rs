@@ -0,0 +1,493 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{compression, disk_cache::DiskCache, request::Request, response::Response};
+
+/// The ResponseCache struct
+///
+/// The ResponseCache struct keeps fully rendered responses in memory, keyed by the
+/// request inputs that can change them: method, path, the codings `Accept-Encoding`
+/// allows, and any request headers named in the response's `Vary` header. Once the
+/// cached bodies exceed `max_bytes` the least recently used entries are evicted.
+/// Responses served from the cache carry an `Age` header with the seconds since they
+/// were stored.
+///
+/// With a max age, entries older than it are stale. A stale entry is still served for
+/// the `stale_while_revalidate` window after that, and the first request to get it is
+/// told to refresh it, after which it is dropped.
+///
+/// With a disk tier, evicted entries and ones too large for memory are written to
+/// disk, and a request missing in memory is looked up there before giving up.
+///
+/// # Fields
+///
+/// * `max_bytes` - The size bound for cached bodies, zero disables the in-memory tier
+/// * `state` - The entries and bookkeeping, behind a lock
+/// * `hits` - How many requests were served from the cache
+/// * `disk` - The optional disk tier
+/// * `max_age` - How long entries are fresh, without it they never go stale
+/// * `stale_while_revalidate` - How long stale entries are still served
+///
+pub struct ResponseCache {
+    max_bytes: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicUsize,
+    disk: Option<DiskCache>,
+    max_age: Option<Duration>,
+    stale_while_revalidate: Duration,
+}
+
+/// The CacheHit struct
+///
+/// The CacheHit struct holds a response served from the cache
+///
+/// # Fields
+///
+/// * `response` - The cached response
+/// * `refresh` - Whether the response is stale and the caller should render it again,
+///   only set for the first request to get a stale entry
+///
+pub struct CacheHit {
+    pub response: Response,
+    pub refresh: bool,
+}
+
+enum Freshness {
+    Fresh,
+    Stale,
+    Expired,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    // The `Vary` header names last seen for each path, needed to build its keys
+    vary: HashMap<String, Vec<String>>,
+    size: usize,
+    clock: u64,
+    // The keys by when they were last used, the least recently used first
+    recency: BTreeMap<u64, String>,
+}
+
+struct CacheEntry {
+    response: Response,
+    last_used: u64,
+    inserted: Instant,
+    // Set once a request has been asked to refresh the stale entry
+    refreshing: bool,
+}
+
+impl ResponseCache {
+    /// Create a new cache holding at most `max_bytes` of response bodies
+    pub fn new(max_bytes: usize) -> Self {
+        ResponseCache {
+            max_bytes,
+            state: Mutex::new(CacheState::default()),
+            hits: AtomicUsize::new(0),
+            disk: None,
+            max_age: None,
+            stale_while_revalidate: Duration::ZERO,
+        }
+    }
+
+    /// Add a disk tier behind the in-memory one
+    pub fn with_disk(mut self, disk: DiskCache) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    /// Let entries go stale after `max_age`, serving them for `stale_while_revalidate`
+    /// longer while they are refreshed
+    pub fn with_max_age(mut self, max_age: Duration, stale_while_revalidate: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self.stale_while_revalidate = stale_while_revalidate;
+        self
+    }
+
+    /// Look up the cached response for a request, in memory and then on disk
+    ///
+    /// A response found on disk is moved back into memory. Entries past the stale
+    /// window are dropped instead of being served.
+    ///
+    pub fn get(&self, request: &Request) -> Option<CacheHit> {
+        if (self.max_bytes == 0 && self.disk.is_none()) || request.method != "GET" {
+            return None;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let vary = state.vary.get(&request.path).cloned().unwrap_or_default();
+        let key = cache_key(request, &vary);
+
+        let (response, inserted, refresh) = match state.touch(&key) {
+            Some(entry) => {
+                let refresh = match self.freshness(entry.inserted) {
+                    Freshness::Fresh => false,
+                    Freshness::Stale => !std::mem::replace(&mut entry.refreshing, true),
+                    Freshness::Expired => {
+                        state.remove(&key);
+                        return None;
+                    }
+                };
+                (entry.response.try_clone()?, entry.inserted, refresh)
+            }
+            None => {
+                drop(state);
+                let (response, stored) = self.disk.as_ref()?.get(&key)?;
+                let age = stored.elapsed().unwrap_or_default();
+                let inserted = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+                let refresh = match self.freshness(inserted) {
+                    Freshness::Fresh => false,
+                    Freshness::Stale => true,
+                    Freshness::Expired => return None,
+                };
+                // One too large for memory stays where it is instead of being spilled again
+                if response.content_length() as usize <= self.max_bytes {
+                    self.store(key, response.try_clone()?, inserted, refresh);
+                }
+                (response, inserted, refresh)
+            }
+        };
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        let age = inserted.elapsed().as_secs();
+        Some(CacheHit {
+            response: response.header("Age", &age.to_string()),
+            refresh,
+        })
+    }
+
+    fn freshness(&self, inserted: Instant) -> Freshness {
+        let max_age = match self.max_age {
+            Some(max_age) => max_age,
+            None => return Freshness::Fresh,
+        };
+        let age = inserted.elapsed();
+        if age <= max_age {
+            Freshness::Fresh
+        } else if age <= max_age + self.stale_while_revalidate {
+            Freshness::Stale
+        } else {
+            Freshness::Expired
+        }
+    }
+
+    /// Store the response rendered for a request
+    ///
+    /// Only successful `GET` responses that fit in the cache are stored, streamed
+    /// bodies never are
+    ///
+    pub fn insert(&self, request: &Request, response: &Response) {
+        if request.method != "GET" || response.status_code() != 200 {
+            return;
+        }
+        let response = match response.try_clone() {
+            Some(response) => response,
+            None => return,
+        };
+
+        let vary: Vec<String> = response
+            .get_header("Vary")
+            .map(|v| {
+                v.split(',')
+                    .map(|h| h.trim().to_ascii_lowercase())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let key = cache_key(request, &vary);
+
+        self.state
+            .lock()
+            .unwrap()
+            .vary
+            .insert(request.path.clone(), vary);
+        self.store(key, response, Instant::now(), false);
+    }
+
+    /// Put a response in memory, moving what doesn't fit there to the disk tier
+    fn store(&self, key: String, response: Response, inserted: Instant, refreshing: bool) {
+        let size = response.content_length() as usize;
+        if size > self.max_bytes {
+            self.spill(&key, &response, inserted);
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let last_used = state.clock;
+        state.recency.insert(last_used, key.clone());
+        let entry = CacheEntry {
+            response,
+            last_used,
+            inserted,
+            refreshing,
+        };
+        if let Some(old) = state.entries.insert(key, entry) {
+            state.size -= old.response.content_length() as usize;
+            state.recency.remove(&old.last_used);
+        }
+        state.size += size;
+
+        let mut evicted = Vec::new();
+        while state.size > self.max_bytes {
+            evicted.extend(state.evict_oldest());
+        }
+        drop(state);
+
+        for (key, entry) in evicted {
+            self.spill(&key, &entry.response, entry.inserted);
+        }
+    }
+
+    /// Write a response to the disk tier, if there is one
+    fn spill(&self, key: &str, response: &Response, inserted: Instant) {
+        if let Some(disk) = &self.disk {
+            let stored = SystemTime::now() - inserted.elapsed();
+            disk.insert(key, response, stored);
+        }
+    }
+
+    /// Let the next request for a stale entry refresh it, once the refresh it was
+    /// handed to couldn't run
+    pub fn cancel_refresh(&self, request: &Request) {
+        let mut state = self.state.lock().unwrap();
+        let vary = state.vary.get(&request.path).cloned().unwrap_or_default();
+        if let Some(entry) = state.entries.get_mut(&cache_key(request, &vary)) {
+            entry.refreshing = false;
+        }
+    }
+
+    /// Remove every entry, e.g. once the pages they were rendered from may have changed
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.recency.clear();
+        state.vary.clear();
+        state.size = 0;
+        drop(state);
+
+        if let Some(disk) = &self.disk {
+            disk.clear();
+        }
+    }
+
+    /// Get the number of requests served from the cache
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+impl CacheState {
+    /// Get an entry, marking it as the most recently used
+    fn touch(&mut self, key: &str) -> Option<&mut CacheEntry> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.last_used);
+        entry.last_used = self.clock;
+        self.recency.insert(self.clock, key.to_string());
+        Some(entry)
+    }
+
+    fn evict_oldest(&mut self) -> Option<(String, CacheEntry)> {
+        let (_, oldest) = self.recency.pop_first()?;
+        let entry = self.entries.remove(&oldest)?;
+        self.size -= entry.response.content_length() as usize;
+        Some((oldest, entry))
+    }
+
+    fn remove(&mut self, key: &str) -> Option<CacheEntry> {
+        let entry = self.entries.remove(key)?;
+        self.size -= entry.response.content_length() as usize;
+        self.recency.remove(&entry.last_used);
+        Some(entry)
+    }
+}
+
+/// The content codings a cached response may be compressed with
+pub const CACHED_ENCODINGS: [&str; 2] = ["br", "gzip"];
+
+/// Build the cache key from the request inputs that select a response
+///
+/// `Accept-Encoding` is reduced to the codings in `CACHED_ENCODINGS` the client
+/// accepts, so the many ways of writing the same preference share one entry
+///
+fn cache_key(request: &Request, vary: &[String]) -> String {
+    let encodings: Vec<&str> = CACHED_ENCODINGS
+        .into_iter()
+        .filter(|encoding| compression::accepts_encoding(request, encoding))
+        .collect();
+    let mut key = format!(
+        "{} {}\naccept-encoding: {}",
+        request.method,
+        request.path,
+        encodings.join(", ")
+    );
+    for name in vary.iter().filter(|name| *name != "accept-encoding") {
+        key.push_str(&format!(
+            "\n{}: {}",
+            name,
+            request.header(name).unwrap_or("")
+        ));
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(path: &str) -> Request {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        Request::parse_head(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = ResponseCache::new(10);
+        cache.insert(&get("/a"), &Response::new().body(vec![0; 4]));
+        cache.insert(&get("/b"), &Response::new().body(vec![0; 4]));
+        assert!(cache.get(&get("/a")).is_some());
+
+        cache.insert(&get("/c"), &Response::new().body(vec![0; 4]));
+        assert!(cache.get(&get("/a")).is_some());
+        assert!(cache.get(&get("/b")).is_none());
+        assert!(cache.get(&get("/c")).is_some());
+    }
+
+    #[test]
+    fn test_vary_header_is_part_of_key() {
+        let cache = ResponseCache::new(100);
+        let english =
+            Request::parse_head(&mut "GET / HTTP/1.1\r\nAccept-Language: en\r\n\r\n".as_bytes())
+                .unwrap();
+        let french =
+            Request::parse_head(&mut "GET / HTTP/1.1\r\nAccept-Language: fr\r\n\r\n".as_bytes())
+                .unwrap();
+
+        cache.insert(&english, &Response::new().header("Vary", "Accept-Language"));
+        assert!(cache.get(&english).is_some());
+        assert!(cache.get(&french).is_none());
+    }
+
+    #[test]
+    fn test_accept_encoding_normalized() {
+        let cache = ResponseCache::new(100);
+        let with = |accept_encoding: &str| {
+            let raw = format!(
+                "GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+                accept_encoding
+            );
+            Request::parse_head(&mut raw.as_bytes()).unwrap()
+        };
+        let response = Response::new().header("Vary", "Accept-Encoding");
+
+        cache.insert(&with("gzip, deflate"), &response);
+        assert!(cache.get(&with("deflate, gzip;q=0.5")).is_some());
+        assert!(cache.get(&with("GZIP, br;q=0")).is_some());
+        assert!(cache.get(&with("gzip, br")).is_none());
+        assert!(cache.get(&with("identity")).is_none());
+    }
+
+    #[test]
+    fn test_reloads_evicted_from_disk() {
+        let dir = std::env::temp_dir().join(format!("web-server-tiers-{}", std::process::id()));
+        let cache = ResponseCache::new(10).with_disk(DiskCache::new(dir.clone(), 1024).unwrap());
+        cache.insert(&get("/a"), &Response::new().body(b"aaaa".to_vec()));
+        cache.insert(&get("/b"), &Response::new().body(vec![0; 4]));
+        cache.insert(&get("/c"), &Response::new().body(vec![0; 4]));
+        assert_eq!(cache.state.lock().unwrap().entries.len(), 2);
+
+        let response = cache.get(&get("/a")).unwrap().response;
+        assert_eq!(response.get_body(), Some(&b"aaaa"[..]));
+        assert_eq!(cache.hits(), 1);
+        // Moved back into memory, evicting `/b` to disk in turn
+        assert!(cache.get(&get("/b")).is_some());
+        assert!(cache.get(&get("/d")).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_hits_not_spilled_again() {
+        let dir = std::env::temp_dir().join(format!("web-server-disk-only-{}", std::process::id()));
+        let cache = ResponseCache::new(0).with_disk(DiskCache::new(dir.clone(), 1024).unwrap());
+        cache.insert(&get("/a"), &Response::new().body(b"aaaa".to_vec()));
+        let files = || {
+            let mut files: Vec<_> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            files.sort();
+            files
+        };
+        let written = files();
+        assert_eq!(written.len(), 1);
+
+        for _ in 0..3 {
+            assert!(cache.get(&get("/a")).is_some());
+        }
+        assert_eq!(files(), written);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_age_header() {
+        let cache = ResponseCache::new(100);
+        cache.insert(&get("/a"), &Response::new().body(b"a".to_vec()));
+        let age = |hit: CacheHit| {
+            let age = hit.response.get_header("Age").unwrap();
+            age.parse::<u64>().unwrap()
+        };
+
+        let first = age(cache.get(&get("/a")).unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        let second = age(cache.get(&get("/a")).unwrap());
+        assert_eq!(first, 0);
+        assert!(second > first);
+    }
+
+    #[test]
+    fn test_serves_stale_while_revalidating() {
+        let cache = ResponseCache::new(100).with_max_age(Duration::ZERO, Duration::from_secs(60));
+        cache.insert(&get("/a"), &Response::new().body(b"old".to_vec()));
+        std::thread::sleep(Duration::from_millis(10));
+
+        // Only the first request for the stale entry is asked to refresh it
+        let first = cache.get(&get("/a")).unwrap();
+        assert_eq!(first.response.get_body(), Some(&b"old"[..]));
+        assert!(first.refresh);
+        let second = cache.get(&get("/a")).unwrap();
+        assert_eq!(second.response.get_body(), Some(&b"old"[..]));
+        assert!(!second.refresh);
+
+        cache.insert(&get("/a"), &Response::new().body(b"new".to_vec()));
+        let refreshed = cache.get(&get("/a")).unwrap();
+        assert_eq!(refreshed.response.get_body(), Some(&b"new"[..]));
+    }
+
+    #[test]
+    fn test_cancel_refresh() {
+        let cache = ResponseCache::new(100).with_max_age(Duration::ZERO, Duration::from_secs(60));
+        cache.insert(&get("/a"), &Response::new().body(b"old".to_vec()));
+        std::thread::sleep(Duration::from_millis(10));
+
+        assert!(cache.get(&get("/a")).unwrap().refresh);
+        cache.cancel_refresh(&get("/a"));
+        assert!(cache.get(&get("/a")).unwrap().refresh);
+        assert!(!cache.get(&get("/a")).unwrap().refresh);
+    }
+
+    #[test]
+    fn test_drops_entries_past_stale_window() {
+        let cache = ResponseCache::new(100).with_max_age(Duration::ZERO, Duration::ZERO);
+        cache.insert(&get("/a"), &Response::new().body(b"a".to_vec()));
+        std::thread::sleep(Duration::from_millis(10));
+
+        assert!(cache.get(&get("/a")).is_none());
+        assert_eq!(cache.state.lock().unwrap().size, 0);
+    }
+}
@@ -0,0 +1,317 @@
+use brotli::CompressorWriter;
+use flate2::{write::GzEncoder, Compression};
+use serde::Deserialize;
+use std::io::Write;
+
+use crate::{etag, mime, request::Request, response::Response};
+
+/// The content types compressed by default
+///
+/// Formats that are already compressed, such as PNG, JPEG and woff2, are left out
+/// since recompressing them costs time without saving bytes
+///
+pub const DEFAULT_COMPRESSIBLE_TYPES: [&str; 4] = [
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "image/svg+xml",
+];
+
+/// A way of sending a compressed body, tried in the order of `compression_chain`
+///
+/// * `BrSibling` - The file's precompressed `.br` sibling
+/// * `GzipSibling` - The file's precompressed `.gz` sibling
+/// * `Br` - The body compressed with brotli on the fly, see `compress`
+/// * `Gzip` - The body gzipped on the fly, see `compress`
+///
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionStep {
+    BrSibling,
+    GzipSibling,
+    Br,
+    Gzip,
+}
+
+impl CompressionStep {
+    /// Get the content coding the step sends
+    pub fn encoding(self) -> &'static str {
+        match self {
+            CompressionStep::BrSibling | CompressionStep::Br => "br",
+            CompressionStep::GzipSibling | CompressionStep::Gzip => "gzip",
+        }
+    }
+
+    /// Whether the step serves a precompressed sibling file
+    pub fn is_sibling(self) -> bool {
+        matches!(
+            self,
+            CompressionStep::BrSibling | CompressionStep::GzipSibling
+        )
+    }
+}
+
+/// The steps tried by default, smallest result first
+pub const DEFAULT_COMPRESSION_CHAIN: [CompressionStep; 4] = [
+    CompressionStep::BrSibling,
+    CompressionStep::GzipSibling,
+    CompressionStep::Br,
+    CompressionStep::Gzip,
+];
+
+/// The brotli quality used on the fly, out of 11, which trades some size for speed
+const BROTLI_QUALITY: u32 = 5;
+
+/// Pick how to compress a response, the first step of `chain` the client accepts
+///
+/// This is the one place a step is chosen, the caller serves the sibling or passes
+/// the step on to `compress`. Sibling steps are skipped when `has_sibling` says the
+/// file has no sibling in their coding, e.g. for responses that aren't files, and
+/// `None` means the body is sent as it is.
+///
+pub fn choose(
+    chain: &[CompressionStep],
+    request: &Request,
+    has_sibling: impl Fn(&str) -> bool,
+) -> Option<CompressionStep> {
+    chain.iter().copied().find(|step| {
+        accepts_encoding(request, step.encoding())
+            && (!step.is_sibling() || has_sibling(step.encoding()))
+    })
+}
+
+/// The smallest body compressed by default, gzip's own framing outweighs the savings
+/// on smaller ones
+pub const DEFAULT_COMPRESS_MIN_BYTES: usize = 256;
+
+/// Compress the response body on the fly if `step` says so and its content type is allowed
+///
+/// Sibling steps, streamed bodies, bodies smaller than `min_bytes` and responses that
+/// already have a `Content-Encoding` are returned unchanged
+///
+/// # Arguments
+///
+/// * `response` - The response to compress
+/// * `step` - The step picked by `choose`
+/// * `allowed` - The content types eligible for compression, `type/*` matches a whole type
+/// * `min_bytes` - The smallest body compressed
+///
+pub fn compress(
+    response: Response,
+    step: Option<CompressionStep>,
+    allowed: &[String],
+    min_bytes: usize,
+) -> Response {
+    let step = match step {
+        Some(step) if !step.is_sibling() => step,
+        _ => return response,
+    };
+    if response.get_header("Content-Encoding").is_some() {
+        return response;
+    }
+    let eligible = response
+        .get_header("Content-Type")
+        .is_some_and(|content_type| mime::matches_any(content_type, allowed));
+    let body = match response.get_body() {
+        Some(body) if eligible && !body.is_empty() && body.len() >= min_bytes => body,
+        _ => return response,
+    };
+
+    let compressed = if step == CompressionStep::Br {
+        let mut encoder = CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, 22);
+        encoder.write_all(body).map(|_| encoder.into_inner())
+    } else {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).and_then(|_| encoder.finish())
+    };
+    let compressed = match compressed {
+        Ok(compressed) => compressed,
+        Err(_) => return response,
+    };
+
+    let vary = match response.get_header("Vary") {
+        Some(vary) => format!("{}, Accept-Encoding", vary),
+        None => String::from("Accept-Encoding"),
+    };
+    // The compressed bytes are only equivalent to the ones the tag was computed from
+    let response = match response.get_header("ETag").map(etag::weaken) {
+        Some(etag) => response.header("ETag", &etag),
+        None => response,
+    };
+    response
+        .header("Content-Encoding", step.encoding())
+        .header("Vary", &vary)
+        .body(compressed)
+}
+
+/// Whether the request's `Accept-Encoding` allows a content coding, e.g. `gzip` or `br`
+pub fn accepts_encoding(request: &Request, encoding: &str) -> bool {
+    let accept_encoding = match request.header("accept-encoding") {
+        Some(value) => value,
+        None => return false,
+    };
+
+    accept_encoding.split(',').any(|coding| {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let refused = params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        (name.eq_ignore_ascii_case(encoding) || name == "*") && !refused
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brotli::Decompressor;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    const CHAIN: [CompressionStep; 4] = DEFAULT_COMPRESSION_CHAIN;
+    const GZIP: Option<CompressionStep> = Some(CompressionStep::Gzip);
+
+    fn allowed() -> Vec<String> {
+        DEFAULT_COMPRESSIBLE_TYPES.map(String::from).to_vec()
+    }
+
+    #[test]
+    fn test_png_never_compressed_json_is() {
+        let png = Response::new()
+            .header("Content-Type", "image/png")
+            .body(vec![0x89; 1024]);
+        let png = compress(png, GZIP, &allowed(), 0);
+        assert_eq!(png.get_header("Content-Encoding"), None);
+        assert_eq!(png.content_length(), 1024);
+
+        let json = Response::new()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(vec![b'1'; 1024]);
+        let json = compress(json, GZIP, &allowed(), 0);
+        assert_eq!(json.get_header("Content-Encoding"), Some("gzip"));
+        assert_eq!(json.get_header("Vary"), Some("Accept-Encoding"));
+        assert!(json.content_length() < 1024);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let page = std::fs::read("pages/index.html").unwrap();
+        let response = Response::new()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(page.clone());
+        let gzipped = compress(
+            response.try_clone().unwrap(),
+            GZIP,
+            &allowed(),
+            DEFAULT_COMPRESS_MIN_BYTES,
+        );
+        assert_eq!(gzipped.get_header("Content-Encoding"), Some("gzip"));
+        let mut decompressed = Vec::new();
+        GzDecoder::new(gzipped.get_body().unwrap())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, page);
+
+        let brotli = compress(
+            response,
+            Some(CompressionStep::Br),
+            &allowed(),
+            DEFAULT_COMPRESS_MIN_BYTES,
+        );
+        assert_eq!(brotli.get_header("Content-Encoding"), Some("br"));
+        assert_eq!(brotli.get_header("Vary"), Some("Accept-Encoding"));
+        let mut decompressed = Vec::new();
+        Decompressor::new(brotli.get_body().unwrap(), 4096)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, page);
+    }
+
+    #[test]
+    fn test_siblings_and_none_left_alone() {
+        let response = || {
+            Response::new()
+                .header("Content-Type", "text/plain")
+                .body(vec![b'a'; 1024])
+        };
+        for step in [None, Some(CompressionStep::BrSibling)] {
+            let response = compress(response(), step, &allowed(), 0);
+            assert_eq!(response.get_header("Content-Encoding"), None);
+            assert_eq!(response.content_length(), 1024);
+        }
+    }
+
+    #[test]
+    fn test_min_bytes() {
+        let response = |length| {
+            let response = Response::new()
+                .header("Content-Type", "text/plain")
+                .body(vec![b'a'; length]);
+            compress(response, GZIP, &allowed(), 100)
+        };
+        assert_eq!(response(99).get_header("Content-Encoding"), None);
+        assert_eq!(response(99).content_length(), 99);
+        assert_eq!(response(100).get_header("Content-Encoding"), Some("gzip"));
+    }
+
+    #[test]
+    fn test_compressed_etag_is_weak() {
+        let response = Response::new()
+            .header("Content-Type", "text/html")
+            .header("ETag", "\"abc\"")
+            .body(vec![b'a'; 1024]);
+        let response = compress(response, GZIP, &allowed(), 0);
+        assert_eq!(response.get_header("ETag"), Some("W/\"abc\""));
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        assert!(mime::matches_any("text/html", &allowed()));
+        assert!(mime::matches_any("Text/CSS; charset=utf-8", &allowed()));
+        assert!(!mime::matches_any("font/woff2", &allowed()));
+        assert!(!mime::matches_any("textual/plain", &allowed()));
+    }
+
+    #[test]
+    fn test_gzip_refused() {
+        let raw = "GET / HTTP/1.1\r\nAccept-Encoding: gzip;q=0, br\r\n\r\n";
+        let request = Request::parse_head(&mut raw.as_bytes()).unwrap();
+        assert!(!accepts_encoding(&request, "gzip"));
+        assert!(accepts_encoding(&request, "br"));
+    }
+
+    #[test]
+    fn test_choose() {
+        use CompressionStep::*;
+
+        let choose = |chain: &[CompressionStep], accept_encoding: &str, siblings: &[&str]| {
+            let raw = format!(
+                "GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+                accept_encoding
+            );
+            let request = Request::parse_head(&mut raw.as_bytes()).unwrap();
+            super::choose(chain, &request, |encoding| siblings.contains(&encoding))
+        };
+
+        // Siblings first, brotli over gzip
+        assert_eq!(choose(&CHAIN, "gzip, br", &["br", "gzip"]), Some(BrSibling));
+        assert_eq!(choose(&CHAIN, "gzip", &["br", "gzip"]), Some(GzipSibling));
+        // On the fly compression when there is no sibling to send, brotli first
+        assert_eq!(choose(&CHAIN, "br", &["gzip"]), Some(Br));
+        assert_eq!(choose(&CHAIN, "gzip, br", &[]), Some(Br));
+        assert_eq!(choose(&CHAIN, "gzip, br;q=0", &[]), Some(Gzip));
+        assert_eq!(choose(&CHAIN, "gzip", &[]), Some(Gzip));
+        assert_eq!(choose(&CHAIN, "gzip, br", &["gzip"]), Some(GzipSibling));
+        assert_eq!(choose(&CHAIN, "identity", &["br", "gzip"]), None);
+        assert_eq!(choose(&CHAIN, "*", &["gzip"]), Some(GzipSibling));
+
+        // A chain without siblings always compresses on the fly
+        assert_eq!(choose(&[Gzip], "gzip, br", &["br", "gzip"]), Some(Gzip));
+        // A chain without on the fly compression sends the body as it is instead
+        assert_eq!(choose(&[BrSibling], "gzip, br", &["gzip"]), None);
+        assert_eq!(choose(&[], "gzip, br", &["br"]), None);
+    }
+}
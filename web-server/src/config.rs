@@ -0,0 +1,1043 @@
+use serde::{Deserialize, Deserializer};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    access_log::LogField,
+    compression::{
+        CompressionStep, DEFAULT_COMPRESSIBLE_TYPES, DEFAULT_COMPRESSION_CHAIN,
+        DEFAULT_COMPRESS_MIN_BYTES,
+    },
+    error_format::ErrorFormat,
+    forwarded::strip_port,
+    method::Method,
+    router::{Resolution, ScanLimit},
+};
+
+/// The ServerConfig struct
+///
+/// The ServerConfig struct holds the tunable settings of the server
+///
+/// It can be loaded from a TOML file with the same keys, where durations are written
+/// as a number of seconds and settings that are left out keep their defaults. Single
+/// settings can be overridden on top of the file, see `from_toml`.
+///
+/// # Fields
+///
+/// * `header_read_timeout` - The read timeout while receiving the request line and headers
+/// * `body_read_timeout` - The read timeout while receiving the request body
+/// * `keep_alive_timeout` - How long an idle keep-alive connection may wait for its next request
+/// * `keep_alive` - Whether connections are kept open for more requests, otherwise every
+///   response is sent with `Connection: close`
+/// * `max_body_bytes` - The largest request body accepted, larger ones get `413`
+/// * `max_query_bytes` - The longest query string accepted, longer ones get `414`
+/// * `max_response_bytes` - The largest body a handler may respond with, larger ones are
+///   replaced with a `500`, `None` is unlimited
+/// * `strict_method_case` - Whether requests whose method isn't uppercase, e.g. `get`,
+///   are rejected with `400` instead of having their method uppercased
+/// * `allowed_methods` - The only methods the server answers, others get `405` whatever
+///   the route, `None` leaves it to the routes
+/// * `error_format` - How `4xx` and `5xx` responses are rendered, `json` gives them a
+///   JSON body and `negotiate` does when the client's `Accept` prefers JSON
+/// * `debug_echo` - Whether `/debug/echo` answers with the request it received
+/// * `response_cache_max_bytes` - The size bound of the response cache, zero disables it
+/// * `disk_cache` - A second response cache tier on disk, for responses evicted from
+///   memory or too large for it
+/// * `response_cache_max_age` - How long cached responses are fresh, `None` keeps them
+///   until the routes are reloaded
+/// * `response_cache_stale_while_revalidate` - How long after going stale a cached
+///   response is still served while it is rendered again in the background
+/// * `warm_cache` - Whether the pages are rendered into the response cache in the
+///   background at startup, so the first request for each is already cached
+/// * `warm_cache_max_bytes` - The most bytes of files read when warming the cache,
+///   `None` reads all of them
+/// * `matrix_params` - Whether `;key=value` path parameters are stripped before routing,
+///   otherwise they are treated as literal path characters
+/// * `strip_prefix` - A path prefix removed before routing, e.g. `/app` when a proxy
+///   forwards `/app/*` to the server, so `/app/about` is served by `/about`
+/// * `require_prefix` - Whether requests outside of `strip_prefix` get a `404`, instead
+///   of being routed as they are
+/// * `sniff_content_type` - Whether files with an unknown extension get their `Content-Type`
+///   from their leading bytes
+/// * `shutdown_retry_after` - The `Retry-After` sent with `503` while shutting down,
+///   `None` keeps serving requests until the connection closes
+/// * `request_timeout` - How long a matched request may take to be answered
+/// * `route_timeouts` - Path prefixes whose requests get their own timeout instead of
+///   `request_timeout`, the longest matching prefix wins
+/// * `route_content_types` - Path prefixes whose request bodies must have one of the
+///   given content types, as for `route_timeouts` the longest matching prefix wins
+/// * `body_required` - Path prefixes whose `POST`, `PUT` and `PATCH` requests must have a
+///   body, those without one get `400`
+/// * `early_hints` - Path prefixes whose requests are first sent a `103 Early Hints` with
+///   the given `Link` values, e.g. `</style.css>; rel=preload; as=style`, as for
+///   `route_timeouts` the longest matching prefix wins
+/// * `robots` - The rules for a generated `/robots.txt`, used when `pages` has none
+/// * `max_bytes_per_sec` - The bandwidth cap for writing responses on each connection
+/// * `trust_proxy` - Whether `Forwarded` and `X-Forwarded-*` headers identify the client
+/// * `head_from_get` - Whether `HEAD` requests use a path's `GET` handler (without the body)
+///   when it has no `HEAD` handler of its own
+/// * `reuse_address` - Whether the listening socket sets `SO_REUSEADDR`, so the server
+///   can restart while connections of the old one are in `TIME_WAIT`
+/// * `reuse_port` - Whether the listening socket sets `SO_REUSEPORT`, so a new server
+///   can listen on the same port while the old one finishes its connections
+/// * `tcp_keepalive` - TCP keepalive probing for accepted connections, to notice peers
+///   that vanished, unrelated to HTTP keep-alive
+/// * `detect_tls` - Whether TCP connections are checked for a TLS handshake before
+///   reading a request, which is refused with a TLS alert since TLS is terminated in
+///   front of the server
+/// * `unix_socket` - A Unix socket path to accept connections on as well as TCP
+/// * `stream_min_bytes` - Files at least this large are streamed from disk instead of
+///   being read into memory first
+/// * `large_file_warning_bytes` - Serving a file larger than this logs a warning, as it
+///   may be a large asset put in `pages` by mistake
+/// * `compression_chain` - The ways of compressing responses, the first one the client
+///   accepts and that is available is used, otherwise the body is sent as it is
+/// * `compressible_types` - The content types compressed on the fly for clients that
+///   accept it, `type/*` matches a whole type and an empty list disables compression
+/// * `compress_min_bytes` - Bodies smaller than this are sent uncompressed
+/// * `virtual_hosts` - The host names the server answers to, empty accepts any host
+/// * `strict_vhost` - Whether requests for a host not in `virtual_hosts` are rejected
+///   instead of being served the default site
+/// * `vhost_reject_status` - The status sent in strict mode, `404` or `421`
+/// * `directory_status` - The status sent for a directory no file resolves to, `403`
+///   or `404`
+/// * `access_log_fields` - The fields of the JSON line logged (to the `access` target)
+///   for every answered request, empty disables the access log
+/// * `quiet_paths` - Paths whose requests are left out of the request and access logs,
+///   such as health checks, a trailing `*` matches any path starting with the rest
+/// * `path_resolution` - How request paths are mapped to files in `pages`, the steps are
+///   tried in order (see `RouteTable::resolve`)
+/// * `hsts` - The `Strict-Transport-Security` policy sent on secure connections
+/// * `expect_ct` - The `Expect-CT` policy sent on secure connections
+/// * `bundles` - Virtual routes serving several files from `pages` concatenated, built
+///   at startup
+/// * `scan_limit` - How many entries of `pages` are read for the routes, so a huge
+///   directory can't hold up startup
+/// * `reload_interval` - How often `pages` is checked for changes, the routes are
+///   rebuilt when files were added, removed or modified, without a restart
+/// * `fingerprint_assets` - Whether every asset in `pages` is also served under a route
+///   with a hash of its contents, e.g. `/app.0123456789.js`, cached as immutable
+/// * `method_not_allowed_body` - The body of `405` responses, sent as plain text
+/// * `server_timing` - Whether responses carry a `Server-Timing` header with how long
+///   route resolution and file reading took, for debugging
+/// * `response_headers` - Headers added to every response, after everything else
+/// * `default_locale` - Enables picking pages such as `about.fr.html` by the request's
+///   `Accept-Language`, falling back to this locale, e.g. `en`
+/// * `max_keep_alive` - How many connections can be kept alive at once, responses on
+///   other connections ask the client to close, `None` is unlimited
+/// * `max_workers` - How many workers the pool grows to while connections wait for one,
+///   it starts with `--workers` and never grows without this
+/// * `maintenance` - When to answer requests with a maintenance page instead
+/// * `redirects` - Paths answered with a redirect instead of being routed, e.g. for
+///   moved pages
+/// * `canonical_host` - The host requests for any other host are redirected to with a
+///   `301`, e.g. `example.com` for `www.example.com`
+/// * `api_key` - The `X-Api-Key` every request must carry, others get a `401`
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    #[serde(deserialize_with = "seconds")]
+    pub header_read_timeout: Duration,
+    #[serde(deserialize_with = "seconds")]
+    pub body_read_timeout: Duration,
+    #[serde(deserialize_with = "seconds")]
+    pub keep_alive_timeout: Duration,
+    pub keep_alive: bool,
+    pub max_body_bytes: usize,
+    pub max_query_bytes: usize,
+    pub max_response_bytes: Option<u64>,
+    pub strict_method_case: bool,
+    pub allowed_methods: Option<Vec<Method>>,
+    pub error_format: ErrorFormat,
+    pub debug_echo: bool,
+    pub response_cache_max_bytes: usize,
+    pub disk_cache: Option<DiskCacheConfig>,
+    #[serde(deserialize_with = "optional_seconds")]
+    pub response_cache_max_age: Option<Duration>,
+    #[serde(deserialize_with = "seconds")]
+    pub response_cache_stale_while_revalidate: Duration,
+    pub warm_cache: bool,
+    pub warm_cache_max_bytes: Option<u64>,
+    pub matrix_params: bool,
+    pub strip_prefix: Option<String>,
+    pub require_prefix: bool,
+    pub sniff_content_type: bool,
+    #[serde(deserialize_with = "optional_seconds")]
+    pub shutdown_retry_after: Option<Duration>,
+    #[serde(deserialize_with = "seconds")]
+    pub request_timeout: Duration,
+    #[serde(deserialize_with = "route_seconds")]
+    pub route_timeouts: Vec<(String, Duration)>,
+    pub route_content_types: Vec<(String, Vec<String>)>,
+    pub body_required: Vec<String>,
+    pub early_hints: Vec<(String, Vec<String>)>,
+    pub robots: Option<RobotsConfig>,
+    pub max_bytes_per_sec: Option<u64>,
+    pub trust_proxy: bool,
+    pub head_from_get: bool,
+    pub reuse_address: bool,
+    pub reuse_port: bool,
+    pub tcp_keepalive: Option<TcpKeepaliveConfig>,
+    pub detect_tls: bool,
+    pub unix_socket: Option<PathBuf>,
+    pub stream_min_bytes: u64,
+    pub large_file_warning_bytes: Option<u64>,
+    pub compression_chain: Vec<CompressionStep>,
+    pub compressible_types: Vec<String>,
+    pub compress_min_bytes: usize,
+    pub virtual_hosts: Vec<String>,
+    pub strict_vhost: bool,
+    pub vhost_reject_status: VhostRejectStatus,
+    pub directory_status: DirectoryStatus,
+    pub access_log_fields: Vec<LogField>,
+    pub quiet_paths: Vec<String>,
+    pub path_resolution: Vec<Resolution>,
+    pub hsts: Option<HstsConfig>,
+    pub expect_ct: Option<ExpectCtConfig>,
+    pub bundles: Vec<BundleConfig>,
+    pub scan_limit: ScanLimit,
+    #[serde(deserialize_with = "optional_seconds")]
+    pub reload_interval: Option<Duration>,
+    pub fingerprint_assets: bool,
+    pub method_not_allowed_body: Option<String>,
+    pub server_timing: bool,
+    pub response_headers: Vec<ResponseHeader>,
+    pub default_locale: Option<String>,
+    pub max_keep_alive: Option<usize>,
+    pub max_workers: Option<usize>,
+    pub maintenance: Option<MaintenanceConfig>,
+    pub redirects: Vec<RedirectConfig>,
+    pub canonical_host: Option<String>,
+    pub api_key: Option<String>,
+}
+
+/// The RobotsConfig struct
+///
+/// The RobotsConfig struct holds the rules of a generated `robots.txt`
+///
+/// # Fields
+///
+/// * `allow` - The paths crawlers may visit
+/// * `disallow` - The paths crawlers should stay out of
+/// * `sitemap` - The absolute URL of the sitemap
+///
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RobotsConfig {
+    pub allow: Vec<String>,
+    pub disallow: Vec<String>,
+    pub sitemap: Option<String>,
+}
+
+/// The BundleConfig struct
+///
+/// The BundleConfig struct describes an asset served as the concatenation of other files
+///
+/// # Fields
+///
+/// * `path` - The route of the bundle, e.g. `/bundle.css`, whose extension sets its type
+/// * `files` - The files to concatenate in order, relative to `pages`
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BundleConfig {
+    pub path: String,
+    pub files: Vec<String>,
+}
+
+/// The HstsConfig struct
+///
+/// The HstsConfig struct holds the `Strict-Transport-Security` policy
+///
+/// # Fields
+///
+/// * `max_age` - How long browsers should only use HTTPS for the site
+/// * `include_subdomains` - Whether the policy covers subdomains too
+/// * `preload` - Whether the site asks to be included in browser preload lists
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HstsConfig {
+    #[serde(deserialize_with = "seconds")]
+    pub max_age: Duration,
+    #[serde(default)]
+    pub include_subdomains: bool,
+    #[serde(default)]
+    pub preload: bool,
+}
+
+/// The ExpectCtConfig struct
+///
+/// The ExpectCtConfig struct holds the `Expect-CT` policy
+///
+/// # Fields
+///
+/// * `max_age` - How long browsers should expect Certificate Transparency for the site
+/// * `enforce` - Whether browsers should refuse connections that don't comply
+/// * `report_uri` - Where browsers report failures
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpectCtConfig {
+    #[serde(deserialize_with = "seconds")]
+    pub max_age: Duration,
+    #[serde(default)]
+    pub enforce: bool,
+    pub report_uri: Option<String>,
+}
+
+/// The ResponseHeader struct
+///
+/// The ResponseHeader struct describes a header added to every response
+///
+/// # Fields
+///
+/// * `name` - The header name
+/// * `value` - The header value
+/// * `always` - Whether the header replaces one the response already has, instead of
+///   only being added when it is absent
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseHeader {
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub always: bool,
+}
+
+/// The TcpKeepaliveConfig struct
+///
+/// The TcpKeepaliveConfig struct holds the timing of TCP keepalive probes
+///
+/// # Fields
+///
+/// * `idle` - How long a connection is quiet before it is probed
+/// * `interval` - The time between probes that get no answer
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TcpKeepaliveConfig {
+    #[serde(deserialize_with = "seconds")]
+    pub idle: Duration,
+    #[serde(deserialize_with = "seconds")]
+    pub interval: Duration,
+}
+
+/// The DiskCacheConfig struct
+///
+/// The DiskCacheConfig struct holds the settings of the disk tier of the response cache
+///
+/// # Fields
+///
+/// * `dir` - The directory the responses are written to, emptied at startup
+/// * `max_bytes` - The size bound of the written responses
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiskCacheConfig {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+}
+
+/// The MaintenanceConfig struct
+///
+/// The MaintenanceConfig struct holds the settings of maintenance mode, where requests
+/// are answered with `503` and a maintenance page
+///
+/// # Fields
+///
+/// * `enabled` - Whether maintenance mode is on regardless of `sentinel`
+/// * `sentinel` - A file that turns maintenance mode on while it exists, e.g. `MAINTENANCE`
+/// * `retry_after` - The `Retry-After` sent with the `503`
+/// * `page` - The HTML file sent as the body of the `503`
+/// * `allow` - The paths that are still served normally, e.g. `/health`
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    pub sentinel: Option<PathBuf>,
+    #[serde(deserialize_with = "seconds")]
+    pub retry_after: Duration,
+    pub page: Option<PathBuf>,
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+/// The RedirectConfig struct
+///
+/// The RedirectConfig struct describes a path that redirects to another
+///
+/// # Fields
+///
+/// * `from` - The path redirected, without a trailing slash, e.g. `/old-blog`
+/// * `to` - Where the redirect points, a path or an absolute URL
+/// * `status` - The status of the redirect, `301` when unset
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectConfig {
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub status: RedirectStatus,
+}
+
+/// A redirect status code, one of `301`, `302`, `303`, `307` and `308`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "u16")]
+pub struct RedirectStatus(u16);
+
+impl RedirectStatus {
+    /// Get the status code
+    pub fn code(self) -> u16 {
+        self.0
+    }
+
+    /// Get the reason phrase
+    pub fn reason(self) -> &'static str {
+        match self.0 {
+            301 => "MOVED PERMANENTLY",
+            302 => "FOUND",
+            303 => "SEE OTHER",
+            307 => "TEMPORARY REDIRECT",
+            _ => "PERMANENT REDIRECT",
+        }
+    }
+}
+
+impl Default for RedirectStatus {
+    fn default() -> Self {
+        RedirectStatus(301)
+    }
+}
+
+impl TryFrom<u16> for RedirectStatus {
+    type Error = String;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        match code {
+            301 | 302 | 303 | 307 | 308 => Ok(RedirectStatus(code)),
+            _ => Err(format!("not a redirect status: {}", code)),
+        }
+    }
+}
+
+/// The status sent for a directory without an index, `403` or `404`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "u16")]
+pub struct DirectoryStatus(u16);
+
+impl DirectoryStatus {
+    /// Get the status code
+    pub fn code(self) -> u16 {
+        self.0
+    }
+}
+
+impl Default for DirectoryStatus {
+    fn default() -> Self {
+        DirectoryStatus(403)
+    }
+}
+
+impl TryFrom<u16> for DirectoryStatus {
+    type Error = String;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        match code {
+            403 | 404 => Ok(DirectoryStatus(code)),
+            _ => Err(format!("not a directory status, 403 or 404: {}", code)),
+        }
+    }
+}
+
+/// The status sent for a host strict mode rejects, `404` or `421`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "u16")]
+pub struct VhostRejectStatus(u16);
+
+impl VhostRejectStatus {
+    /// Get the status code
+    pub fn code(self) -> u16 {
+        self.0
+    }
+}
+
+impl Default for VhostRejectStatus {
+    fn default() -> Self {
+        VhostRejectStatus(421)
+    }
+}
+
+impl TryFrom<u16> for VhostRejectStatus {
+    type Error = String;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        match code {
+            404 | 421 => Ok(VhostRejectStatus(code)),
+            _ => Err(format!(
+                "not a virtual host reject status, 404 or 421: {}",
+                code
+            )),
+        }
+    }
+}
+
+/// The reasons loading a configuration file can fail
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be read
+    Read(io::Error),
+    /// The file is not valid TOML, or it and the overrides don't describe a configuration
+    Parse(toml::de::Error),
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            header_read_timeout: Duration::from_secs(5),
+            body_read_timeout: Duration::from_secs(10),
+            keep_alive_timeout: Duration::from_secs(2),
+            keep_alive: true,
+            max_body_bytes: 10 * 1024 * 1024,
+            max_query_bytes: 8 * 1024,
+            max_response_bytes: None,
+            strict_method_case: false,
+            allowed_methods: None,
+            error_format: ErrorFormat::Plain,
+            debug_echo: false,
+            response_cache_max_bytes: 0,
+            disk_cache: None,
+            response_cache_max_age: None,
+            response_cache_stale_while_revalidate: Duration::ZERO,
+            warm_cache: false,
+            warm_cache_max_bytes: None,
+            matrix_params: false,
+            strip_prefix: None,
+            require_prefix: false,
+            sniff_content_type: false,
+            shutdown_retry_after: Some(Duration::from_secs(5)),
+            request_timeout: Duration::from_secs(30),
+            route_timeouts: Vec::new(),
+            route_content_types: Vec::new(),
+            body_required: Vec::new(),
+            early_hints: Vec::new(),
+            robots: None,
+            max_bytes_per_sec: None,
+            trust_proxy: false,
+            head_from_get: true,
+            reuse_address: true,
+            reuse_port: false,
+            tcp_keepalive: None,
+            detect_tls: false,
+            unix_socket: None,
+            stream_min_bytes: 1024 * 1024,
+            large_file_warning_bytes: None,
+            compression_chain: DEFAULT_COMPRESSION_CHAIN.to_vec(),
+            compressible_types: DEFAULT_COMPRESSIBLE_TYPES.map(String::from).to_vec(),
+            compress_min_bytes: DEFAULT_COMPRESS_MIN_BYTES,
+            virtual_hosts: Vec::new(),
+            strict_vhost: false,
+            vhost_reject_status: VhostRejectStatus::default(),
+            directory_status: DirectoryStatus::default(),
+            access_log_fields: LogField::ALL.to_vec(),
+            quiet_paths: Vec::new(),
+            path_resolution: vec![
+                Resolution::Exact,
+                Resolution::HtmlSibling,
+                Resolution::DirectoryIndex,
+            ],
+            hsts: None,
+            expect_ct: None,
+            bundles: Vec::new(),
+            scan_limit: ScanLimit::default(),
+            reload_interval: None,
+            fingerprint_assets: false,
+            method_not_allowed_body: None,
+            server_timing: false,
+            response_headers: Vec::new(),
+            default_locale: None,
+            max_keep_alive: None,
+            max_workers: None,
+            maintenance: None,
+            redirects: Vec::new(),
+            canonical_host: None,
+            api_key: None,
+        }
+    }
+}
+
+/// The prefix of the environment variables overriding settings, see `env_overrides`
+const ENV_PREFIX: &str = "WEB_SERVER_";
+
+impl ServerConfig {
+    /// Load the configuration from an optional TOML file, with `overrides` replacing
+    /// its values, see `from_toml`
+    pub fn load(path: Option<&Path>, overrides: &[(String, String)]) -> Result<Self, ConfigError> {
+        let source = match path {
+            Some(path) => fs::read_to_string(path).map_err(ConfigError::Read)?,
+            None => String::new(),
+        };
+        Self::from_toml(&source, overrides).map_err(ConfigError::Parse)
+    }
+
+    /// Parse a configuration written in TOML, with `overrides` replacing its values
+    ///
+    /// Each override is a key and a TOML value, later ones win. The key names a
+    /// setting, with dots for nested ones such as `maintenance.enabled`. A value that
+    /// isn't valid TOML is taken as a string, so hosts and paths need no quotes.
+    ///
+    pub fn from_toml(
+        source: &str,
+        overrides: &[(String, String)],
+    ) -> Result<Self, toml::de::Error> {
+        let mut table: toml::Table = toml::from_str(source)?;
+        for (key, value) in overrides {
+            let mut keys = key.split('.');
+            let last = keys.next_back().unwrap_or_default();
+            let mut parent = &mut table;
+            for key in keys {
+                let entry = parent
+                    .entry(key)
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+                // A value in the way is replaced, the result is checked as a whole
+                if !entry.is_table() {
+                    *entry = toml::Value::Table(toml::Table::new());
+                }
+                parent = entry.as_table_mut().unwrap();
+            }
+            parent.insert(last.to_string(), parse_value(value));
+        }
+        toml::Value::Table(table).try_into()
+    }
+
+    /// Get the request timeout that applies to a path
+    ///
+    /// A prefix matches whole path segments, so `/reports` covers `/reports` and
+    /// `/reports/2024` but not `/reportsx`
+    ///
+    pub fn timeout_for(&self, path: &str) -> Duration {
+        self.route_timeouts
+            .iter()
+            .filter(|(prefix, _)| matches_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, timeout)| *timeout)
+            .unwrap_or(self.request_timeout)
+    }
+
+    /// Get the content types a request body sent to a path may have
+    ///
+    /// Returns `None` when any content type is accepted
+    ///
+    pub fn content_types_for(&self, path: &str) -> Option<&[String]> {
+        self.route_content_types
+            .iter()
+            .filter(|(prefix, _)| matches_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, content_types)| content_types.as_slice())
+    }
+
+    /// Whether a request to a path with the given method must have a body
+    pub fn requires_body(&self, method: &str, path: &str) -> bool {
+        matches!(method, "POST" | "PUT" | "PATCH")
+            && self
+                .body_required
+                .iter()
+                .any(|prefix| matches_prefix(path, prefix))
+    }
+
+    /// Check whether a method passes the server-wide `allowed_methods` list
+    pub fn is_method_allowed(&self, method: &Method) -> bool {
+        self.allowed_methods
+            .as_ref()
+            .is_none_or(|allowed| allowed.iter().any(|m| m == method))
+    }
+
+    /// Get the redirect configured for a path
+    pub fn redirect_for(&self, path: &str) -> Option<&RedirectConfig> {
+        self.redirects.iter().find(|redirect| redirect.from == path)
+    }
+
+    /// Get the canonical host a request for `host` (which may carry a port) is
+    /// redirected to
+    ///
+    /// Requests without a `Host` aren't, there is no telling which host they are for
+    ///
+    pub fn canonical_host_for(&self, host: Option<&str>) -> Option<&str> {
+        let canonical = self.canonical_host.as_deref()?;
+        let host = strip_port(host?);
+        (!host.eq_ignore_ascii_case(strip_port(canonical))).then_some(canonical)
+    }
+
+    /// Get the `Link` values sent as early hints before answering a path
+    pub fn early_hints_for(&self, path: &str) -> Option<&[String]> {
+        self.early_hints
+            .iter()
+            .filter(|(prefix, _)| matches_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, links)| links.as_slice())
+    }
+
+    /// Whether requests for a path are left out of the logs, see `quiet_paths`
+    pub fn is_quiet_path(&self, path: &str) -> bool {
+        self.quiet_paths
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == pattern,
+            })
+    }
+
+    /// Whether a request for `host` (which may carry a port) is for a configured virtual host
+    ///
+    /// Always true when no virtual hosts are configured
+    ///
+    pub fn is_virtual_host(&self, host: Option<&str>) -> bool {
+        if self.virtual_hosts.is_empty() {
+            return true;
+        }
+        let host = match host {
+            Some(host) => strip_port(host),
+            None => return false,
+        };
+        self.virtual_hosts
+            .iter()
+            .any(|vhost| vhost.eq_ignore_ascii_case(host))
+    }
+}
+
+impl RobotsConfig {
+    /// Render the rules as a `robots.txt` applying to every user agent
+    pub fn render(&self) -> String {
+        let mut robots = String::from("User-agent: *\n");
+        for path in &self.allow {
+            robots.push_str(&format!("Allow: {}\n", path));
+        }
+        for path in &self.disallow {
+            robots.push_str(&format!("Disallow: {}\n", path));
+        }
+        if let Some(sitemap) = &self.sitemap {
+            robots.push_str(&format!("\nSitemap: {}\n", sitemap));
+        }
+        robots
+    }
+}
+
+impl MaintenanceConfig {
+    /// Whether a request for `path` should get the maintenance page
+    ///
+    /// The sentinel file is checked on every call, so it can be created and removed
+    /// while the server runs
+    ///
+    pub fn applies_to(&self, path: &str) -> bool {
+        (self.enabled || self.sentinel.as_deref().is_some_and(Path::exists))
+            && !self.allow.iter().any(|allowed| allowed == path)
+    }
+}
+
+impl HstsConfig {
+    /// Render the `Strict-Transport-Security` header value
+    pub fn render(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age.as_secs());
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+impl ExpectCtConfig {
+    /// Render the `Expect-CT` header value
+    pub fn render(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age.as_secs());
+        if self.enforce {
+            value.push_str(", enforce");
+        }
+        if let Some(report_uri) = &self.report_uri {
+            value.push_str(&format!(", report-uri=\"{}\"", report_uri));
+        }
+        value
+    }
+}
+
+/// Read the overrides set by `WEB_SERVER_` environment variables
+///
+/// The rest of the name, lowercased, is the key, with `__` for a dot, e.g.
+/// `WEB_SERVER_KEEP_ALIVE_TIMEOUT=10` or `WEB_SERVER_MAINTENANCE__ENABLED=true`
+///
+pub fn env_overrides(vars: impl IntoIterator<Item = (String, String)>) -> Vec<(String, String)> {
+    let mut overrides: Vec<(String, String)> = vars
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let key = name.strip_prefix(ENV_PREFIX)?;
+            Some((key.to_ascii_lowercase().replace("__", "."), value))
+        })
+        .collect();
+    // The environment has no order, sorting keeps the result the same between runs
+    overrides.sort();
+    overrides
+}
+
+/// Parse an override's value as TOML, or take it as a string when it isn't
+fn parse_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+/// A duration written as a number of seconds, e.g. `0.5`
+#[derive(Deserialize)]
+#[serde(try_from = "f64")]
+struct Seconds(Duration);
+
+impl TryFrom<f64> for Seconds {
+    type Error = String;
+
+    fn try_from(secs: f64) -> Result<Self, Self::Error> {
+        Duration::try_from_secs_f64(secs)
+            .map(Seconds)
+            .map_err(|_| format!("invalid number of seconds: {}", secs))
+    }
+}
+
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    Seconds::deserialize(deserializer).map(|Seconds(duration)| duration)
+}
+
+fn optional_seconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<Seconds>::deserialize(deserializer).map(|secs| secs.map(|Seconds(duration)| duration))
+}
+
+fn route_seconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<(String, Duration)>, D::Error> {
+    let routes = Vec::<(String, Seconds)>::deserialize(deserializer)?;
+    Ok(routes
+        .into_iter()
+        .map(|(prefix, Seconds(duration))| (prefix, duration))
+        .collect())
+}
+
+fn matches_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeout_for() {
+        let config = ServerConfig {
+            request_timeout: Duration::from_secs(30),
+            route_timeouts: vec![
+                (String::from("/health"), Duration::from_secs(1)),
+                (String::from("/reports"), Duration::from_secs(120)),
+                (String::from("/reports/live"), Duration::from_secs(5)),
+            ],
+            ..ServerConfig::default()
+        };
+
+        assert_eq!(config.timeout_for("/health"), Duration::from_secs(1));
+        assert_eq!(
+            config.timeout_for("/reports/2024"),
+            Duration::from_secs(120)
+        );
+        assert_eq!(config.timeout_for("/reports/live"), Duration::from_secs(5));
+        assert_eq!(config.timeout_for("/reportsx"), Duration::from_secs(30));
+        assert_eq!(config.timeout_for("/about"), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_quiet_paths() {
+        let config = ServerConfig {
+            quiet_paths: vec![String::from("/health"), String::from("/metrics*")],
+            ..ServerConfig::default()
+        };
+
+        assert!(config.is_quiet_path("/health"));
+        assert!(config.is_quiet_path("/metrics"));
+        assert!(config.is_quiet_path("/metrics/cpu"));
+        assert!(!config.is_quiet_path("/health/db"));
+        assert!(!config.is_quiet_path("/about"));
+    }
+
+    #[test]
+    fn test_load_toml() {
+        let path =
+            std::env::temp_dir().join(format!("web-server-config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+header_read_timeout = 0.5
+keep_alive_timeout = 10
+shutdown_retry_after = 2
+route_timeouts = [["/reports", 120]]
+route_content_types = [["/upload", ["image/png", "image/jpeg"]]]
+virtual_hosts = ["example.com"]
+canonical_host = "example.com"
+access_log_fields = ["method", "path", "status"]
+path_resolution = ["exact", "directory_index"]
+max_keep_alive = 64
+max_workers = 16
+
+[hsts]
+max_age = 31536000
+preload = true
+
+[[bundles]]
+path = "/bundle.css"
+files = ["reset.css", "theme.css"]
+
+[[response_headers]]
+name = "X-Served-By"
+value = "web-server"
+always = true
+
+[[redirects]]
+from = "/old-blog"
+to = "/blog"
+
+[[redirects]]
+from = "/promo"
+to = "https://example.com/sale"
+status = 302
+"#,
+        )
+        .unwrap();
+        let config = ServerConfig::load(Some(&path), &[]).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.header_read_timeout, Duration::from_millis(500));
+        assert_eq!(config.keep_alive_timeout, Duration::from_secs(10));
+        assert_eq!(config.shutdown_retry_after, Some(Duration::from_secs(2)));
+        assert_eq!(
+            config.timeout_for("/reports/2024"),
+            Duration::from_secs(120)
+        );
+        assert_eq!(
+            config.content_types_for("/upload"),
+            Some(&[String::from("image/png"), String::from("image/jpeg")][..])
+        );
+        assert_eq!(config.virtual_hosts, vec!["example.com"]);
+        assert_eq!(
+            config.canonical_host_for(Some("www.example.com")),
+            Some("example.com")
+        );
+        assert_eq!(config.canonical_host_for(Some("example.com:443")), None);
+        assert_eq!(config.canonical_host_for(None), None);
+        assert_eq!(
+            config.access_log_fields,
+            vec![LogField::Method, LogField::Path, LogField::Status]
+        );
+        assert_eq!(
+            config.path_resolution,
+            vec![Resolution::Exact, Resolution::DirectoryIndex]
+        );
+        assert_eq!(config.max_keep_alive, Some(64));
+        assert_eq!(config.max_workers, Some(16));
+        let hsts = config.hsts.as_ref().unwrap();
+        assert_eq!(hsts.render(), "max-age=31536000; preload");
+        assert_eq!(config.bundles[0].files, vec!["reset.css", "theme.css"]);
+        assert!(config.response_headers[0].always);
+        assert_eq!(config.redirect_for("/old-blog").unwrap().status.code(), 301);
+        assert_eq!(config.redirect_for("/promo").unwrap().status.code(), 302);
+        assert!(config.redirect_for("/blog").is_none());
+
+        // Settings the file leaves out keep their defaults
+        let defaults = ServerConfig::default();
+        assert_eq!(config.body_read_timeout, defaults.body_read_timeout);
+        assert_eq!(config.max_body_bytes, defaults.max_body_bytes);
+        assert_eq!(config.compressible_types, defaults.compressible_types);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let source = "keep_alive_timeout = 10\n[maintenance]\nenabled = false\nretry_after = 30";
+        let overrides = [
+            ("keep_alive_timeout", "0.5"),
+            ("canonical_host", "example.com"),
+            ("maintenance.enabled", "true"),
+            ("quiet_paths", "[\"/health\"]"),
+            ("max_workers", "8"),
+            ("max_workers", "16"),
+            ("allowed_methods", "[\"GET\", \"PURGE\"]"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        let config = ServerConfig::from_toml(source, &overrides).unwrap();
+
+        assert_eq!(config.keep_alive_timeout, Duration::from_millis(500));
+        assert_eq!(config.canonical_host.as_deref(), Some("example.com"));
+        assert!(config.maintenance.unwrap().enabled);
+        assert_eq!(config.quiet_paths, vec!["/health"]);
+        // The last override of a key wins
+        assert_eq!(config.max_workers, Some(16));
+        assert_eq!(
+            config.allowed_methods,
+            Some(vec![Method::Get, Method::Other(String::from("PURGE"))])
+        );
+
+        // Overrides are checked like the file
+        let invalid = |key: &str, value: &str| {
+            let overrides = [(key.to_string(), value.to_string())];
+            ServerConfig::from_toml("", &overrides).is_err()
+        };
+        assert!(invalid("keep_alive_timout", "10"));
+        assert!(invalid("keep_alive_timeout", "soon"));
+        assert!(invalid("keep_alive.timeout", "10"));
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let vars = [
+            ("WEB_SERVER_MAINTENANCE__ENABLED", "true"),
+            ("WEB_SERVER_KEEP_ALIVE_TIMEOUT", "10"),
+            ("CONFIG", "server.toml"),
+            ("PATH", "/usr/bin"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+
+        assert_eq!(
+            env_overrides(vars),
+            [
+                ("keep_alive_timeout", "10"),
+                ("maintenance.enabled", "true")
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_invalid_toml() {
+        assert!(ServerConfig::from_toml("keep_alive_timout = 10", &[]).is_err());
+        assert!(ServerConfig::from_toml("keep_alive_timeout = -1", &[]).is_err());
+        let redirect = "[[redirects]]\nfrom = \"/a\"\nto = \"/b\"\nstatus = 200";
+        assert!(ServerConfig::from_toml(redirect, &[]).is_err());
+        assert!(ServerConfig::from_toml("directory_status = 500", &[]).is_err());
+        let config = ServerConfig::from_toml("directory_status = 404", &[]).unwrap();
+        assert_eq!(config.directory_status.code(), 404);
+        assert!(ServerConfig::from_toml("vhost_reject_status = 403", &[]).is_err());
+        let config = ServerConfig::from_toml("vhost_reject_status = 404", &[]).unwrap();
+        assert_eq!(config.vhost_reject_status.code(), 404);
+        assert!(matches!(
+            ServerConfig::load(Some(Path::new("missing.toml")), &[]),
+            Err(ConfigError::Read(_))
+        ));
+    }
+}
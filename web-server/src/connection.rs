@@ -0,0 +1,227 @@
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+/// How many connections may wait to be accepted, as used by the standard library
+const LISTEN_BACKLOG: i32 = 128;
+
+/// A connected byte stream the server can speak HTTP over
+pub trait Connection: Read + Write + Send {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Describe the peer for logs
+    fn peer(&self) -> String;
+}
+
+/// A buffered connection, reading requests and writing responses through `get_mut`
+pub type ConnectionReader = BufReader<Box<dyn Connection>>;
+
+/// The content type of a TLS handshake record, the first byte of a ClientHello
+const TLS_HANDSHAKE: u8 = 0x16;
+
+/// The protocol a client speaks, told apart by the first byte it sends
+#[derive(Debug, PartialEq)]
+pub enum Protocol {
+    Tls,
+    Http,
+}
+
+/// Peek at the first byte of a connection, leaving it to be read by the request parser
+///
+/// # Returns
+///
+/// The protocol the client speaks, or `None` if it closed the connection before
+/// sending anything
+///
+pub fn sniff_protocol(stream: &TcpStream) -> io::Result<Option<Protocol>> {
+    let mut first = [0; 1];
+    Ok(match stream.peek(&mut first)? {
+        0 => None,
+        _ if first[0] == TLS_HANDSHAKE => Some(Protocol::Tls),
+        _ => Some(Protocol::Http),
+    })
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn peer(&self) -> String {
+        self.peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|_| String::from("unknown"))
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn peer(&self) -> String {
+        String::from("unix")
+    }
+}
+
+/// Turn on TCP keepalive probes, so a peer that vanished without closing is noticed
+///
+/// `idle` is how long the connection is quiet before the first probe and `interval`
+/// the time between probes. `interval` is only applied on Linux, macOS and Windows,
+/// other platforms keep their system default for it.
+///
+pub fn set_tcp_keepalive(stream: &TcpStream, idle: Duration, interval: Duration) -> io::Result<()> {
+    // Both are set in whole seconds, and zero is refused
+    let secs = |duration: Duration| duration.max(Duration::from_secs(1));
+    let keepalive = TcpKeepalive::new().with_time(secs(idle));
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    let keepalive = keepalive.with_interval(secs(interval));
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    let _ = interval;
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// Listen on `addr`, the first of its resolved addresses that can be bound
+///
+/// `reuse_address` sets `SO_REUSEADDR`, so a restarted server can bind while
+/// connections of the old one linger in `TIME_WAIT`. `reuse_port` sets `SO_REUSEPORT`,
+/// so a new process can listen on the same port while the old one drains; the
+/// kernel spreads new connections over every listener with it set.
+///
+pub fn bind_listener(addr: &str, reuse_address: bool, reuse_port: bool) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match bind_socket(addr, reuse_address, reuse_port) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    }))
+}
+
+fn bind_socket(addr: SocketAddr, reuse_address: bool, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(addr),
+        Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    #[cfg(unix)]
+    {
+        socket.set_reuse_address(reuse_address)?;
+        if reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+    }
+    // `SO_REUSEADDR` lets other sockets take over the port on Windows, so it is left
+    // unset, and there is no `SO_REUSEPORT`
+    #[cfg(not(unix))]
+    {
+        let _ = reuse_address;
+        if reuse_port {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connect to a new listener and return both ends
+    fn connected() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (client, stream)
+    }
+
+    #[test]
+    fn test_sniff_protocol() {
+        let (mut client, stream) = connected();
+        client.write_all(&[0x16, 0x03, 0x01]).unwrap();
+        assert_eq!(sniff_protocol(&stream).unwrap(), Some(Protocol::Tls));
+
+        let (mut client, mut stream) = connected();
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        assert_eq!(sniff_protocol(&stream).unwrap(), Some(Protocol::Http));
+        // Peeking leaves the byte for the request parser
+        let mut first = [0; 1];
+        stream.read_exact(&mut first).unwrap();
+        assert_eq!(&first, b"G");
+
+        let (client, stream) = connected();
+        drop(client);
+        assert_eq!(sniff_protocol(&stream).unwrap(), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_set_tcp_keepalive() {
+        let (_client, stream) = connected();
+        let socket = SockRef::from(&stream);
+        assert!(!socket.keepalive().unwrap());
+
+        set_tcp_keepalive(&stream, Duration::from_secs(60), Duration::from_secs(10)).unwrap();
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(
+            socket.tcp_keepalive_time().unwrap(),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            socket.tcp_keepalive_interval().unwrap(),
+            Duration::from_secs(10)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_listener_reuse_port() {
+        let first = bind_listener("127.0.0.1:0", true, true).unwrap();
+        let addr = first.local_addr().unwrap().to_string();
+
+        // Another listener on the same port is only allowed with SO_REUSEPORT
+        let error = bind_listener(&addr, true, false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        let second = bind_listener(&addr, true, true).unwrap();
+        assert_eq!(second.local_addr().unwrap().to_string(), addr);
+
+        // The old listener stops accepting, new connections go to the other one
+        drop(first);
+        let mut client = TcpStream::connect(&addr).unwrap();
+        let (mut stream, _) = second.accept().unwrap();
+        client.write_all(b"x").unwrap();
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(&byte, b"x");
+    }
+
+    #[test]
+    fn test_bind_listener_ipv6() {
+        // Not every sandbox has IPv6, only check the address when it does
+        if let Ok(listener) = bind_listener("[::1]:0", true, false) {
+            assert!(listener.local_addr().unwrap().is_ipv6());
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Format a time as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+///
+/// Times before the Unix epoch are formatted as the epoch
+///
+pub fn http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Parse an HTTP date, in any of the three formats RFC 7231 has recipients accept
+///
+/// * `Sun, 06 Nov 1994 08:49:37 GMT` - The IMF-fixdate sent by current clients
+/// * `Sunday, 06-Nov-94 08:49:37 GMT` - The obsolete RFC 850 format
+/// * `Sun Nov  6 08:49:37 1994` - The obsolete format of C's `asctime`
+///
+/// Returns `None` for anything else, including dates before the Unix epoch
+///
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+    let (day, month, year, time) = match value.split_once(", ") {
+        Some((_, rest)) => match rest.split(' ').collect::<Vec<_>>()[..] {
+            [day, month, year, time, "GMT"] if year.len() == 4 => {
+                (day, month, year.parse().ok()?, time)
+            }
+            [date, time, "GMT"] => {
+                let mut parts = date.split('-');
+                let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+                if year.len() != 2 || parts.next().is_some() {
+                    return None;
+                }
+                // Two digit years are taken to be in the past century or so
+                let year: u64 = year.parse().ok()?;
+                (day, month, year + if year < 70 { 2000 } else { 1900 }, time)
+            }
+            _ => return None,
+        },
+        None => match value.split_whitespace().collect::<Vec<_>>()[..] {
+            [_, month, day, time, year] => (day, month, year.parse().ok()?, time),
+            _ => return None,
+        },
+    };
+
+    let day: u64 = digits(day)?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+    let mut clock = time.split(':').map(digits);
+    let (hours, minutes, secs) = (clock.next()??, clock.next()??, clock.next()??);
+    if clock.next().is_some() || !(1970..=9999).contains(&year) || day == 0 {
+        return None;
+    }
+    if hours > 23 || minutes > 59 || secs > 59 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    // Days past the end of their month come out as a different date
+    if civil_from_days(days) != (year, month, day) {
+        return None;
+    }
+    let secs = days * 86400 + hours * 3600 + minutes * 60 + secs;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Parse a number written with one or two digits and nothing else
+fn digits(value: &str) -> Option<u64> {
+    if value.is_empty() || value.len() > 2 || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+/// Convert a date in the Gregorian calendar from 1970 on to days since 1970-01-01,
+/// the inverse of `civil_from_days`
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = year - u64::from(month <= 2);
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) date in the Gregorian calendar
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Shift the epoch to 0000-03-01, so leap days fall at the end of each year
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_date() {
+        let date = |secs| http_date(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        // The example from RFC 7231 section 7.1.1.1
+        assert_eq!(date(784_111_777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(date(951_782_400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(date(1_709_251_199), "Thu, 29 Feb 2024 23:59:59 GMT");
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        let example = Some(UNIX_EPOCH + Duration::from_secs(784_111_777));
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), example);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), example);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), example);
+
+        for secs in [0, 951_782_400, 1_709_251_199] {
+            let time = UNIX_EPOCH + Duration::from_secs(secs);
+            assert_eq!(parse_http_date(&http_date(time)), Some(time));
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_http_date() {
+        for value in [
+            "",
+            "yesterday",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Fri, 30 Feb 2024 00:00:00 GMT",
+            "Wed, 31 Dec 1969 23:59:59 GMT",
+            "Sun, 06 Nov 94 08:49:37 GMT",
+            "Sun, +6 Nov 1994 08:49:37 GMT",
+            "Sun, 00 Nov 1994 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 99999999999999999",
+        ] {
+            assert_eq!(parse_http_date(value), None, "{:?}", value);
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use log::error;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use crate::response::Response;
+
+/// The extension of the files holding cached responses
+const EXTENSION: &str = "response";
+
+/// The DiskCache struct
+///
+/// The DiskCache struct is the second tier of the response cache. It keeps responses
+/// as files in `dir`, named by a hash of their cache key, and once the files exceed
+/// `max_bytes` the least recently used ones are removed.
+///
+/// Files are read, written and removed outside of the lock. Every write goes to a new
+/// file, so removing a replaced or evicted file never touches one written since.
+///
+/// # Fields
+///
+/// * `dir` - The directory the responses are written to
+/// * `max_bytes` - The size bound for the files
+/// * `state` - The index of the stored responses, behind a lock
+/// * `writes` - Numbers the files, to keep them apart
+///
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    state: Mutex<DiskState>,
+    writes: AtomicU64,
+}
+
+#[derive(Default)]
+struct DiskState {
+    // Keyed by the hash of the cache key
+    entries: HashMap<String, DiskEntry>,
+    size: u64,
+    clock: u64,
+}
+
+struct DiskEntry {
+    file: String,
+    size: u64,
+    last_used: u64,
+    // When the response was first cached, in memory or on disk
+    stored: SystemTime,
+}
+
+impl DiskCache {
+    /// Create a disk cache in `dir`, removing the responses an earlier run left there
+    pub fn new(dir: PathBuf, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == EXTENSION)
+            {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(DiskCache {
+            dir,
+            max_bytes,
+            state: Mutex::new(DiskState::default()),
+            writes: AtomicU64::new(0),
+        })
+    }
+
+    /// Look up the response stored under a cache key, along with when it was first cached
+    pub fn get(&self, key: &str) -> Option<(Response, SystemTime)> {
+        let hash = hash(key);
+        let (file, stored) = {
+            let mut state = self.state.lock().unwrap();
+            state.clock += 1;
+            let clock = state.clock;
+            let entry = state.entries.get_mut(&hash)?;
+            entry.last_used = clock;
+            (entry.file.clone(), entry.stored)
+        };
+
+        let bytes = match fs::read(self.dir.join(&file)) {
+            Ok(bytes) => bytes,
+            // Replaced or evicted since the lookup
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                error!("Failed to read cached response {:#?}: {:#?}", file, e);
+                self.forget(&hash, &file);
+                return None;
+            }
+        };
+        match decode(&bytes) {
+            Some(response) => Some((response, stored)),
+            None => {
+                error!("Failed to decode cached response {:#?}", file);
+                self.forget(&hash, &file);
+                None
+            }
+        }
+    }
+
+    /// Store a response under a cache key, `stored` being when it was first cached
+    ///
+    /// Streamed bodies and responses larger than the cache are never stored
+    ///
+    pub fn insert(&self, key: &str, response: &Response, stored: SystemTime) {
+        if response.get_body().is_none() {
+            return;
+        }
+        let bytes = response.to_bytes();
+        let size = bytes.len() as u64;
+        if size > self.max_bytes {
+            return;
+        }
+
+        let hash = hash(key);
+        let write = self.writes.fetch_add(1, Ordering::Relaxed);
+        let file = format!("{}-{}.{}", hash, write, EXTENSION);
+        if let Err(e) = fs::write(self.dir.join(&file), &bytes) {
+            error!("Failed to write cached response {:#?}: {:#?}", file, e);
+            remove_files(&self.dir, [file]);
+            return;
+        }
+
+        let mut removed = Vec::new();
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let entry = DiskEntry {
+            file,
+            size,
+            last_used: state.clock,
+            stored,
+        };
+        removed.extend(state.remove(&hash));
+        state.entries.insert(hash, entry);
+        state.size += size;
+
+        while state.size > self.max_bytes {
+            removed.extend(state.evict_oldest());
+        }
+        drop(state);
+
+        remove_files(&self.dir, removed);
+    }
+
+    /// Remove every stored response
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.size = 0;
+        let removed: Vec<String> = state.entries.drain().map(|(_, entry)| entry.file).collect();
+        drop(state);
+
+        remove_files(&self.dir, removed);
+    }
+
+    /// Drop the entry of a file that can't be read back, unless it was replaced since
+    fn forget(&self, hash: &str, file: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .entries
+            .get(hash)
+            .is_some_and(|entry| entry.file == file)
+        {
+            let removed = state.remove(hash);
+            drop(state);
+            remove_files(&self.dir, removed);
+        }
+    }
+}
+
+impl DiskState {
+    /// Remove the least recently used entry, returning its file
+    fn evict_oldest(&mut self) -> Option<String> {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(hash, _)| hash.clone())?;
+        self.remove(&oldest)
+    }
+
+    /// Remove an entry, returning its file
+    fn remove(&mut self, hash: &str) -> Option<String> {
+        let entry = self.entries.remove(hash)?;
+        self.size -= entry.size;
+        Some(entry.file)
+    }
+}
+
+/// Remove the files of entries no longer in the index
+fn remove_files(dir: &Path, files: impl IntoIterator<Item = String>) {
+    for file in files {
+        if let Err(e) = fs::remove_file(dir.join(&file)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                error!("Failed to remove cached response {:#?}: {:#?}", file, e);
+            }
+        }
+    }
+}
+
+/// Hash a cache key to name its files, since keys hold arbitrary header values
+fn hash(key: &str) -> String {
+    Sha1::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Read back a response written with `Response::to_bytes`
+fn decode(bytes: &[u8]) -> Option<Response> {
+    let split = bytes.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&bytes[..split]).ok()?;
+    let body = bytes[split + 4..].to_vec();
+
+    let mut lines = head.split("\r\n");
+    let mut status_line = lines.next()?.splitn(3, ' ');
+    let _version = status_line.next()?;
+    let code = status_line.next()?.parse().ok()?;
+    let reason = status_line.next().unwrap_or_default();
+
+    let mut response = Response::new().status(code, reason);
+    for line in lines {
+        let (name, value) = line.split_once(": ")?;
+        // Derived from the body when the response is written
+        if !name.eq_ignore_ascii_case("content-length") {
+            response = response.header(name, value);
+        }
+    }
+    Some(response.body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("web-server-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = temp_dir("disk-cache-round-trip");
+        let cache = DiskCache::new(dir.clone(), 1024).unwrap();
+        let response = Response::new()
+            .header("Content-Type", "text/plain")
+            .body(b"hello".to_vec());
+        let stored = SystemTime::now();
+        cache.insert("GET /a", &response, stored);
+
+        let (cached, cached_stored) = cache.get("GET /a").unwrap();
+        assert_eq!(cached.to_bytes(), response.to_bytes());
+        assert_eq!(cached_stored, stored);
+        assert!(cache.get("GET /b").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let dir = temp_dir("disk-cache-eviction");
+        let response = Response::new().body(vec![0; 10]);
+        let size = response.to_bytes().len() as u64;
+        let cache = DiskCache::new(dir.clone(), size * 2).unwrap();
+
+        cache.insert("GET /a", &response, SystemTime::now());
+        cache.insert("GET /b", &response, SystemTime::now());
+        assert!(cache.get("GET /a").is_some());
+        cache.insert("GET /c", &response, SystemTime::now());
+
+        assert!(cache.get("GET /a").is_some());
+        assert!(cache.get("GET /b").is_none());
+        assert!(cache.get("GET /c").is_some());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replace() {
+        let dir = temp_dir("disk-cache-replace");
+        let cache = DiskCache::new(dir.clone(), 1024).unwrap();
+        cache.insert(
+            "GET /a",
+            &Response::new().body(b"old".to_vec()),
+            SystemTime::now(),
+        );
+        cache.insert(
+            "GET /a",
+            &Response::new().body(b"new".to_vec()),
+            SystemTime::now(),
+        );
+
+        let (cached, _) = cache.get("GET /a").unwrap();
+        assert_eq!(cached.get_body(), Some(&b"new"[..]));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        cache.clear();
+        assert!(cache.get("GET /a").is_none());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use serde::Deserialize;
+
+use crate::{access_log::json_string, request::Request, response::Response};
+
+/// How error responses, `4xx` and `5xx`, are rendered
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorFormat {
+    /// Error responses are sent as they were built
+    Plain,
+    /// Every error response gets a JSON body
+    Json,
+    /// Error responses get a JSON body when the request's `Accept` prefers JSON to HTML
+    Negotiate,
+}
+
+/// Give an error response the body of the chosen format
+///
+/// The body becomes `{"error":{"status":404,"message":"Not Found"}}`, built from the
+/// status line. Successful responses and ones that already are JSON are left alone.
+///
+pub fn render(request: &Request, response: Response, format: ErrorFormat) -> Response {
+    let json = match format {
+        ErrorFormat::Plain => false,
+        ErrorFormat::Json => true,
+        ErrorFormat::Negotiate => prefers_json(request.header("accept")),
+    };
+    let is_json = response
+        .get_header("Content-Type")
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    if !json || response.status_code() < 400 || is_json {
+        return response;
+    }
+
+    let body = format!(
+        "{{\"error\":{{\"status\":{},\"message\":{}}}}}",
+        response.status_code(),
+        json_string(&title_case(response.reason()))
+    );
+    response
+        .header("Content-Type", "application/json")
+        .body(body.into_bytes())
+}
+
+/// Whether an `Accept` header ranks `application/json` above `text/html`
+fn prefers_json(accept: Option<&str>) -> bool {
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return false,
+    };
+    let quality = |media_type: &str| {
+        accept
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';').map(str::trim);
+                if !params.next()?.eq_ignore_ascii_case(media_type) {
+                    return None;
+                }
+                match params.find_map(|param| param.strip_prefix("q=")) {
+                    Some(q) => q.parse::<f32>().ok(),
+                    None => Some(1.0),
+                }
+            })
+            .fold(0.0, f32::max)
+    };
+    quality("application/json") > quality("text/html")
+}
+
+/// Turn a reason phrase like `NOT FOUND` into `Not Found`
+fn title_case(reason: &str) -> String {
+    reason
+        .split(' ')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => {
+                    first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase()
+                }
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(accept: &str) -> Request {
+        let raw = format!("GET / HTTP/1.1\r\nAccept: {}\r\n\r\n", accept);
+        Request::parse_head(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_prefers_json() {
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("text/html;q=0.5, application/json")));
+        assert!(!prefers_json(Some(
+            "text/html,application/xhtml+xml,*/*;q=0.8"
+        )));
+        assert!(!prefers_json(Some("application/json;q=0")));
+        assert!(!prefers_json(None));
+    }
+
+    #[test]
+    fn test_render() {
+        let not_found = || Response::new().status(404, "NOT FOUND");
+        let json = render(&request("text/html"), not_found(), ErrorFormat::Json);
+        assert_eq!(json.get_header("Content-Type"), Some("application/json"));
+        assert_eq!(
+            json.get_body(),
+            Some(&b"{\"error\":{\"status\":404,\"message\":\"Not Found\"}}"[..])
+        );
+
+        let html = render(&request("text/html"), not_found(), ErrorFormat::Negotiate);
+        assert_eq!(html.get_body(), Some(&b""[..]));
+        let ok = render(
+            &request("application/json"),
+            Response::new(),
+            ErrorFormat::Json,
+        );
+        assert_eq!(ok.get_body(), Some(&b""[..]));
+    }
+}
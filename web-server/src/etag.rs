@@ -0,0 +1,100 @@
+use sha1::{Digest, Sha1};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Compute the strong `ETag` of a body, a quoted hash of its bytes
+pub fn strong(body: &[u8]) -> String {
+    let hash: String = Sha1::digest(body)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("\"{}\"", hash)
+}
+
+/// Compute the weak `ETag` of a file from its modification time and size
+///
+/// This avoids reading the file, at the cost of missing changes that keep both
+///
+pub fn from_metadata(modified: SystemTime, size: u64) -> String {
+    let mtime = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!("W/\"{}-{}\"", mtime, size)
+}
+
+/// Turn an `ETag` into a weak one, for bodies that are only semantically equivalent
+pub fn weaken(etag: &str) -> String {
+    if is_weak(etag) {
+        etag.to_string()
+    } else {
+        format!("W/{}", etag)
+    }
+}
+
+pub fn is_weak(etag: &str) -> bool {
+    etag.starts_with("W/")
+}
+
+/// Compare two `ETag`s as validators of the same bytes, weak ones never match
+pub fn strong_match(a: &str, b: &str) -> bool {
+    !is_weak(a) && !is_weak(b) && a == b
+}
+
+/// Compare two `ETag`s as validators of equivalent content, ignoring weakness
+pub fn weak_match(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+/// Whether an `If-None-Match` header matches `etag`, using weak comparison
+pub fn if_none_match(header: &str, etag: &str) -> bool {
+    header.trim() == "*" || header.split(',').any(|tag| weak_match(tag.trim(), etag))
+}
+
+/// Whether an `If-Match` header matches `etag`, using strong comparison
+pub fn if_match(header: &str, etag: &str) -> bool {
+    header.trim() == "*" || header.split(',').any(|tag| strong_match(tag.trim(), etag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strong_matching() {
+        assert!(strong_match("\"abc\"", "\"abc\""));
+        assert!(!strong_match("W/\"abc\"", "\"abc\""));
+        assert!(!strong_match("W/\"abc\"", "W/\"abc\""));
+        assert!(!strong_match("\"abc\"", "\"abd\""));
+
+        assert!(if_match("\"xyz\", \"abc\"", "\"abc\""));
+        assert!(!if_match("W/\"abc\"", "\"abc\""));
+        assert!(if_match("*", "W/\"abc\""));
+    }
+
+    #[test]
+    fn test_weak_matching() {
+        assert!(weak_match("W/\"abc\"", "\"abc\""));
+        assert!(weak_match("W/\"abc\"", "W/\"abc\""));
+        assert!(!weak_match("W/\"abc\"", "\"abd\""));
+
+        assert!(if_none_match("\"xyz\", W/\"abc\"", "\"abc\""));
+        assert!(if_none_match("\"abc\"", "W/\"abc\""));
+        assert!(!if_none_match("\"xyz\"", "\"abc\""));
+    }
+
+    #[test]
+    fn test_weaken() {
+        let etag = strong(b"hello");
+        assert_eq!(etag, "\"aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d\"");
+        assert_eq!(weaken(&etag), format!("W/{}", etag));
+        assert_eq!(weaken(&weaken(&etag)), weaken(&etag));
+    }
+
+    #[test]
+    fn test_from_metadata() {
+        let modified = UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let etag = from_metadata(modified, 512);
+        assert_eq!(etag, "W/\"1700000000-512\"");
+        assert!(is_weak(&etag));
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use crate::request::Request;
+
+/// The ClientInfo struct
+///
+/// The ClientInfo struct describes the client a request originally came from
+///
+/// # Fields
+///
+/// * `addr` - The client address, or an obfuscated identifier such as `_hidden` or `unknown`
+/// * `proto` - The protocol the client used, e.g. `https`
+/// * `host` - The host the client asked for
+///
+#[derive(Debug, PartialEq)]
+pub struct ClientInfo {
+    pub addr: String,
+    pub proto: String,
+    pub host: Option<String>,
+}
+
+/// Work out who sent a request
+///
+/// Proxy headers are only believed when `trust_proxy` is set: `Forwarded` (RFC 7239)
+/// is preferred, then `X-Forwarded-For` with `X-Forwarded-Proto` and `X-Forwarded-Host`.
+/// Anything they leave out comes from the connection itself.
+///
+/// # Arguments
+///
+/// * `request` - The request
+/// * `peer` - The address of the connected peer
+/// * `trust_proxy` - Whether the peer is a proxy whose headers can be trusted
+///
+pub fn client_info(request: &Request, peer: &str, trust_proxy: bool) -> ClientInfo {
+    let mut client = ClientInfo {
+        addr: peer.to_string(),
+        proto: String::from("http"),
+        host: request.header("host").map(str::to_string),
+    };
+    if !trust_proxy {
+        return client;
+    }
+
+    if let Some(forwarded) = request.header("forwarded") {
+        // The first element was added by the proxy closest to the client
+        if let Some(element) = parse_forwarded(forwarded).into_iter().next() {
+            if let Some(addr) = element.get("for") {
+                client.addr = strip_port(addr).to_string();
+            }
+            if let Some(proto) = element.get("proto") {
+                client.proto = proto.to_ascii_lowercase();
+            }
+            if let Some(host) = element.get("host") {
+                client.host = Some(host.to_string());
+            }
+        }
+        return client;
+    }
+
+    if let Some(addr) = request
+        .header("x-forwarded-for")
+        .and_then(|value| value.split(',').next())
+    {
+        client.addr = addr.trim().to_string();
+    }
+    if let Some(proto) = request.header("x-forwarded-proto") {
+        client.proto = proto.trim().to_ascii_lowercase();
+    }
+    if let Some(host) = request.header("x-forwarded-host") {
+        client.host = Some(host.trim().to_string());
+    }
+    client
+}
+
+/// Parse a `Forwarded` header into its elements
+///
+/// Each element maps lowercase parameter names to their (unquoted) values
+///
+pub fn parse_forwarded(value: &str) -> Vec<HashMap<String, String>> {
+    split_unquoted(value, ',')
+        .into_iter()
+        .map(|element| {
+            split_unquoted(&element, ';')
+                .into_iter()
+                .filter_map(|pair| {
+                    let (name, value) = pair.split_once('=')?;
+                    Some((name.trim().to_ascii_lowercase(), unquote(value.trim())))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Split on `separator`, ignoring separators inside quoted strings
+fn split_unquoted(value: &str, separator: char) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in value.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    parts.push(current.trim().to_string());
+
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut unquoted = String::new();
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
+/// Remove the port from a node, e.g. `[2001:db8::1]:4711` or `192.0.2.43:47011`
+pub fn strip_port(node: &str) -> &str {
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match node.split_once(':') {
+        Some((addr, _)) => addr,
+        None => node,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET / HTTP/1.1\r\nHost: internal\r\n{}\r\n", headers);
+        Request::parse_head(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_forwarded_with_multiple_elements() {
+        let request = request(
+            "Forwarded: for=\"[2001:db8:cafe::17]:4711\";proto=HTTPS;host=\"example.com\", for=192.0.2.60;by=203.0.113.43\r\n\
+             X-Forwarded-For: 198.51.100.1\r\n",
+        );
+
+        assert_eq!(
+            client_info(&request, "10.0.0.1", true),
+            ClientInfo {
+                addr: String::from("2001:db8:cafe::17"),
+                proto: String::from("https"),
+                host: Some(String::from("example.com")),
+            }
+        );
+    }
+
+    #[test]
+    fn test_forwarded_obfuscated() {
+        let request = request("Forwarded: for=_hidden;by=_proxy1\r\n");
+        assert_eq!(client_info(&request, "10.0.0.1", true).addr, "_hidden");
+    }
+
+    #[test]
+    fn test_x_forwarded_for() {
+        let request =
+            request("X-Forwarded-For: 198.51.100.1, 10.0.0.2\r\nX-Forwarded-Proto: https\r\n");
+
+        let client = client_info(&request, "10.0.0.1", true);
+        assert_eq!(client.addr, "198.51.100.1");
+        assert_eq!(client.proto, "https");
+
+        let client = client_info(&request, "10.0.0.1", false);
+        assert_eq!(client.addr, "10.0.0.1");
+        assert_eq!(client.host.as_deref(), Some("internal"));
+    }
+}
@@ -0,0 +1,85 @@
+/// Parse an `Accept-Language` header into the language ranges it accepts, most
+/// preferred first
+///
+/// Ranges are lowercased and ranges with the same q-value keep the order they were
+/// sent in. Ranges refused with `q=0` and the `*` wildcard are left out.
+///
+pub fn preferred_languages(header: &str) -> Vec<String> {
+    let mut languages: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let language = params.next()?.to_ascii_lowercase();
+            let q = params
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            if language.is_empty() || language == "*" || q <= 0.0 {
+                return None;
+            }
+            Some((language, q))
+        })
+        .collect();
+
+    // A stable sort, so ties keep the client's order
+    languages.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    languages
+        .into_iter()
+        .map(|(language, _)| language)
+        .collect()
+}
+
+/// The locales to look for a page in, in order
+///
+/// Each language the client prefers is followed by its primary subtag, so `fr-CH`
+/// also finds `fr` pages, and `default` comes last
+///
+/// # Arguments
+///
+/// * `accept_language` - The request's `Accept-Language` header, if any
+/// * `default` - The locale used when the client accepts none that exist
+///
+pub fn locales(accept_language: Option<&str>, default: &str) -> Vec<String> {
+    let mut locales: Vec<String> = Vec::new();
+    let languages = accept_language.map(preferred_languages).unwrap_or_default();
+    for language in languages {
+        let primary = language.split('-').next().unwrap_or_default().to_string();
+        for locale in [language, primary] {
+            if !locales.contains(&locale) {
+                locales.push(locale);
+            }
+        }
+    }
+    let default = default.to_ascii_lowercase();
+    if !locales.contains(&default) {
+        locales.push(default);
+    }
+    locales
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preferred_languages() {
+        assert_eq!(
+            preferred_languages("en;q=0.5, fr-CH, fr;q=0.9, de;q=0, *;q=0.1"),
+            vec!["fr-ch", "fr", "en"]
+        );
+        assert_eq!(
+            preferred_languages("da, en-GB;q=0.8, en;q=0.8"),
+            vec!["da", "en-gb", "en"]
+        );
+        assert!(preferred_languages("").is_empty());
+    }
+
+    #[test]
+    fn test_locales() {
+        assert_eq!(
+            locales(Some("fr-CH, de;q=0.5"), "en"),
+            vec!["fr-ch", "fr", "de", "en"]
+        );
+        assert_eq!(locales(Some("EN-us"), "en"), vec!["en-us", "en"]);
+        assert_eq!(locales(None, "en"), vec!["en"]);
+    }
+}
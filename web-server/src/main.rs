@@ -1,26 +1,513 @@
-use lazy_static::lazy_static;
-use log::{error, info};
+use log::{debug, error, info, warn};
 use simple_logger::SimpleLogger;
 use std::{
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
+    collections::HashMap,
+    env,
+    fs::{self, File},
+    io::{self, prelude::*, BufReader},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
+#[cfg(unix)]
+use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+
+mod access_log;
+mod args;
+mod bundle;
+mod cache;
+mod compression;
+mod config;
+mod connection;
+mod date;
+mod disk_cache;
+mod error_format;
+mod etag;
+mod forwarded;
+mod language;
+mod method;
+mod middleware;
+mod mime;
+mod range;
+mod request;
+mod response;
 mod router;
+mod signal;
+#[cfg(test)]
+mod temp_pages;
 mod thread_pool;
+mod throttle;
+mod websocket;
+
+use access_log::StructuredLogEntry;
+use args::Args;
+use cache::{ResponseCache, CACHED_ENCODINGS};
+use compression::CompressionStep;
+use config::{ConfigError, MaintenanceConfig, RedirectConfig, RobotsConfig, ServerConfig};
+use connection::{Connection, ConnectionReader, Protocol};
+use disk_cache::DiskCache;
+use method::Method;
+use request::{ParseError, Request};
+use response::{Response, WriteError};
+use router::{RouteTable, Router, FILE_METHODS};
+use thread_pool::ThreadPool;
+use throttle::ThrottledWriter;
+use websocket::{WebSocket, WebSocketHandler};
+
+/// How often the accept loop is checked on for a stop signal
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How much of a streamed file is read up front to sniff its content type
+const SNIFF_BYTES: u64 = 512;
+
+/// The Server struct
+///
+/// The Server struct holds the state shared by every connection
+///
+/// # Fields
+///
+/// * `router` - The routes being served
+/// * `config` - The server configuration
+/// * `cache` - The rendered response cache
+/// * `shutting_down` - Set once the server has been asked to stop
+/// * `websocket_routes` - The handlers for paths that accept WebSocket upgrades
+/// * `bundles` - The responses of the configured bundles, by path
+/// * `keep_alive_connections` - How many connections are currently being kept alive
+/// * `cache_generation` - The newest route table generation seen, the cache is cleared
+///   when it goes up
+/// * `background` - The pool stale cached responses are rendered again and the cache
+///   is warmed on, only there when they can be served while stale or it is warmed
+///
+struct Server {
+    router: Router,
+    config: Arc<ServerConfig>,
+    cache: Arc<ResponseCache>,
+    shutting_down: AtomicBool,
+    websocket_routes: HashMap<String, WebSocketHandler>,
+    bundles: HashMap<String, Response>,
+    keep_alive_connections: AtomicUsize,
+    cache_generation: AtomicU64,
+    background: Option<ThreadPool>,
+}
+
+impl Server {
+    fn new(mut router: Router, config: ServerConfig) -> Self {
+        let mut cache = ResponseCache::new(config.response_cache_max_bytes);
+        if let Some(disk) = &config.disk_cache {
+            match DiskCache::new(disk.dir.clone(), disk.max_bytes) {
+                Ok(disk) => cache = cache.with_disk(disk),
+                Err(e) => error!("Failed to open disk cache {:#?}: {:#?}", disk.dir, e),
+            }
+        }
+        let mut background = None;
+        if let Some(max_age) = config.response_cache_max_age {
+            let stale = config.response_cache_stale_while_revalidate;
+            cache = cache.with_max_age(max_age, stale);
+            if !stale.is_zero() {
+                background = Some(ThreadPool::new(1));
+            }
+        }
+        if config.warm_cache && background.is_none() {
+            background = Some(ThreadPool::new(1));
+        }
+        let bundles = build_bundles(router.root(), &config);
+        let config = Arc::new(config);
+        router.add_outer_middleware(middleware::log_requests(Arc::clone(&config)));
+        Server {
+            router,
+            config,
+            cache: Arc::new(cache),
+            shutting_down: AtomicBool::new(false),
+            websocket_routes: HashMap::new(),
+            bundles,
+            keep_alive_connections: AtomicUsize::new(0),
+            cache_generation: AtomicU64::new(0),
+            background,
+        }
+    }
+
+    /// Render the pages into the response cache on the background pool
+    ///
+    /// Every request path that resolves to a file is cached, as if requested without
+    /// `Accept-Encoding`, until `warm_cache_max_bytes` of files have been read. Pages
+    /// picked by `Accept-Language` aren't warmed, they depend on the client.
+    ///
+    fn warm_cache(&self) {
+        let config = Arc::clone(&self.config);
+        if !config.warm_cache {
+            return;
+        }
+        if config.default_locale.is_some() {
+            warn!("Not warming the response cache, pages are localized");
+            return;
+        }
+        let pool = match &self.background {
+            Some(pool) => pool,
+            None => return,
+        };
+        let routes = self.router.table();
+        let cache = Arc::clone(&self.cache);
+
+        let result = pool.execute(move || {
+            let start = Instant::now();
+            let mut files: Vec<_> = routes.get_routes().iter().collect();
+            files.sort();
+            info!("Warming the response cache with {} files", files.len());
+            let budget = config.warm_cache_max_bytes.unwrap_or(u64::MAX);
+            let (mut loaded, mut warmed) = (0, 0);
+            for (route, file) in files {
+                let size = fs::metadata(file).map_or(0, |metadata| metadata.len());
+                if loaded + size > budget {
+                    debug!("Not warming {:#?}, it is past the size limit", route);
+                    continue;
+                }
+                loaded += size;
+                for path in routes.request_paths(route, &config.path_resolution) {
+                    // Once for each set of codings a client may accept, as each has
+                    // its own entry
+                    for accepted in warm_encodings() {
+                        let mut request = Request::new("GET", &path);
+                        if !accepted.is_empty() {
+                            request
+                                .headers
+                                .insert(String::from("accept-encoding"), accepted);
+                        }
+                        let response = render_page(&request, &routes, file, None, &config);
+                        cache.insert(&request, &response);
+                    }
+                    debug!("Warmed {:#?} in the response cache", path);
+                }
+                warmed += 1;
+            }
+            info!(
+                "Warmed the response cache with {} files, {} bytes, in {:?}",
+                warmed,
+                loaded,
+                start.elapsed()
+            );
+        });
+        if let Err(e) = result {
+            warn!("Failed to schedule warming the response cache: {:#?}", e);
+        }
+    }
+
+    /// Take one of the `max_keep_alive` slots, or `None` if they are all in use
+    fn try_keep_alive(&self) -> Option<KeepAliveSlot<'_>> {
+        let max = self.config.max_keep_alive.unwrap_or(usize::MAX);
+        self.keep_alive_connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .ok()?;
+        Some(KeepAliveSlot(&self.keep_alive_connections))
+    }
+
+    /// Register a handler that takes over connections upgraded to a WebSocket on `path`
+    fn add_websocket_route(&mut self, path: &str, handler: WebSocketHandler) {
+        self.websocket_routes.insert(path.to_string(), handler);
+    }
+}
+
+/// Every `Accept-Encoding` the cache keys a page by, each subset of `CACHED_ENCODINGS`
+fn warm_encodings() -> Vec<String> {
+    (0..1 << CACHED_ENCODINGS.len())
+        .map(|subset: usize| {
+            let encodings: Vec<&str> = CACHED_ENCODINGS
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| subset & (1 << i) != 0)
+                .map(|(_, encoding)| encoding)
+                .collect();
+            encodings.join(", ")
+        })
+        .collect()
+}
+
+/// A connection's place in the count of kept alive connections, given back on drop
+struct KeepAliveSlot<'a>(&'a AtomicUsize);
+
+impl Drop for KeepAliveSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
-lazy_static! {
-    static ref ROUTES: router::Router = router::Router::new();
+/// Build the configured bundles, leaving out any whose files can't be read
+fn build_bundles(root: &Path, config: &ServerConfig) -> HashMap<String, Response> {
+    let mut bundles = HashMap::new();
+    for bundle in &config.bundles {
+        match bundle::build(root, bundle) {
+            Ok(response) => {
+                info!("Bundled {:#?} from {:#?}", bundle.path, bundle.files);
+                bundles.insert(bundle.path.clone(), response);
+            }
+            Err(e) => error!("Failed to build bundle {:#?}: {:#?}", bundle.path, e),
+        }
+    }
+    bundles
 }
 
 fn main() {
     SimpleLogger::new().init().unwrap();
-    let _router: &router::Router = &*ROUTES;
+    let args = match Args::parse(env::args_os().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            error!("Invalid arguments: {}", e);
+            error!(
+                "Usage: web-server [--addr HOST:PORT] [--workers N] [--read-timeout SECONDS] \
+                 [--set KEY=VALUE]... [CONFIG]"
+            );
+            process::exit(2);
+        }
+    };
+    let mut config = load_config(&args);
+    if let Some(timeout) = args.read_timeout {
+        config.header_read_timeout = timeout;
+    }
+    let router = match config.reload_interval {
+        Some(interval) => Router::new_watching(config.scan_limit, interval),
+        None => Router::new(config.scan_limit),
+    };
+    let mut router = match router {
+        Ok(router) => router,
+        Err(e) => {
+            error!("Failed to read the pages directory: {:#?}", e);
+            process::exit(1);
+        }
+    };
+    if config.fingerprint_assets {
+        if let Err(e) = router.fingerprint_assets() {
+            error!("Failed to fingerprint assets: {:#?}", e);
+        }
+    }
+    router.add_route("/health", |_| b"ok".to_vec());
+    if let Some(key) = &config.api_key {
+        router.add_middleware(middleware::require_api_key(key.clone()));
+    }
+    // A `404.html` in pages takes the place of the plain text message
+    if router.not_found_page().is_none() {
+        router.set_not_found_handler(Box::new(|request| {
+            Response::new()
+                .status(404, "NOT FOUND")
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .body(format!("No page at {}\n", request.path).into_bytes())
+        }));
+    }
+
+    info!(
+        "Serving {} files from pages",
+        router.table().get_routes().len()
+    );
+    if router.table().is_partial() {
+        warn!("Some files in pages are not served, they are past the scan limit");
+    }
+
+    let mut server = Server::new(router, config);
+    server.add_websocket_route("/ws/echo", Box::new(websocket::echo));
+    server.warm_cache();
+    let server = Arc::new(server);
+
+    let config = &server.config;
+    let listener =
+        match connection::bind_listener(&args.addr, config.reuse_address, config.reuse_port) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to listen on {}: {:#?}", args.addr, e);
+                process::exit(1);
+            }
+        };
+    info!("Listening on {} with {} workers", args.addr, args.workers);
+    let pool = Arc::new(ThreadPool::new(args.workers));
+
+    signal::install();
+    let addr = listener.local_addr().unwrap();
+    #[cfg(unix)]
+    let unix_socket = server.config.unix_socket.clone();
+    thread::spawn(move || {
+        while !signal::stop_requested() {
+            thread::sleep(SIGNAL_POLL_INTERVAL);
+        }
+        // Accepting blocks, so wake the listeners with connections of our own to notice
+        // the signal
+        let _ = TcpStream::connect(addr);
+        #[cfg(unix)]
+        if let Some(path) = unix_socket {
+            let _ = std::os::unix::net::UnixStream::connect(path);
+        }
+    });
+
+    #[cfg(unix)]
+    let unix_listener = match &server.config.unix_socket {
+        Some(path) => match bind_unix(path) {
+            Ok((listener, socket_file)) => {
+                let pool = Arc::clone(&pool);
+                let server = Arc::clone(&server);
+                let accepting = thread::spawn(move || serve_unix(listener, &pool, &server));
+                Some((accepting, socket_file))
+            }
+            Err(e) => {
+                error!("Failed to bind Unix socket {:#?}: {:#?}", path, e);
+                None
+            }
+        },
+        None => None,
+    };
+    #[cfg(not(unix))]
+    if server.config.unix_socket.is_some() {
+        error!("Unix sockets are not supported on this platform");
+    }
 
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = thread_pool::ThreadPool::new(4);
+    let max_workers = server.config.max_workers.unwrap_or_default();
+    for stream in listener.incoming() {
+        if signal::stop_requested() {
+            info!("Stop requested, no longer accepting connections");
+            break;
+        }
+        let stream = match stream {
+            Err(e) => {
+                error!("Failed to establish a connection: {:#?}", e);
+                continue;
+            }
+            Ok(stream) => stream,
+        };
+        if let Some(keepalive) = &server.config.tcp_keepalive {
+            let result = connection::set_tcp_keepalive(&stream, keepalive.idle, keepalive.interval);
+            if let Err(e) = result {
+                warn!("Failed to enable TCP keepalive: {:#?}", e);
+            }
+        }
+
+        let server = Arc::clone(&server);
+        let result = pool.execute(move || {
+            handle_tcp_connection(stream, &server);
+        });
+
+        match result {
+            Ok(()) => info!("Connection established!"),
+            Err(e) => warn!("Dropping connection, thread pool refused it: {:#?}", e),
+        }
+        let stats = pool.stats();
+        if stats.queued > 0 {
+            debug!(
+                "{} of {} workers busy, {} connections waiting",
+                stats.active, stats.workers, stats.queued
+            );
+            if stats.workers < max_workers {
+                info!("Growing the pool to {} workers", stats.workers + 1);
+                pool.set_size(stats.workers + 1);
+            }
+        }
+    }
+
+    // The Unix socket listener holds the pool as well, it stops on the same signal
+    #[cfg(unix)]
+    let _socket_file = unix_listener.map(|(accepting, socket_file)| {
+        if accepting.join().is_err() {
+            error!("The Unix socket listener panicked");
+        }
+        socket_file
+    });
+
+    server.shutting_down.store(true, Ordering::SeqCst);
+    pool.shutdown();
+    for stat in pool.worker_stats() {
+        info!(
+            "Worker {} served {} connections, busy for {:?}",
+            stat.id, stat.jobs, stat.busy
+        );
+    }
+    // Dropping the pool waits for the connections being served
+    drop(pool);
+}
+
+/// Load the configuration file given on the command line or by the `CONFIG` variable
+///
+/// Without either the defaults are used. `WEB_SERVER_` environment variables override
+/// the file's values and `--set` flags override both, see `config::env_overrides`. A
+/// configuration that fails to load stops the server.
+///
+fn load_config(args: &Args) -> ServerConfig {
+    let path = args
+        .config
+        .clone()
+        .or_else(|| env::var_os("CONFIG").map(PathBuf::from));
+    let mut overrides =
+        config::env_overrides(env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        }));
+    overrides.extend(args.overrides.iter().cloned());
+
+    match ServerConfig::load(path.as_deref(), &overrides) {
+        Ok(config) => {
+            if let Some(path) = path {
+                info!("Loaded configuration from {:#?}", path);
+            }
+            if !overrides.is_empty() {
+                info!("Overrode configuration settings: {:?}", overrides);
+            }
+            config
+        }
+        Err(ConfigError::Read(e)) => {
+            error!(
+                "Failed to read configuration {:#?}: {:#?}",
+                path.unwrap(),
+                e
+            );
+            process::exit(1);
+        }
+        Err(ConfigError::Parse(e)) => {
+            match path {
+                Some(path) => error!("Invalid configuration {:#?}: {}", path, e),
+                None => error!("Invalid configuration: {}", e),
+            }
+            process::exit(1);
+        }
+    }
+}
+
+/// Removes the Unix socket file when the server stops
+#[cfg(unix)]
+struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            error!("Failed to remove Unix socket {:#?}: {:#?}", self.0, e);
+        }
+    }
+}
+
+/// Bind a Unix socket, replacing a socket file left behind by an earlier run
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> io::Result<(UnixListener, SocketFile)> {
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
 
+    let listener = UnixListener::bind(path)?;
+    info!("Listening on Unix socket {:#?}", path);
+    Ok((listener, SocketFile(path.to_path_buf())))
+}
+
+/// Accept connections on a Unix socket alongside the TCP listener
+///
+/// Stops once a stop is requested, see `signal::stop_requested`
+///
+#[cfg(unix)]
+fn serve_unix(listener: UnixListener, pool: &ThreadPool, server: &Arc<Server>) {
     for stream in listener.incoming() {
+        if signal::stop_requested() {
+            info!("Stop requested, no longer accepting connections on the Unix socket");
+            break;
+        }
         let stream = match stream {
             Err(e) => {
                 error!("Failed to establish a connection: {:#?}", e);
@@ -29,50 +516,3409 @@ fn main() {
             Ok(stream) => stream,
         };
 
-        pool.execute(|| {
-            handle_connection(stream);
+        let server = Arc::clone(server);
+        let result = pool.execute(move || {
+            handle_connection(stream, &server);
         });
 
-        info!("Connection established!")
+        if let Err(e) = result {
+            warn!("Dropping connection, thread pool refused it: {:#?}", e);
+        }
+    }
+}
+
+/// A TLS alert record: a fatal `handshake_failure`
+const TLS_HANDSHAKE_FAILURE: [u8; 7] = [0x15, 0x03, 0x01, 0x00, 0x02, 0x02, 0x28];
+
+/// How much of a refused TLS handshake is read before closing
+const TLS_DRAIN_BYTES: u64 = 16 * 1024;
+
+/// How long a refused TLS client is given to close after the alert
+const TLS_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Serve a TCP connection, first turning away clients that start a TLS handshake
+/// when `detect_tls` is set
+fn handle_tcp_connection(stream: TcpStream, server: &Server) {
+    if server.config.detect_tls {
+        if let Err(e) = stream.set_read_timeout(Some(server.config.header_read_timeout)) {
+            error!("Failed to set read timeout: {:#?}", e);
+            return;
+        }
+        match connection::sniff_protocol(&stream) {
+            Ok(Some(Protocol::Tls)) => {
+                reject_tls(stream);
+                return;
+            }
+            // A connection closed without a request is noticed by `handle_connection`
+            Ok(Some(Protocol::Http)) | Ok(None) => {}
+            Err(e) if is_timeout(&e) || is_disconnect(&e) => {
+                debug!("Connection closed before its protocol was known: {:#?}", e);
+                return;
+            }
+            Err(e) => {
+                error!("Failed to read from connection: {:#?}", e);
+                return;
+            }
+        }
     }
+    handle_connection(stream, server);
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let router: &router::Router = &*ROUTES;
+/// Answer a TLS handshake with an alert, so the client fails fast instead of waiting
+fn reject_tls(mut stream: TcpStream) {
+    warn!(
+        "Refusing TLS handshake from {}, TLS is not served here",
+        stream.peer()
+    );
+    if let Err(e) = stream.write_all(&TLS_HANDSHAKE_FAILURE) {
+        debug!("Failed to send TLS alert: {:#?}", e);
+        return;
+    }
+    // Closing with the rest of the handshake unread would reset the connection and
+    // could discard the alert, so wait briefly for the client to close first
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+    let _ = stream.set_read_timeout(Some(TLS_DRAIN_TIMEOUT));
+    let _ = io::copy(&mut (&stream).take(TLS_DRAIN_BYTES), &mut io::sink());
+}
+
+/// Serve requests on a connection until the client closes it or a timeout expires
+///
+/// The first request is read with the header timeout, later requests on the same
+/// connection must start arriving within the keep-alive timeout
+///
+fn handle_connection<S: Connection + 'static>(stream: S, server: &Server) {
+    let config = &server.config;
+    let cache = &server.cache;
+    let peer = stream.peer();
+    let mut reader: ConnectionReader = BufReader::new(Box::new(stream));
+    let mut idle_timeout = config.header_read_timeout;
+    let mut keep_alive_slot = None;
+    let mut request_number = 0;
+
+    loop {
+        if !wait_for_request(&mut reader, idle_timeout, request_number == 0) {
+            return;
+        }
+
+        if !set_read_timeout(&reader, config.header_read_timeout) {
+            return;
+        }
+        let started = Instant::now();
+        let mut request = match Request::parse_head(&mut reader) {
+            Ok(request) => request,
+            Err(e) => {
+                handle_parse_error(&mut reader, e, "headers");
+                return;
+            }
+        };
+        request_number += 1;
+        // Methods are case-sensitive, but some clients send them in lowercase
+        if request.method.bytes().any(|b| b.is_ascii_lowercase()) {
+            if config.strict_method_case {
+                let reason = format!("Method is not uppercase: {:?}", request.method);
+                handle_parse_error(&mut reader, ParseError::Malformed(reason), "headers");
+                return;
+            }
+            request.method.make_ascii_uppercase();
+        }
+
+        if !set_read_timeout(&reader, config.body_read_timeout) {
+            return;
+        }
+        if let Err(e) = request.read_body(&mut reader, config.max_body_bytes) {
+            handle_parse_error(&mut reader, e, "body");
+            return;
+        }
+
+        let client = forwarded::client_info(&request, &peer, config.trust_proxy);
+        request.client_addr = Some(client.addr.clone());
+        let quiet = config.is_quiet_path(&request.path);
+        debug!(
+            "Client: {:#?}, protocol: {:#?}, Host: {:#?}",
+            client.addr, client.proto, client.host
+        );
+
+        if config.matrix_params {
+            request.strip_matrix_params();
+            debug!("Matrix parameters: {:?}", request.matrix_params);
+        }
+        if let Err(e) = request.normalize_path() {
+            handle_parse_error(&mut reader, e, "target");
+            return;
+        }
+        // Queries past `max_query_bytes` are refused below without being parsed
+        let query_len = request.query().map(str::len);
+        if query_len.is_some_and(|len| len <= config.max_query_bytes) {
+            debug!("Query parameters: {:?}", request.query_params());
+        }
+        // The target as it was sent, still encoded, so nothing decoded is echoed back
+        let canonical_location = config
+            .canonical_host_for(client.host.as_deref())
+            .map(|host| {
+                let query = request.query().map(|query| format!("?{}", query));
+                format!(
+                    "{}://{}{}{}",
+                    client.proto,
+                    host,
+                    request.raw_path,
+                    query.unwrap_or_default()
+                )
+            });
+        let mut outside_prefix = false;
+        if let Some(prefix) = &config.strip_prefix {
+            outside_prefix = !request.strip_prefix(prefix);
+        }
+
+        let shutting_down = server.shutting_down.load(Ordering::SeqCst);
+        if let (true, Some(retry_after)) = (shutting_down, config.shutdown_retry_after) {
+            warn!("Rejecting {:#?}, server is shutting down", request.path);
+            let response = Response::new()
+                .status(503, "SERVICE UNAVAILABLE")
+                .header("Retry-After", &retry_after.as_secs().to_string())
+                .header("Connection", "close");
+            let _ = reader.get_mut().write_all(&response.to_bytes());
+            return;
+        }
+
+        let misdirected = config.strict_vhost && !config.is_virtual_host(client.host.as_deref());
+        let maintenance = config
+            .maintenance
+            .as_ref()
+            .filter(|maintenance| maintenance.applies_to(&request.path));
+
+        let websocket = server
+            .websocket_routes
+            .get(&request.path)
+            .filter(|_| websocket::is_upgrade(&request) && !misdirected && maintenance.is_none());
+
+        let wants_keep_alive = config.keep_alive && request.keep_alive();
+        if wants_keep_alive && keep_alive_slot.is_none() {
+            keep_alive_slot = server.try_keep_alive();
+            if keep_alive_slot.is_none() {
+                warn!("Too many kept alive connections, closing after this response");
+            }
+        }
+        let keep_alive = wants_keep_alive && keep_alive_slot.is_some();
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+
+        request.deadline = Some(Instant::now() + config.timeout_for(&request.path));
+
+        // HTTP/1.0 clients don't expect informational responses
+        let early_hints = config
+            .early_hints_for(&request.path)
+            .filter(|_| request.version == "HTTP/1.1" && !misdirected && maintenance.is_none());
+        // Set when the request got past the middleware to an upgrade, or to hints that
+        // could not be written
+        let mut upgrade = false;
+        let mut hints_failed = false;
+
+        // The phases measured for `Server-Timing`
+        let mut timings = Vec::new();
+        // Set for pages from the cache or rendered into it, which are already tagged
+        // and compressed
+        let mut encoded = false;
+
+        let route_start = Instant::now();
+        let method = Method::from(request.method.as_str());
+        let handler = server
+            .router
+            .find_handler(&method, &request.path, config.head_from_get);
+        let routes = server.router.table();
+        // The pages cached from older routes may have changed or be gone
+        if server
+            .cache_generation
+            .fetch_max(routes.generation(), Ordering::SeqCst)
+            < routes.generation()
+        {
+            cache.clear();
+        }
+        let localized = config
+            .default_locale
+            .as_ref()
+            .map(|default| language::locales(request.header("accept-language"), default));
+        let localized = localized.as_deref().and_then(|locales| {
+            routes.resolve_localized(&request.path, &config.path_resolution, locales)
+        });
+        let route_data = match localized {
+            Some((file, _)) => Some(file),
+            None => routes.resolve(&request.path, &config.path_resolution),
+        };
+        timings.push(("route", route_start.elapsed()));
+        let serves_file = route_data.is_some() || server.bundles.contains_key(&request.path);
+        let reads_file = FILE_METHODS.contains(&method);
+
+        // Upgrades and early hints only happen once the middleware, e.g. an API key
+        // check, passed the request on
+        let response = server.router.run_middleware(&request, || {
+            if websocket.is_some() {
+                upgrade = true;
+                return Response::new().status(101, "SWITCHING PROTOCOLS");
+            }
+            if let Some(links) = early_hints {
+                let links = fingerprint_links(links, &server.router);
+                let hints = Response::new()
+                    .status(103, "EARLY HINTS")
+                    .header("Link", &links.join(", "));
+                if let Err(e) = reader.get_mut().write_all(&hints.to_bytes()) {
+                    error!("Failed to write early hints: {:#?}", e);
+                    hints_failed = true;
+                    return Response::new().status(500, "INTERNAL SERVER ERROR");
+                }
+            }
+
+            if let Some(maintenance) = maintenance {
+                warn!("Rejecting {:#?}, server is in maintenance", request.path);
+                handle_maintenance(maintenance)
+            } else if let Some(location) = canonical_location {
+                info!("Redirecting {:#?} to the canonical host", client.host);
+                Response::new()
+                    .status(301, "MOVED PERMANENTLY")
+                    .header("Location", &location)
+            } else if misdirected {
+                warn!("Rejecting request for unknown host {:#?}", client.host);
+                match config.vhost_reject_status.code() {
+                    404 => Response::new().status(404, "NOT FOUND"),
+                    _ => Response::new().status(421, "MISDIRECTED REQUEST"),
+                }
+            } else if outside_prefix && config.require_prefix {
+                warn!(
+                    "Rejecting {:#?}, it is outside of {:#?}",
+                    request.path, config.strip_prefix
+                );
+                handle_not_found(server.router.not_found_page())
+            } else if let Some(redirect) = config.redirect_for(&request.path) {
+                info!("Redirecting {:#?} to {:#?}", request.path, redirect.to);
+                handle_redirect(redirect, request.query())
+            } else if !config.is_method_allowed(&method) {
+                warn!("Method {} not allowed on this server", request.method);
+                let allowed = config.allowed_methods.as_deref().unwrap_or_default();
+                handle_method_not_allowed(allowed, config)
+            } else if request
+                .query()
+                .is_some_and(|query| query.len() > config.max_query_bytes)
+            {
+                warn!(
+                    "Rejecting query string longer than {} bytes",
+                    config.max_query_bytes
+                );
+                Response::new().status(414, "URI TOO LONG")
+            } else if !has_accepted_content_type(&request, config) {
+                warn!(
+                    "Rejecting {:#?} body of {:#?}",
+                    request.header("content-type"),
+                    request.path
+                );
+                Response::new().status(415, "UNSUPPORTED MEDIA TYPE")
+            } else if request.body.is_empty()
+                && config.requires_body(&request.method, &request.path)
+            {
+                warn!(
+                    "Rejecting {} {:#?} without a body",
+                    request.method, request.path
+                );
+                Response::new()
+                    .status(400, "BAD REQUEST")
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .body(
+                        format!(
+                            "{} {} requires a request body\n",
+                            request.method, request.path
+                        )
+                        .into_bytes(),
+                    )
+            } else if config.debug_echo && request.path == "/debug/echo" {
+                handle_echo(&request)
+            } else if let Some(handler) = handler {
+                limit_response_size(&request, handler(&request), config)
+            } else if let Some(mut allowed) = server
+                .router
+                .allowed_methods(&request.path, config.head_from_get)
+                .filter(|_| !(serves_file && reads_file))
+            {
+                warn!(
+                    "Method {} not allowed on {:#?}",
+                    request.method, request.path
+                );
+                // e.g. a page with a `POST` handler for its form
+                if serves_file {
+                    allowed.extend(FILE_METHODS);
+                    allowed.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+                    allowed.dedup();
+                }
+                handle_method_not_allowed(&allowed, config)
+            } else if !reads_file && serves_file {
+                warn!(
+                    "Method {} not allowed on file {:#?}",
+                    request.method, request.path
+                );
+                handle_method_not_allowed(&FILE_METHODS, config)
+            } else if let Some(response) = server
+                .bundles
+                .get(&request.path)
+                .and_then(Response::try_clone)
+            {
+                // Bundles are built once at startup
+                response.header("Date", &date::http_date(SystemTime::now()))
+            } else if let Some(hit) = cache.get(&request) {
+                debug!(
+                    "Serving {:#?} from the response cache ({} hits)",
+                    request.path,
+                    cache.hits()
+                );
+                if let (true, Some(route_data)) = (hit.refresh, route_data) {
+                    let locale = localized.map(|(_, locale)| locale);
+                    refresh_cached(server, &request, &routes, route_data, locale);
+                }
+                encoded = true;
+                hit.response
+            } else {
+                match route_data {
+                    Some(route_data) => {
+                        let file_start = Instant::now();
+                        let locale = localized.map(|(_, locale)| locale);
+                        let response = render_page(&request, &routes, route_data, locale, config);
+                        timings.push(("file", file_start.elapsed()));
+                        cache.insert(&request, &response);
+                        encoded = true;
+                        response
+                    }
+                    None => match &config.robots {
+                        Some(robots) if request.path == "/robots.txt" => handle_robots(robots),
+                        _ if routes.is_directory(&request.path) => {
+                            warn!("Directory {:#?} has no index to serve", request.path);
+                            match config.directory_status.code() {
+                                404 => handle_not_found(server.router.not_found_page()),
+                                _ => Response::new().status(403, "FORBIDDEN"),
+                            }
+                        }
+                        _ => {
+                            error!("Route not found: {:#?}", request.path);
+                            match server.router.not_found_handler() {
+                                Some(handler) => handler(&request),
+                                None => handle_not_found(server.router.not_found_page()),
+                            }
+                        }
+                    },
+                }
+            }
+        });
+        if let (true, Some(handler)) = (upgrade, websocket) {
+            upgrade_connection(reader, &request, handler);
+            return;
+        }
+        if hints_failed {
+            return;
+        }
+        let response = error_format::render(&request, response, config.error_format);
+        let response = if encoded {
+            response
+        } else {
+            let step = compression::choose(&config.compression_chain, &request, |_| false);
+            encode(response, step, config)
+        };
+        let response = range::apply(&request, check_preconditions(&request, response));
+        let mut response = response.header("Connection", connection);
+        // TLS is terminated in front of the server, so only a trusted proxy can say
+        // the connection is secure
+        if client.proto == "https" {
+            response = add_transport_security(response, config);
+        }
+        if config.server_timing {
+            response = response.header("Server-Timing", &server_timing(&timings));
+        }
+        let response = add_response_headers(response, config);
 
-    let buf_reader = BufReader::new(&mut stream);
-    let request_line = buf_reader.lines().next().unwrap().unwrap();
+        let remaining = match request.time_remaining() {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => {
+                warn!("Request {:#?} ran past its deadline", request.path);
+                return;
+            }
+        };
+        if let Err(e) = reader.get_ref().set_write_timeout(Some(remaining)) {
+            error!("Failed to set write timeout: {:#?}", e);
+            return;
+        }
+        let deadline = Instant::now() + remaining;
 
-    info!("Request: {:#?}", request_line);
+        let include_body = request.method != "HEAD";
+        let mut entry = StructuredLogEntry {
+            method: &request.method,
+            path: &request.path,
+            status: response.status_code(),
+            duration: Duration::ZERO,
+            bytes: if include_body {
+                response.content_length()
+            } else {
+                0
+            },
+            peer: &client.addr,
+            request_id: request.header("x-request-id"),
+            user_agent: request.header("user-agent"),
+            referer: request.header("referer"),
+            request_number,
+        };
+        let result = match config.max_bytes_per_sec.filter(|rate| *rate > 0) {
+            Some(rate) => response.write_to(
+                &mut ThrottledWriter::new(reader.get_mut(), rate, deadline),
+                include_body,
+            ),
+            None => response.write_to(reader.get_mut(), include_body),
+        };
+        match result {
+            Ok(()) => {}
+            // The headers are already out, so close rather than send a short body
+            Err(WriteError::Body(e)) => {
+                error!(
+                    "Body of {:#?} failed mid-stream, aborting connection: {:#?}",
+                    request.path, e
+                );
+                return;
+            }
+            Err(WriteError::Write(e)) if is_disconnect(&e) => {
+                debug!(
+                    "Client disconnected while {:#?} was being sent",
+                    request.path
+                );
+                return;
+            }
+            Err(WriteError::Write(e)) => {
+                error!("Failed to write response: {:#?}", e);
+                return;
+            }
+        }
 
-    let mut split_iter = request_line.split_whitespace();
-    let _method = split_iter.next().unwrap();
-    let path = split_iter.next().unwrap();
+        if !quiet && !config.access_log_fields.is_empty() {
+            entry.duration = started.elapsed();
+            info!(target: "access", "{}", entry.to_json(&config.access_log_fields));
+        }
 
-    let response = match router.get_routes().get(path) {
-        Some(_) => {
-            let route_data = router.get_routes().get(path).unwrap();
-            handle_route(route_data)
+        if !keep_alive {
+            return;
         }
+        idle_timeout = config.keep_alive_timeout;
+    }
+}
+
+/// Complete the WebSocket handshake and hand the connection to `handler`
+fn upgrade_connection(mut reader: ConnectionReader, request: &Request, handler: &WebSocketHandler) {
+    let response = match websocket::handshake(request) {
+        Some(response) => response,
         None => {
-            error!("Route not found: {:#?}", path);
-            String::from("HTTP/1.1 404 NOT FOUND\r\n\r\n")
+            error!("Invalid WebSocket handshake for {:#?}", request.path);
+            let response = Response::new()
+                .status(400, "BAD REQUEST")
+                .header("Connection", "close");
+            let _ = reader.get_mut().write_all(&response.to_bytes());
+            return;
         }
     };
 
-    stream.write_all(response.as_bytes()).unwrap();
+    if let Err(e) = reader.get_mut().write_all(&response.to_bytes()) {
+        error!("Failed to write response: {:#?}", e);
+        return;
+    }
+    // WebSocket connections are long lived, the handler decides when to stop
+    if let Err(e) = reader.get_ref().set_read_timeout(None) {
+        error!("Failed to clear read timeout: {:#?}", e);
+        return;
+    }
+
+    info!("Upgraded {:#?} to a WebSocket", request.path);
+    handler(&mut WebSocket::new(reader));
 }
 
-fn handle_route(path: &String) -> String {
-    let contents = std::fs::read_to_string(path).unwrap();
-    let status_line = "HTTP/1.1 200 OK";
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        status_line,
-        contents.len(),
-        contents
-    );
+/// Block until the next request starts arriving
+///
+/// Returns false if the client closed the connection or nothing arrived within `timeout`.
+/// Going idle between kept alive requests is expected, but a `first` request that never
+/// comes is logged as a warning, it may be a client holding connections open on purpose.
+///
+fn wait_for_request(reader: &mut ConnectionReader, timeout: Duration, first: bool) -> bool {
+    if !set_read_timeout(reader, timeout) {
+        return false;
+    }
+
+    match reader.fill_buf() {
+        Ok(buf) => !buf.is_empty(),
+        Err(e) if is_timeout(&e) && first => {
+            warn!("No request sent within {:?}, closing connection", timeout);
+            false
+        }
+        Err(e) if is_timeout(&e) => {
+            debug!("Connection idle for {:?}, closing", timeout);
+            false
+        }
+        Err(e) if is_disconnect(&e) => {
+            debug!("Connection closed by the client: {:#?}", e);
+            false
+        }
+        Err(e) => {
+            error!("Failed to read from connection: {:#?}", e);
+            false
+        }
+    }
+}
 
-    info!("Response: {:#?}, File: {:#?}", status_line, path);
+fn set_read_timeout(reader: &ConnectionReader, timeout: Duration) -> bool {
+    match reader.get_ref().set_read_timeout(Some(timeout)) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to set read timeout: {:#?}", e);
+            false
+        }
+    }
+}
+
+fn handle_parse_error(reader: &mut ConnectionReader, e: ParseError, phase: &str) {
+    match e {
+        ParseError::Closed => debug!("Connection closed before a request was sent"),
+        ParseError::Malformed(reason) => {
+            error!("Bad request: {}", reason);
+            let response = Response::new()
+                .status(400, "BAD REQUEST")
+                .header("Connection", "close");
+            let _ = reader.get_mut().write_all(&response.to_bytes());
+        }
+        ParseError::TooLarge => {
+            warn!("Request body is larger than the limit, closing connection");
+            let response = Response::new()
+                .status(413, "PAYLOAD TOO LARGE")
+                .header("Connection", "close");
+            let _ = reader.get_mut().write_all(&response.to_bytes());
+        }
+        ParseError::Io(e) if is_timeout(&e) => {
+            warn!("Timed out reading request {}, closing connection", phase);
+        }
+        ParseError::Io(e) if is_disconnect(&e) => {
+            debug!("Connection closed while reading request {}", phase);
+        }
+        ParseError::Io(e) => error!("Failed to read request {}: {:#?}", phase, e),
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Whether reading or writing failed because the client went away
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Serve a page from disk
+///
+/// Files of at least `stream_min_bytes` are streamed, only their first bytes are read
+/// up front for content type sniffing, and get a weak `ETag` from their metadata. A
+/// precompressed `sibling` is served in place of the page with its `Content-Encoding`,
+/// keeping the page's `Content-Type`.
+///
+fn handle_route(path: &String, sibling: Option<(String, &str)>, config: &ServerConfig) -> Response {
+    let (file_path, encoding) = match &sibling {
+        Some((file, encoding)) => (file.as_str(), Some(*encoding)),
+        None => (path.as_str(), None),
+    };
+    // Compressed bytes say nothing about the type of the page
+    let sniff = config.sniff_content_type && encoding.is_none();
+
+    let opened = File::open(file_path).and_then(|file| {
+        let metadata = file.metadata()?;
+        Ok((file, metadata))
+    });
+    let (mut file, metadata) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            error!("Failed to open {:#?}: {:#?}", file_path, e);
+            return Response::new().status(500, "INTERNAL SERVER ERROR");
+        }
+    };
+    // The routes are only as fresh as the last scan, the file may have been replaced since
+    if !metadata.is_file() {
+        let kind = if metadata.is_dir() {
+            "directory"
+        } else {
+            "special file"
+        };
+        error!("Route file {:#?} is now a {}, not a file", file_path, kind);
+        return Response::new().status(500, "INTERNAL SERVER ERROR");
+    }
+    let length = metadata.len();
+    if let Some(max) = config.large_file_warning_bytes.filter(|max| length > *max) {
+        warn!(
+            "Serving {:#?}, {} bytes is over the large file warning of {} bytes",
+            file_path, length, max
+        );
+    }
+
+    let response = if length >= config.stream_min_bytes {
+        let mut prefix = Vec::new();
+        if let Err(e) = (&mut file).take(SNIFF_BYTES).read_to_end(&mut prefix) {
+            error!("Failed to read {:#?}: {:#?}", file_path, e);
+            return Response::new().status(500, "INTERNAL SERVER ERROR");
+        }
+        let content_type = mime::content_type(Path::new(path), &prefix, sniff);
+        let response = Response::new()
+            .header("Content-Type", content_type)
+            .stream(Box::new(io::Cursor::new(prefix).chain(file)), length);
+        // Hashing a streamed file would mean reading all of it up front
+        match metadata.modified() {
+            Ok(modified) => response.header("ETag", &etag::from_metadata(modified, length)),
+            Err(_) => response,
+        }
+    } else {
+        let mut contents = Vec::new();
+        if let Err(e) = file.read_to_end(&mut contents) {
+            error!("Failed to read {:#?}: {:#?}", file_path, e);
+            return Response::new().status(500, "INTERNAL SERVER ERROR");
+        }
+        let content_type = mime::content_type(Path::new(path), &contents, sniff);
+        Response::new()
+            .header("Content-Type", content_type)
+            .body(contents)
+    };
+    let response = match encoding {
+        Some(encoding) => response
+            .header("Content-Encoding", encoding)
+            .header("Vary", "Accept-Encoding"),
+        None => response,
+    };
+    let response = match metadata.modified() {
+        Ok(modified) => response.header("Last-Modified", &date::http_date(modified)),
+        Err(_) => response,
+    };
+    let response = response.header("Accept-Ranges", "bytes");
+
+    info!("Response: {:#?}, File: {:#?}", "200 OK", file_path);
     response
 }
+
+/// The `Cache-Control` of fingerprinted assets, a year being the usual maximum
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Render a page for a request, the way it is kept in the response cache
+fn render_page(
+    request: &Request,
+    routes: &RouteTable,
+    route_data: &String,
+    locale: Option<&str>,
+    config: &ServerConfig,
+) -> Response {
+    // The routes come from the pages directory, but a symlink in it may lead elsewhere
+    if !routes.is_under_root(route_data) {
+        warn!(
+            "Refusing to serve {:#?}, it is outside of pages",
+            route_data
+        );
+        return Response::new().status(403, "FORBIDDEN");
+    }
+    let step = compression::choose(&config.compression_chain, request, |encoding| {
+        routes.precompressed_sibling(route_data, encoding).is_some()
+    });
+    let sibling = step.filter(|step| step.is_sibling()).and_then(|step| {
+        let sibling = routes.precompressed_sibling(route_data, step.encoding())?;
+        Some((sibling, step.encoding()))
+    });
+    let mut response = handle_route(route_data, sibling, config);
+    if routes.is_fingerprinted(&request.path) {
+        // The route changes along with the contents, so it can be cached for good
+        response = response.header("Cache-Control", IMMUTABLE);
+    }
+    if let Some(locale) = locale {
+        response = add_vary(
+            response.header("Content-Language", locale),
+            "Accept-Language",
+        );
+    }
+    encode(response, step, config)
+}
+
+/// Render a stale cached page again on the background pool, replacing its cache entry
+fn refresh_cached(
+    server: &Server,
+    request: &Request,
+    routes: &Arc<RouteTable>,
+    route_data: &str,
+    locale: Option<&str>,
+) {
+    let pool = match &server.background {
+        Some(pool) => pool,
+        None => return,
+    };
+    let original = request;
+    let request = request.clone();
+    let routes = Arc::clone(routes);
+    let route_data = route_data.to_string();
+    let locale = locale.map(String::from);
+    let config = Arc::clone(&server.config);
+    let cache = Arc::clone(&server.cache);
+
+    let result = pool.execute(move || {
+        let response = render_page(&request, &routes, &route_data, locale.as_deref(), &config);
+        cache.insert(&request, &response);
+        debug!("Refreshed {:#?} in the response cache", request.path);
+    });
+    if let Err(e) = result {
+        warn!(
+            "Failed to schedule a refresh of {:#?}: {:#?}",
+            original.path, e
+        );
+        server.cache.cancel_refresh(original);
+    }
+}
+
+/// Add a request header to the ones listed in the response's `Vary`
+fn add_vary(response: Response, header: &str) -> Response {
+    let vary = match response.get_header("Vary") {
+        Some(vary) => format!("{}, {}", vary, header),
+        None => header.to_string(),
+    };
+    response.header("Vary", &vary)
+}
+
+/// Tag and compress a response with `step`, as it is cached
+///
+/// Done once for cached pages, so hits aren't hashed and compressed again
+///
+fn encode(response: Response, step: Option<CompressionStep>, config: &ServerConfig) -> Response {
+    compression::compress(
+        with_etag(response),
+        step,
+        &config.compressible_types,
+        config.compress_min_bytes,
+    )
+}
+
+/// Give a successful response a strong `ETag` of its body, unless it already has one
+fn with_etag(response: Response) -> Response {
+    if response.status_code() != 200 || response.get_header("ETag").is_some() {
+        return response;
+    }
+    match response.get_body().map(etag::strong) {
+        Some(etag) => response.header("ETag", &etag),
+        None => response,
+    }
+}
+
+/// Evaluate `If-Match`, `If-None-Match` and `If-Modified-Since` against the response to
+/// a `GET` or `HEAD`
+///
+/// `If-Match` uses the strong comparison, so a weak `ETag` never satisfies it and the
+/// request fails with `412`. `If-None-Match` uses the weak comparison and a match is
+/// answered with `304`, as is a request whose `If-Modified-Since` is no earlier than
+/// the `Last-Modified` of the response.
+///
+fn check_preconditions(request: &Request, response: Response) -> Response {
+    if !matches!(request.method.as_str(), "GET" | "HEAD") || response.status_code() != 200 {
+        return response;
+    }
+    let etag = response.get_header("ETag").map(String::from);
+
+    if let (Some(if_match), Some(etag)) = (request.header("if-match"), &etag) {
+        if !etag::if_match(if_match, etag) {
+            return Response::new().status(412, "PRECONDITION FAILED");
+        }
+    }
+    // `If-Modified-Since` is only a fallback for clients without an `ETag` to send
+    let not_modified = match (request.header("if-none-match"), &etag) {
+        (Some(if_none_match), Some(etag)) => etag::if_none_match(if_none_match, etag),
+        (Some(_), None) => false,
+        (None, _) => is_unmodified_since(request, &response),
+    };
+    if !not_modified {
+        return response;
+    }
+
+    let mut not_modified = Response::new().status(304, "NOT MODIFIED");
+    for name in ["ETag", "Last-Modified", "Vary"] {
+        if let Some(value) = response.get_header(name) {
+            not_modified = not_modified.header(name, value);
+        }
+    }
+    not_modified
+}
+
+/// Whether the response's `Last-Modified` is no later than the request's
+/// `If-Modified-Since`
+///
+/// A date that can't be parsed on either side is ignored, so the full response is sent
+///
+fn is_unmodified_since(request: &Request, response: &Response) -> bool {
+    let since = request
+        .header("if-modified-since")
+        .and_then(date::parse_http_date);
+    let modified = response
+        .get_header("Last-Modified")
+        .and_then(date::parse_http_date);
+    match (since, modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// Add the configured `response_headers`
+fn add_response_headers(response: Response, config: &ServerConfig) -> Response {
+    config
+        .response_headers
+        .iter()
+        .fold(response, |response, header| {
+            if header.always || response.get_header(&header.name).is_none() {
+                response.header(&header.name, &header.value)
+            } else {
+                response
+            }
+        })
+}
+
+/// Add the `Strict-Transport-Security` and `Expect-CT` headers that are configured
+///
+/// Browsers ignore these over plain HTTP, where they could also be forged, so they
+/// must only be added to responses sent over a secure connection
+///
+fn add_transport_security(response: Response, config: &ServerConfig) -> Response {
+    let response = match &config.hsts {
+        Some(hsts) => response.header("Strict-Transport-Security", &hsts.render()),
+        None => response,
+    };
+    match &config.expect_ct {
+        Some(expect_ct) => response.header("Expect-CT", &expect_ct.render()),
+        None => response,
+    }
+}
+
+/// Format measured phases as a `Server-Timing` header value, in milliseconds
+fn server_timing(timings: &[(&str, Duration)]) -> String {
+    timings
+        .iter()
+        .map(|(name, duration)| format!("{};dur={:.3}", name, duration.as_secs_f64() * 1000.0))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Describe the received request back to the client as plain text
+///
+/// The request line and headers (sorted by name) are followed by a blank line and the body
+///
+fn handle_echo(request: &Request) -> Response {
+    let mut headers: Vec<_> = request.headers.iter().collect();
+    headers.sort();
+
+    let target = match request.query() {
+        Some(query) => format!("{}?{}", request.path, query),
+        None => request.path.clone(),
+    };
+    let mut contents = format!("{} {} {}\r\n", request.method, target, request.version);
+    for (name, value) in headers {
+        contents.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    contents.push_str("\r\n");
+    contents.push_str(&String::from_utf8_lossy(&request.body));
+
+    Response::new()
+        .header("Content-Type", "text/plain")
+        .body(contents.into_bytes())
+}
+
+/// Whether the request body has a content type its route accepts
+///
+/// Requests without a body or `Content-Type` have nothing to check
+///
+fn has_accepted_content_type(request: &Request, config: &ServerConfig) -> bool {
+    let content_types = match config.content_types_for(&request.path) {
+        Some(content_types) => content_types,
+        None => return true,
+    };
+    match request.header("content-type") {
+        Some(content_type) => mime::matches_any(content_type, content_types),
+        None => request.body.is_empty(),
+    }
+}
+
+/// Replace a handler's response with a `500` if its body is over `max_response_bytes`
+fn limit_response_size(request: &Request, response: Response, config: &ServerConfig) -> Response {
+    match config.max_response_bytes {
+        Some(max) if response.content_length() > max => {
+            error!(
+                "Handler for {:#?} responded with {} bytes, more than the limit of {}",
+                request.path,
+                response.content_length(),
+                max
+            );
+            Response::new().status(500, "INTERNAL SERVER ERROR")
+        }
+        _ => response,
+    }
+}
+
+/// Answer a request whose path has handlers, but none for its method
+fn handle_method_not_allowed(allowed: &[Method], config: &ServerConfig) -> Response {
+    let allowed: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+    let response = Response::new()
+        .status(405, "METHOD NOT ALLOWED")
+        .header("Allow", &allowed.join(", "));
+    match &config.method_not_allowed_body {
+        Some(body) => response
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(body.clone().into_bytes()),
+        None => response,
+    }
+}
+
+/// Point `Link` values, e.g. `</app.js>; rel=preload`, at the fingerprinted routes of
+/// their assets, which pages link to once assets are fingerprinted
+fn fingerprint_links(links: &[String], router: &Router) -> Vec<String> {
+    links
+        .iter()
+        .map(|link| {
+            let fingerprinted = link
+                .strip_prefix('<')
+                .and_then(|link| link.split_once('>'))
+                .and_then(|(target, rest)| {
+                    let route = router.fingerprinted(target)?;
+                    Some(format!("<{}>{}", route, rest))
+                });
+            fingerprinted.unwrap_or_else(|| link.clone())
+        })
+        .collect()
+}
+
+/// Answer a request no route matches, with the pages directory's `404.html` if it has one
+fn handle_not_found(page: Option<&[u8]>) -> Response {
+    let response = Response::new().status(404, "NOT FOUND");
+    match page {
+        Some(page) => response
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(page.to_vec()),
+        None => response,
+    }
+}
+
+/// Answer a request with a configured redirect, keeping its query string unless the
+/// target has its own
+fn handle_redirect(redirect: &RedirectConfig, query: Option<&str>) -> Response {
+    let location = match query {
+        Some(query) if !redirect.to.contains('?') => format!("{}?{}", redirect.to, query),
+        _ => redirect.to.clone(),
+    };
+    Response::new()
+        .status(redirect.status.code(), redirect.status.reason())
+        .header("Location", &location)
+}
+
+/// Answer a request with the maintenance page
+fn handle_maintenance(maintenance: &MaintenanceConfig) -> Response {
+    let response = Response::new().status(503, "SERVICE UNAVAILABLE").header(
+        "Retry-After",
+        &maintenance.retry_after.as_secs().to_string(),
+    );
+    let page = match &maintenance.page {
+        Some(page) => page,
+        None => return response,
+    };
+    match fs::read(page) {
+        Ok(contents) => response
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(contents),
+        Err(e) => {
+            error!("Failed to read maintenance page {:#?}: {:#?}", page, e);
+            response
+        }
+    }
+}
+
+/// Serve the `robots.txt` generated from config
+fn handle_robots(robots: &RobotsConfig) -> Response {
+    Response::new()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(robots.render().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compression::DEFAULT_COMPRESSION_CHAIN;
+    use config::{BundleConfig, ExpectCtConfig, HstsConfig, ResponseHeader};
+    use error_format::ErrorFormat;
+    use router::ScanLimit;
+    use std::{
+        net::TcpListener,
+        sync::Mutex,
+        time::{Instant, UNIX_EPOCH},
+    };
+    use temp_pages::TempPages;
+
+    const SHORT: Duration = Duration::from_millis(100);
+    const LONG: Duration = Duration::from_secs(30);
+
+    /// Serve one connection on a background thread and return the client end
+    fn connect(config: ServerConfig) -> TcpStream {
+        connect_to(Arc::new(Server::new(
+            Router::new(ScanLimit::default()).unwrap(),
+            config,
+        )))
+    }
+
+    fn connect_to(server: Arc<Server>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_tcp_connection(stream, &server);
+        });
+
+        let client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+    }
+
+    /// Send `data` and read until the server closes the connection
+    fn send_and_wait_for_close(mut client: TcpStream, data: &str) -> (String, Duration) {
+        let start = Instant::now();
+        client.write_all(data.as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        (response, start.elapsed())
+    }
+
+    #[test]
+    fn test_header_read_timeout() {
+        let client = connect(ServerConfig {
+            header_read_timeout: SHORT,
+            body_read_timeout: LONG,
+            keep_alive_timeout: LONG,
+            ..ServerConfig::default()
+        });
+
+        let (response, elapsed) = send_and_wait_for_close(client, "GET / HTTP/1.1\r\nHost: x\r\n");
+        assert!(response.is_empty());
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_body_read_timeout() {
+        let client = connect(ServerConfig {
+            header_read_timeout: LONG,
+            body_read_timeout: SHORT,
+            keep_alive_timeout: LONG,
+            ..ServerConfig::default()
+        });
+
+        let (response, elapsed) =
+            send_and_wait_for_close(client, "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nab");
+        assert!(response.is_empty());
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_max_keep_alive() {
+        let server = Arc::new(Server::new(
+            hello_router(),
+            ServerConfig {
+                keep_alive_timeout: LONG,
+                max_keep_alive: Some(1),
+                ..ServerConfig::default()
+            },
+        ));
+
+        let mut kept = connect_to(Arc::clone(&server));
+        kept.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"hello world") {
+            let mut buffer = [0; 1024];
+            let read = kept.read(&mut buffer).unwrap();
+            assert!(read > 0);
+            response.extend_from_slice(&buffer[..read]);
+        }
+        assert!(String::from_utf8(response)
+            .unwrap()
+            .contains("Connection: keep-alive\r\n"));
+
+        let (response, elapsed) = send_and_wait_for_close(
+            connect_to(Arc::clone(&server)),
+            "GET /hello HTTP/1.1\r\n\r\n",
+        );
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("hello world"));
+        assert!(elapsed < Duration::from_secs(2));
+
+        // Closing the kept alive connection frees its slot
+        drop(kept);
+        let start = Instant::now();
+        while server.keep_alive_connections.load(Ordering::SeqCst) > 0 {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_http10_keep_alive() {
+        let server = Arc::new(Server::new(
+            hello_router(),
+            ServerConfig {
+                keep_alive_timeout: LONG,
+                ..ServerConfig::default()
+            },
+        ));
+
+        // HTTP/1.0 closes after each response unless the client asks otherwise
+        let (response, elapsed) = send_and_wait_for_close(
+            connect_to(Arc::clone(&server)),
+            "GET /hello HTTP/1.0\r\n\r\nGET /hello HTTP/1.0\r\n\r\n",
+        );
+        assert_eq!(response.matches("200 OK").count(), 1);
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(elapsed < Duration::from_secs(2));
+
+        // Asking for keep-alive gets the second request answered on the same
+        // connection, which closes after it instead of waiting for the timeout
+        let mut client = connect_to(server);
+        client
+            .write_all(b"GET /hello HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .unwrap();
+        let (response, elapsed) = send_and_wait_for_close(client, "GET /hello HTTP/1.0\r\n\r\n");
+        assert_eq!(response.matches("200 OK").count(), 2);
+        assert_eq!(response.matches("Connection: keep-alive\r\n").count(), 1);
+        assert_eq!(response.matches("Connection: close\r\n").count(), 1);
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_keep_alive_disabled() {
+        let client = connect_to(Arc::new(Server::new(
+            hello_router(),
+            ServerConfig {
+                keep_alive_timeout: LONG,
+                keep_alive: false,
+                ..ServerConfig::default()
+            },
+        )));
+
+        // The second request is never answered, the connection closes after the first
+        let (response, elapsed) = send_and_wait_for_close(
+            client,
+            "GET /hello HTTP/1.1\r\nConnection: keep-alive\r\n\r\nGET /hello HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("hello world"));
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_keep_alive_timeout() {
+        let client = connect(ServerConfig {
+            header_read_timeout: LONG,
+            body_read_timeout: LONG,
+            keep_alive_timeout: SHORT,
+            ..ServerConfig::default()
+        });
+
+        let (response, elapsed) = send_and_wait_for_close(client, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Connection: keep-alive"));
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_chunked_body_too_large() {
+        let client = connect(ServerConfig {
+            max_body_bytes: 64,
+            ..ServerConfig::default()
+        });
+
+        let chunks = "10\r\n0123456789abcdef\r\n".repeat(5);
+        let (response, _) = send_and_wait_for_close(
+            client,
+            &format!("POST /about HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{chunks}0\r\n\r\n"),
+        );
+        assert!(response.starts_with("HTTP/1.1 413 PAYLOAD TOO LARGE"));
+        assert!(response.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_content_length_with_transfer_encoding() {
+        let client = connect(ServerConfig {
+            keep_alive_timeout: LONG,
+            ..ServerConfig::default()
+        });
+
+        let (response, elapsed) = send_and_wait_for_close(
+            client,
+            "POST /about HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(elapsed < Duration::from_secs(2));
+
+        // Framings a proxy could read differently, the body would be taken as the next
+        // request if the connection stayed open
+        for framing in [
+            "Content-Length: 0\r\nContent-Length: 23",
+            "Transfer-Encoding: gzip",
+            "Transfer-Encoding: chunked, gzip",
+        ] {
+            let client = connect(ServerConfig {
+                keep_alive_timeout: LONG,
+                ..ServerConfig::default()
+            });
+            let request = format!(
+                "POST /about HTTP/1.1\r\n{}\r\n\r\nGET /about HTTP/1.1\r\n\r\n",
+                framing
+            );
+            let (response, elapsed) = send_and_wait_for_close(client, &request);
+            assert!(
+                response.starts_with("HTTP/1.1 400 BAD REQUEST"),
+                "{}",
+                framing
+            );
+            assert!(response.contains("Connection: close\r\n"));
+            assert_eq!(response.matches("HTTP/1.1 ").count(), 1, "{}", framing);
+            assert!(elapsed < Duration::from_secs(2));
+        }
+
+        // The same length repeated frames the body one way only
+        let (response, _) = send_and_wait_for_close(
+            connect(ServerConfig::default()),
+            "POST /about HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+        );
+        assert!(!response.starts_with("HTTP/1.1 400"), "{}", response);
+    }
+
+    /// A connection the client reset as soon as it was accepted
+    struct ResetConnection;
+
+    impl Read for ResetConnection {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::ConnectionReset.into())
+        }
+    }
+
+    impl Write for ResetConnection {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for ResetConnection {
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn peer(&self) -> String {
+            String::from("reset")
+        }
+    }
+
+    #[test]
+    fn test_connection_reset_on_accept() {
+        let server = Server::new(
+            Router::new(ScanLimit::default()).unwrap(),
+            ServerConfig::default(),
+        );
+        handle_connection(ResetConnection, &server);
+        assert_eq!(server.keep_alive_connections.load(Ordering::SeqCst), 0);
+    }
+
+    /// A connection that reads requests from memory and discards the responses
+    struct MemoryConnection {
+        input: io::Cursor<Vec<u8>>,
+    }
+
+    impl Read for MemoryConnection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MemoryConnection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for MemoryConnection {
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn peer(&self) -> String {
+            String::from("memory")
+        }
+    }
+
+    /// Records the log lines `filter` picks, see `capture_logs`
+    struct LogCapture {
+        filter: fn(&log::Metadata) -> bool,
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl log::Log for LogCapture {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            (self.filter)(metadata)
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                self.lines.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Capture the log lines `filter` picks while the test named `test` runs
+    ///
+    /// The logger is set once for the whole process, so the test runs again in a copy
+    /// of the test binary running just it. The copy gets the capture, the caller gets
+    /// `None` once the copy passed and should return.
+    ///
+    fn capture_logs(test: &str, filter: fn(&log::Metadata) -> bool) -> Option<&'static LogCapture> {
+        if env::var_os("LOG_CAPTURE").is_none() {
+            let output = std::process::Command::new(env::current_exe().unwrap())
+                .args(["--exact", test])
+                .env("LOG_CAPTURE", "1")
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stdout)
+            );
+            return None;
+        }
+        let capture = Box::leak(Box::new(LogCapture {
+            filter,
+            lines: Mutex::new(Vec::new()),
+        }));
+        log::set_logger(capture).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+        Some(capture)
+    }
+
+    #[test]
+    fn test_request_number_logged() {
+        let access_log = match capture_logs("tests::test_request_number_logged", |metadata| {
+            metadata.target() == "access"
+        }) {
+            Some(capture) => capture,
+            None => return,
+        };
+
+        let server = Server::new(hello_router(), ServerConfig::default());
+        let requests = "GET /hello HTTP/1.1\r\n\r\n".repeat(2)
+            + "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let connection = MemoryConnection {
+            input: io::Cursor::new(requests.into_bytes()),
+        };
+        handle_connection(connection, &server);
+
+        let lines = access_log.lines.lock().unwrap();
+        assert_eq!(lines.len(), 3);
+        for (i, line) in lines.iter().enumerate() {
+            assert!(line.contains(&format!("\"request_number\":{}", i + 1)));
+        }
+    }
+
+    #[test]
+    fn test_quiet_paths_not_logged() {
+        let logs = match capture_logs("tests::test_quiet_paths_not_logged", |metadata| {
+            metadata.level() <= log::Level::Info
+        }) {
+            Some(capture) => capture,
+            None => return,
+        };
+
+        let mut router = hello_router();
+        router.add_handler(
+            Method::Get,
+            "/health",
+            Box::new(|_| Response::new().body(b"ok".to_vec())),
+        );
+        let config = ServerConfig {
+            quiet_paths: vec![String::from("/health")],
+            ..ServerConfig::default()
+        };
+        let server = Server::new(router, config);
+        let requests = "GET /health HTTP/1.1\r\n\r\n".to_string()
+            + "GET /hello HTTP/1.1\r\n\r\n"
+            + "GET /health HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let connection = MemoryConnection {
+            input: io::Cursor::new(requests.into_bytes()),
+        };
+        handle_connection(connection, &server);
+
+        // Both the request log and the access log have a line for `/hello` only
+        let lines = logs.lines.lock().unwrap();
+        assert!(lines.iter().all(|line| !line.contains("/health")));
+        assert!(lines.iter().any(|line| line.starts_with("Request:")));
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("\"path\":\"/hello\"")));
+    }
+
+    #[test]
+    fn test_large_file_warning() {
+        let warnings = match capture_logs("tests::test_large_file_warning", |metadata| {
+            metadata.level() == log::Level::Warn
+        }) {
+            Some(capture) => capture,
+            None => return,
+        };
+
+        let pages = TempPages::new("large");
+        pages.write("small.txt", [b'a'; 100]);
+        pages.write("large.txt", [b'a'; 101]);
+        let config = ServerConfig {
+            large_file_warning_bytes: Some(100),
+            ..ServerConfig::default()
+        };
+        let small = pages.join("small.txt").to_str().unwrap().to_string();
+        let large = pages.join("large.txt").to_str().unwrap().to_string();
+
+        handle_route(&small, None, &config);
+        assert!(warnings.lines.lock().unwrap().is_empty());
+
+        // The file is still served in full
+        let response = handle_route(&large, None, &config);
+        assert_eq!(response.get_body().map(<[u8]>::len), Some(101));
+        let warnings = warnings.lines.lock().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("large.txt"));
+        assert!(warnings[0].contains("101 bytes"));
+    }
+
+    #[test]
+    fn test_empty_stream() {
+        let client = connect(ServerConfig {
+            header_read_timeout: LONG,
+            ..ServerConfig::default()
+        });
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let (response, elapsed) = send_and_wait_for_close(client, "");
+        assert!(response.is_empty());
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_truncated_request_line() {
+        for request_line in ["GET", "GET /", "\t"] {
+            let client = connect(ServerConfig {
+                keep_alive_timeout: LONG,
+                ..ServerConfig::default()
+            });
+
+            let (response, elapsed) =
+                send_and_wait_for_close(client, &format!("{}\r\n\r\n", request_line));
+            assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST"));
+            assert!(response.contains("Connection: close\r\n"));
+            assert!(elapsed < Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn test_query_too_long() {
+        let client = connect(ServerConfig {
+            max_query_bytes: 1024,
+            ..ServerConfig::default()
+        });
+
+        let query = "a=1&".repeat(100_000);
+        let (response, _) = send_and_wait_for_close(
+            client,
+            &format!("GET /about?{} HTTP/1.1\r\n\r\nGET /about?a=1 HTTP/1.1\r\nConnection: close\r\n\r\n", query),
+        );
+        let (long, short) = response.split_once("\r\n\r\n").unwrap();
+        assert!(long.starts_with("HTTP/1.1 414 URI TOO LONG"));
+        assert!(!short.contains("414"));
+    }
+
+    #[test]
+    fn test_lowercase_method() {
+        let request = "get /hello HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+        let client = connect_to(Arc::new(Server::new(
+            hello_router(),
+            ServerConfig::default(),
+        )));
+        let (response, _) = send_and_wait_for_close(client, request);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("hello world"));
+
+        let client = connect_to(Arc::new(Server::new(
+            hello_router(),
+            ServerConfig {
+                strict_method_case: true,
+                ..ServerConfig::default()
+            },
+        )));
+        let (response, _) = send_and_wait_for_close(client, request);
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST"));
+        assert!(response.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_detect_tls() {
+        let server = Arc::new(Server::new(
+            hello_router(),
+            ServerConfig {
+                detect_tls: true,
+                ..ServerConfig::default()
+            },
+        ));
+
+        // The start of a ClientHello record
+        let mut client = connect_to(Arc::clone(&server));
+        client
+            .write_all(&[0x16, 0x03, 0x01, 0x00, 0xc8, 0x01])
+            .unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, TLS_HANDSHAKE_FAILURE);
+
+        let (response, _) = send_and_wait_for_close(
+            connect_to(server),
+            "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\nhello world"));
+    }
+
+    #[test]
+    fn test_normalized_path_lookup() {
+        let pages = TempPages::new("normalize");
+        pages.write("about.html", "About");
+        pages.write("my page.html", "Mine");
+        pages.write("index.html", "Home");
+        let server = Arc::new(Server::new(
+            Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap(),
+            ServerConfig::default(),
+        ));
+        let get = |path: &str| {
+            let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), &request).0
+        };
+
+        assert!(get("/about/").ends_with("\r\n\r\nAbout"));
+        assert!(get("/my%20page").ends_with("\r\n\r\nMine"));
+        assert!(get("/").ends_with("\r\n\r\nHome"));
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        let pages = TempPages::new("prefix");
+        pages.write("about.html", "About");
+        pages.write("index.html", "Home");
+        let server = |require_prefix| {
+            Arc::new(Server::new(
+                Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap(),
+                ServerConfig {
+                    strip_prefix: Some(String::from("/app")),
+                    require_prefix,
+                    ..ServerConfig::default()
+                },
+            ))
+        };
+        let get = |server: &Arc<Server>, path: &str| {
+            let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+            send_and_wait_for_close(connect_to(Arc::clone(server)), &request).0
+        };
+
+        let passing = server(false);
+        assert!(get(&passing, "/app/about").ends_with("\r\n\r\nAbout"));
+        assert!(get(&passing, "/app/").ends_with("\r\n\r\nHome"));
+        assert!(get(&passing, "/about").ends_with("\r\n\r\nAbout"));
+
+        let requiring = server(true);
+        assert!(get(&requiring, "/app/about").ends_with("\r\n\r\nAbout"));
+        assert!(get(&requiring, "/about").starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(get(&requiring, "/application").starts_with("HTTP/1.1 404 NOT FOUND"));
+    }
+
+    #[test]
+    fn test_redirects() {
+        let redirect = |from: &str, to: &str, status| RedirectConfig {
+            from: from.to_string(),
+            to: to.to_string(),
+            status: config::RedirectStatus::try_from(status).unwrap(),
+        };
+        let server = Arc::new(Server::new(
+            Router::new(ScanLimit::default()).unwrap(),
+            ServerConfig {
+                redirects: vec![
+                    redirect("/old-about", "/about", 301),
+                    redirect("/promo", "https://example.com/sale?ref=web", 302),
+                ],
+                ..ServerConfig::default()
+            },
+        ));
+        let get = |path: &str| {
+            let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), &request).0
+        };
+
+        let response = get("/old-about/");
+        assert!(response.starts_with("HTTP/1.1 301 MOVED PERMANENTLY"));
+        assert!(response.contains("Location: /about\r\n"));
+
+        // The query string is kept, unless the target has its own
+        let response = get("/old-about?lang=fr");
+        assert!(response.contains("Location: /about?lang=fr\r\n"));
+        let response = get("/promo?x=1");
+        assert!(response.starts_with("HTTP/1.1 302 FOUND"));
+        assert!(response.contains("Location: https://example.com/sale?ref=web\r\n"));
+
+        assert!(get("/about").starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_canonical_host() {
+        let server = Arc::new(Server::new(
+            Router::new(ScanLimit::default()).unwrap(),
+            ServerConfig {
+                canonical_host: Some(String::from("example.com")),
+                ..ServerConfig::default()
+            },
+        ));
+        let get = |head: &str| {
+            let request = format!("GET /about?lang=fr {}\r\nConnection: close\r\n\r\n", head);
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), &request).0
+        };
+
+        let response = get("HTTP/1.1\r\nHost: www.example.com");
+        assert!(response.starts_with("HTTP/1.1 301 MOVED PERMANENTLY"));
+        assert!(response.contains("Location: http://example.com/about?lang=fr\r\n"));
+
+        // The path is sent back encoded as it was requested
+        let request =
+            "GET /my%20page/ HTTP/1.1\r\nHost: www.example.com\r\nConnection: close\r\n\r\n";
+        let (response, _) = send_and_wait_for_close(connect_to(Arc::clone(&server)), request);
+        assert!(response.contains("Location: http://example.com/my%20page/\r\n"));
+
+        // An encoded CR/LF can't be used to add headers to the redirect
+        let request = "GET /x%0d%0aSet-Cookie:%20evil=1 HTTP/1.1\r\nHost: www.example.com\r\nConnection: close\r\n\r\n";
+        let (response, _) = send_and_wait_for_close(connect_to(Arc::clone(&server)), request);
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST"));
+        assert!(!response.contains("Set-Cookie"));
+
+        // The canonical host itself, on any port and in any case
+        assert!(get("HTTP/1.1\r\nHost: Example.COM:8080").starts_with("HTTP/1.1 200 OK"));
+        // Without a `Host` there is nothing to compare
+        assert!(get("HTTP/1.0").starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_middleware() {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.add_middleware(middleware::require_api_key(String::from("secret")));
+        router.add_middleware(Box::new(|_, next| {
+            next.run().header("X-Powered-By", "web-server")
+        }));
+        let server = Arc::new(Server::new(router, ServerConfig::default()));
+        let get = |headers: &str| {
+            let request = format!(
+                "GET /about HTTP/1.1\r\n{}Connection: close\r\n\r\n",
+                headers
+            );
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), &request).0
+        };
+
+        let response = get("");
+        assert!(response.starts_with("HTTP/1.1 401 UNAUTHORIZED"));
+        // The rejected request never reached the layers after the check
+        assert!(!response.contains("X-Powered-By"));
+        assert!(get("X-Api-Key: guess\r\n").starts_with("HTTP/1.1 401 UNAUTHORIZED"));
+
+        let response = get("X-Api-Key: secret\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("X-Powered-By: web-server\r\n"));
+    }
+
+    #[test]
+    fn test_api_key_guards_upgrades_and_hints() {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.add_middleware(middleware::require_api_key(String::from("secret")));
+        let mut server = Server::new(
+            router,
+            ServerConfig {
+                early_hints: vec![(
+                    String::from("/about"),
+                    vec![String::from("</style.css>; rel=preload; as=style")],
+                )],
+                ..ServerConfig::default()
+            },
+        );
+        server.add_websocket_route("/ws/echo", Box::new(websocket::echo));
+        let server = Arc::new(server);
+
+        let upgrade = |headers: &str| {
+            let mut client = connect_to(Arc::clone(&server));
+            let request = format!(
+                "GET /ws/echo HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+                 {}\r\n",
+                headers
+            );
+            client.write_all(request.as_bytes()).unwrap();
+            let mut reader = BufReader::new(client);
+            let mut status = String::new();
+            reader.read_line(&mut status).unwrap();
+            status
+        };
+        assert!(upgrade("").starts_with("HTTP/1.1 401 UNAUTHORIZED"));
+        assert!(upgrade("X-Api-Key: secret\r\n").starts_with("HTTP/1.1 101 SWITCHING PROTOCOLS"));
+
+        let get = |headers: &str| {
+            let request = format!(
+                "GET /about HTTP/1.1\r\n{}Connection: close\r\n\r\n",
+                headers
+            );
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), &request).0
+        };
+        // No hints are sent for a request the key check turns away
+        assert!(get("").starts_with("HTTP/1.1 401 UNAUTHORIZED"));
+        assert!(get("X-Api-Key: secret\r\n").starts_with("HTTP/1.1 103 EARLY HINTS"));
+    }
+
+    #[test]
+    fn test_query_string_routing() {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.add_handler(
+            Method::Get,
+            "/search",
+            Box::new(|request| {
+                let query = request.query_params();
+                let body = format!("{} page {}", query["q"], query["page"]);
+                Response::new().body(body.into_bytes())
+            }),
+        );
+        let client = connect_to(Arc::new(Server::new(router, ServerConfig::default())));
+
+        let (response, _) = send_and_wait_for_close(
+            client,
+            "GET /search?q=rust%21&page=2 HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\nrust! page 2"));
+    }
+
+    #[test]
+    fn test_json_errors() {
+        let server = Arc::new(Server::new(
+            Router::new(ScanLimit::default()).unwrap(),
+            ServerConfig {
+                error_format: ErrorFormat::Negotiate,
+                ..ServerConfig::default()
+            },
+        ));
+        let get = |accept: &str| {
+            let request = format!(
+                "GET /missing HTTP/1.1\r\nAccept: {}\r\nConnection: close\r\n\r\n",
+                accept
+            );
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), &request).0
+        };
+
+        let json = get("application/json");
+        assert!(json.starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(json.contains("Content-Type: application/json\r\n"));
+        assert!(json.ends_with("\r\n\r\n{\"error\":{\"status\":404,\"message\":\"Not Found\"}}"));
+
+        let html = get("text/html");
+        assert!(html.starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(!html.contains("application/json"));
+    }
+
+    #[test]
+    fn test_allowed_methods() {
+        let mut router = hello_router();
+        router.add_handler(
+            Method::Post,
+            "/hello",
+            Box::new(|_| Response::new().body(b"posted".to_vec())),
+        );
+        let server = Arc::new(Server::new(
+            router,
+            ServerConfig {
+                allowed_methods: Some(vec![Method::Get, Method::Head]),
+                ..ServerConfig::default()
+            },
+        ));
+
+        // Rejected even though the route has a `POST` handler
+        let (response, _) = send_and_wait_for_close(
+            connect_to(Arc::clone(&server)),
+            "POST /hello HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED"));
+        assert!(response.contains("Allow: GET, HEAD\r\n"));
+
+        let (response, _) = send_and_wait_for_close(
+            connect_to(server),
+            "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_body_required() {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.add_handler(
+            Method::Post,
+            "/api/items",
+            Box::new(|request| Response::new().body(request.body.clone())),
+        );
+        let server = Arc::new(Server::new(
+            router,
+            ServerConfig {
+                body_required: vec![String::from("/api")],
+                ..ServerConfig::default()
+            },
+        ));
+
+        let (response, _) = send_and_wait_for_close(
+            connect_to(Arc::clone(&server)),
+            "POST /api/items HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST"));
+        assert!(response.ends_with("POST /api/items requires a request body\n"));
+
+        let (response, _) = send_and_wait_for_close(
+            connect_to(server),
+            "POST /api/items HTTP/1.1\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\nok"));
+    }
+
+    #[test]
+    fn test_debug_echo() {
+        let client = connect(ServerConfig {
+            debug_echo: true,
+            ..ServerConfig::default()
+        });
+
+        let (response, _) = send_and_wait_for_close(
+            client,
+            "POST /debug/echo HTTP/1.1\r\nConnection: close\r\nContent-Length: 11\r\n\r\nhello world",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("POST /debug/echo HTTP/1.1\r\n"));
+        assert!(response.contains("content-length: 11\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello world"));
+    }
+
+    #[test]
+    fn test_debug_echo_disabled() {
+        let client = connect(ServerConfig::default());
+
+        let (response, _) = send_and_wait_for_close(
+            client,
+            "GET /debug/echo HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"));
+    }
+
+    #[test]
+    fn test_response_cache_hit() {
+        let config = ServerConfig {
+            response_cache_max_bytes: 1024 * 1024,
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(
+            Router::new(ScanLimit::default()).unwrap(),
+            config,
+        ));
+        let client = connect_to(Arc::clone(&server));
+
+        let (response, _) = send_and_wait_for_close(
+            client,
+            "GET /about HTTP/1.1\r\n\r\nGET /about HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+        assert_eq!(server.cache.hits(), 1);
+    }
+
+    #[test]
+    fn test_response_cache_stores_encoded() {
+        let server = Arc::new(Server::new(
+            Router::new(ScanLimit::default()).unwrap(),
+            ServerConfig {
+                response_cache_max_bytes: 1024 * 1024,
+                compress_min_bytes: 0,
+                ..ServerConfig::default()
+            },
+        ));
+        let raw = "GET /index.html HTTP/1.1\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n";
+        // The gzipped bodies aren't text
+        let get = || {
+            let mut client = connect_to(Arc::clone(&server));
+            client.write_all(raw.as_bytes()).unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            String::from_utf8_lossy(&response).into_owned()
+        };
+        let (first, second) = (get(), get());
+        assert_eq!(server.cache.hits(), 1);
+
+        // What's cached is the compressed response, so hits are sent as they are
+        let cached = server
+            .cache
+            .get(&Request::parse_head(&mut raw.as_bytes()).unwrap())
+            .unwrap()
+            .response;
+        assert_eq!(cached.get_header("Content-Encoding"), Some("gzip"));
+        let etag = cached.get_header("ETag").unwrap();
+        assert!(etag.starts_with("W/"));
+        for response in [first, second] {
+            assert!(response.contains("Content-Encoding: gzip\r\n"));
+            assert!(response.contains(&format!("ETag: {}\r\n", etag)));
+        }
+    }
+
+    #[test]
+    fn test_response_cache_eviction() {
+        let pages = TempPages::new("eviction");
+        for page in ["a", "b", "c"] {
+            std::fs::write(pages.join(format!("{}.html", page)), page.repeat(4)).unwrap();
+        }
+        // Room for two of the pages
+        let config = ServerConfig {
+            response_cache_max_bytes: 10,
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(
+            Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap(),
+            config,
+        ));
+        let get = |path: &str| {
+            let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), &request).0
+        };
+
+        get("/a");
+        get("/b");
+        get("/a");
+        assert_eq!(server.cache.hits(), 1);
+        // Storing `/c` evicts `/b`, the least recently used, which is read from disk again
+        get("/c");
+        assert!(get("/b").ends_with("\r\n\r\nbbbb"));
+        assert_eq!(server.cache.hits(), 1);
+        get("/c");
+        assert_eq!(server.cache.hits(), 2);
+    }
+
+    #[test]
+    fn test_watched_pages_clear_cache() {
+        let pages = TempPages::new("watched");
+        pages.write("about.html", "old");
+        let router = Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap();
+        router.watch(Duration::from_millis(20));
+        let config = ServerConfig {
+            response_cache_max_bytes: 1024,
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(router, config));
+        let get = || {
+            let request = "GET /about HTTP/1.1\r\nConnection: close\r\n\r\n";
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), request).0
+        };
+
+        assert!(get().ends_with("\r\n\r\nold"));
+        assert!(get().ends_with("\r\n\r\nold"));
+        assert_eq!(server.cache.hits(), 1);
+        // Edited in place, so only the reload tells the cache the page changed
+        pages.write("about.html", "new!");
+        let start = Instant::now();
+        while !get().ends_with("\r\n\r\nnew!") {
+            assert!(start.elapsed() < Duration::from_secs(5), "Never reloaded");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_stale_while_revalidate() {
+        let pages = TempPages::new("stale");
+        pages.write("about.html", "old");
+        let config = ServerConfig {
+            response_cache_max_bytes: 1024,
+            response_cache_max_age: Some(Duration::from_millis(500)),
+            response_cache_stale_while_revalidate: Duration::from_secs(60),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(
+            Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap(),
+            config,
+        ));
+        let get = || {
+            let request = "GET /about HTTP/1.1\r\nConnection: close\r\n\r\n";
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), request).0
+        };
+
+        assert!(get().ends_with("\r\n\r\nold"));
+        pages.write("about.html", "new");
+        thread::sleep(Duration::from_millis(600));
+        // The stale page is answered from the cache, the change only shows once the
+        // background refresh has stored it
+        assert!(get().ends_with("\r\n\r\nold"));
+        let start = Instant::now();
+        while !get().ends_with("\r\n\r\nnew") {
+            assert!(start.elapsed() < Duration::from_secs(5), "Never refreshed");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(server.cache.hits() >= 2);
+    }
+
+    #[test]
+    fn test_failed_refresh_is_retried() {
+        let pages = TempPages::new("refresh");
+        pages.write("about.html", "old");
+        let config = ServerConfig {
+            response_cache_max_bytes: 1024,
+            response_cache_max_age: Some(Duration::from_millis(100)),
+            response_cache_stale_while_revalidate: Duration::from_secs(60),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(
+            Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap(),
+            config,
+        ));
+        let get = || {
+            let request = "GET /about HTTP/1.1\r\nConnection: close\r\n\r\n";
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), request).0
+        };
+
+        assert!(get().ends_with("\r\n\r\nold"));
+        server.background.as_ref().unwrap().shutdown();
+        thread::sleep(Duration::from_millis(200));
+        assert!(get().ends_with("\r\n\r\nold"));
+        // The refresh couldn't be scheduled, so the next request is asked again
+        let hit = server.cache.get(&Request::new("GET", "/about")).unwrap();
+        assert!(hit.refresh);
+    }
+
+    #[test]
+    fn test_shutdown_returns_service_unavailable() {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.add_handler(
+            Method::Get,
+            "/health",
+            Box::new(|_| Response::new().body(b"ok".to_vec())),
+        );
+
+        let mut server = Server::new(router, ServerConfig::default());
+        server.add_websocket_route("/ws/echo", Box::new(websocket::echo));
+        let server = Arc::new(server);
+        server.shutting_down.store(true, Ordering::SeqCst);
+        let client = connect_to(server);
+
+        let (response, _) = send_and_wait_for_close(client, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 503 SERVICE UNAVAILABLE"));
+        assert!(response.contains("Retry-After: 5\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_maintenance_mode() {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.add_handler(
+            Method::Get,
+            "/health",
+            Box::new(|_| Response::new().body(b"ok".to_vec())),
+        );
+        let sentinel =
+            std::env::temp_dir().join(format!("web-server-maintenance-{}", std::process::id()));
+        let server = Arc::new(Server::new(
+            router,
+            ServerConfig {
+                maintenance: Some(MaintenanceConfig {
+                    enabled: false,
+                    sentinel: Some(sentinel.clone()),
+                    retry_after: Duration::from_secs(120),
+                    page: Some(PathBuf::from("pages/index.html")),
+                    allow: vec![String::from("/health")],
+                }),
+                ..ServerConfig::default()
+            },
+        ));
+        let request = "GET /about HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+        let (response, _) = send_and_wait_for_close(connect_to(Arc::clone(&server)), request);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+        fs::write(&sentinel, b"").unwrap();
+        let (response, _) = send_and_wait_for_close(connect_to(Arc::clone(&server)), request);
+        let (health, _) = send_and_wait_for_close(
+            connect_to(Arc::clone(&server)),
+            "GET /health HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        fs::remove_file(&sentinel).unwrap();
+
+        let page = fs::read_to_string("pages/index.html").unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 SERVICE UNAVAILABLE"));
+        assert!(response.contains("Retry-After: 120\r\n"));
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(response.ends_with(&page));
+        assert!(health.starts_with("HTTP/1.1 200 OK"));
+        assert!(health.ends_with("\r\n\r\nok"));
+    }
+
+    #[test]
+    fn test_websocket_echo() {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.add_handler(
+            Method::Get,
+            "/health",
+            Box::new(|_| Response::new().body(b"ok".to_vec())),
+        );
+
+        let mut server = Server::new(router, ServerConfig::default());
+        server.add_websocket_route("/ws/echo", Box::new(websocket::echo));
+        let mut client = connect_to(Arc::new(server));
+
+        client
+            .write_all(
+                b"GET /ws/echo HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.starts_with("HTTP/1.1 101 SWITCHING PROTOCOLS"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        // A masked text frame carrying "hello"
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![0x81, 0x80 | 5];
+        frame.extend_from_slice(&mask);
+        frame.extend(b"hello".iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        client.write_all(&frame).unwrap();
+
+        let mut echoed = [0; 7];
+        reader.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"\x81\x05hello");
+    }
+
+    #[test]
+    fn test_generated_robots_txt() {
+        let client = connect(ServerConfig {
+            robots: Some(RobotsConfig {
+                allow: vec![String::from("/")],
+                disallow: vec![String::from("/contact")],
+                sitemap: Some(String::from("https://example.com/sitemap.xml")),
+            }),
+            ..ServerConfig::default()
+        });
+
+        let (response, _) = send_and_wait_for_close(
+            client,
+            "GET /robots.txt HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with(
+            "\r\n\r\nUser-agent: *\nAllow: /\nDisallow: /contact\n\nSitemap: https://example.com/sitemap.xml\n"
+        ));
+    }
+
+    fn hello_router() -> Router {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.add_handler(
+            Method::Get,
+            "/hello",
+            Box::new(|_| {
+                Response::new()
+                    .header("X-Greeting", "hello")
+                    .body(b"hello world".to_vec())
+            }),
+        );
+        router
+    }
+
+    #[test]
+    fn test_not_found_page() {
+        let pages = TempPages::new("404-page");
+        pages.write("404.html", "<h1>Not here</h1>");
+        let router = Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap();
+        std::fs::remove_dir_all(&pages).unwrap();
+        let client = connect_to(Arc::new(Server::new(router, ServerConfig::default())));
+
+        let (response, _) =
+            send_and_wait_for_close(client, "GET /missing HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(response.contains("Content-Length: 17\r\n"));
+        assert!(response.ends_with("\r\n\r\n<h1>Not here</h1>"));
+    }
+
+    #[test]
+    fn test_fingerprinted_asset() {
+        let pages = TempPages::new("fingerprinted");
+        pages.write("app.js", "console.log(1);");
+        pages.write("index.html", "Home");
+        let router = Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap();
+        router.fingerprint_assets().unwrap();
+        let route = router.fingerprinted("/app.js").unwrap();
+        assert_ne!(route, "/app.js");
+        let server = Arc::new(Server::new(
+            router,
+            ServerConfig {
+                early_hints: vec![(
+                    String::from("/index"),
+                    vec![
+                        String::from("</app.js>; rel=preload; as=script"),
+                        String::from("</missing.js>; rel=preload; as=script"),
+                    ],
+                )],
+                ..ServerConfig::default()
+            },
+        ));
+
+        let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", route);
+        let (response, _) = send_and_wait_for_close(connect_to(Arc::clone(&server)), &request);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Cache-Control: public, max-age=31536000, immutable\r\n"));
+        assert!(response.ends_with("\r\n\r\nconsole.log(1);"));
+
+        // The canonical route serves the same file, without the long caching
+        let (response, _) = send_and_wait_for_close(
+            connect_to(Arc::clone(&server)),
+            "GET /app.js HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.ends_with("\r\n\r\nconsole.log(1);"));
+        assert!(!response.contains("immutable"));
+
+        // Early hints preload the fingerprinted route, other links are left alone
+        let (hints, _) = send_and_wait_for_close(
+            connect_to(server),
+            "GET /index HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(hints.contains(&format!(
+            "\r\nLink: <{}>; rel=preload; as=script, </missing.js>; rel=preload; as=script\r\n",
+            route
+        )));
+    }
+
+    #[test]
+    fn test_not_found_handler() {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.set_not_found_handler(Box::new(|request| {
+            Response::new()
+                .status(404, "NOT FOUND")
+                .header("Content-Type", "application/json")
+                .body(format!("{{\"error\":\"no route for {}\"}}", request.path).into_bytes())
+        }));
+        let client = connect_to(Arc::new(Server::new(router, ServerConfig::default())));
+
+        let (response, _) =
+            send_and_wait_for_close(client, "GET /missing HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"error\":\"no route for /missing\"}"));
+    }
+
+    #[test]
+    fn test_locale_negotiation() {
+        let pages = TempPages::new("locales");
+        pages.write("about.en.html", "About");
+        pages.write("about.fr.html", "À propos");
+        pages.write("index.html", "Home");
+        let server = Arc::new(Server::new(
+            Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap(),
+            ServerConfig {
+                default_locale: Some(String::from("en")),
+                ..ServerConfig::default()
+            },
+        ));
+        let get = |accept_language: &str| {
+            let request = format!(
+                "GET /about HTTP/1.1\r\nAccept-Language: {}\r\nConnection: close\r\n\r\n",
+                accept_language
+            );
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), &request).0
+        };
+
+        let french = get("de, fr-CH;q=0.9, en;q=0.5");
+        assert!(french.contains("Content-Language: fr\r\n"));
+        assert!(french.contains("Vary: Accept-Language\r\n"));
+        assert!(french.ends_with("\r\n\r\nÀ propos"));
+
+        let fallback = get("de");
+        assert!(fallback.contains("Content-Language: en\r\n"));
+        assert!(fallback.ends_with("\r\n\r\nAbout"));
+
+        // Pages without variants are served as they are
+        let (index, _) = send_and_wait_for_close(
+            connect_to(Arc::clone(&server)),
+            "GET / HTTP/1.1\r\nAccept-Language: fr\r\nConnection: close\r\n\r\n",
+        );
+        assert!(index.ends_with("\r\n\r\nHome"));
+        assert!(!index.contains("Content-Language"));
+    }
+
+    #[test]
+    fn test_response_headers() {
+        let config = ServerConfig {
+            response_headers: vec![
+                ResponseHeader {
+                    name: String::from("X-Trace-Origin"),
+                    value: String::from("edge"),
+                    always: true,
+                },
+                ResponseHeader {
+                    name: String::from("X-Greeting"),
+                    value: String::from("hi"),
+                    always: false,
+                },
+            ],
+            ..ServerConfig::default()
+        };
+        let get = |path: &str| {
+            let server = Server::new(hello_router(), config.clone());
+            let client = connect_to(Arc::new(server));
+            let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+            send_and_wait_for_close(client, &request).0
+        };
+
+        let hello = get("/hello");
+        assert!(hello.contains("X-Trace-Origin: edge\r\n"));
+        assert!(hello.contains("X-Greeting: hello\r\n"));
+        assert!(!hello.contains("X-Greeting: hi\r\n"));
+
+        for path in ["/about", "/missing"] {
+            let response = get(path);
+            assert!(response.contains("X-Trace-Origin: edge\r\n"));
+            assert!(response.contains("X-Greeting: hi\r\n"));
+        }
+    }
+
+    #[test]
+    fn test_head_file() {
+        let about = fs::read("pages/about.html").unwrap();
+        for stream_min_bytes in [0, u64::MAX] {
+            let config = ServerConfig {
+                stream_min_bytes,
+                ..ServerConfig::default()
+            };
+            let (get, _) = send_and_wait_for_close(
+                connect(config.clone()),
+                "GET /about HTTP/1.1\r\nConnection: close\r\n\r\n",
+            );
+            let (head, _) = send_and_wait_for_close(
+                connect(config),
+                "HEAD /about HTTP/1.1\r\nConnection: close\r\n\r\n",
+            );
+
+            assert!(head.starts_with("HTTP/1.1 200 OK"));
+            assert!(head.contains(&format!("Content-Length: {}\r\n", about.len())));
+            assert!(head.contains("Content-Type: text/html; charset=utf-8\r\n"));
+            assert!(head.ends_with("\r\n\r\n"));
+            // The two responses may be a second apart
+            let without_date = |response: &str| {
+                response
+                    .split("\r\n")
+                    .filter(|line| !line.starts_with("Date: "))
+                    .collect::<Vec<_>>()
+                    .join("\r\n")
+            };
+            let get = get
+                .strip_suffix(std::str::from_utf8(&about).unwrap())
+                .unwrap();
+            assert_eq!(without_date(get), without_date(&head));
+        }
+    }
+
+    #[test]
+    fn test_date_header() {
+        let before = std::time::SystemTime::now();
+        let (response, _) = send_and_wait_for_close(
+            connect(ServerConfig::default()),
+            "GET /about HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let after = std::time::SystemTime::now();
+
+        let date = response
+            .lines()
+            .find_map(|line| line.strip_prefix("Date: "))
+            .unwrap();
+        assert!(date.ends_with(" GMT"));
+        assert!(date == date::http_date(before) || date == date::http_date(after));
+    }
+
+    #[test]
+    fn test_head_not_found() {
+        let client = connect(ServerConfig::default());
+        let (response, _) = send_and_wait_for_close(
+            client,
+            "HEAD /nonexistent HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(response.contains("Content-Length: 0\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        // A handler's 404 keeps the length of the body a GET would get, and the next
+        // request on the connection isn't mistaken for the missing body
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.set_not_found_handler(Box::new(|_| {
+            Response::new()
+                .status(404, "NOT FOUND")
+                .body(b"gone".to_vec())
+        }));
+        let client = connect_to(Arc::new(Server::new(router, ServerConfig::default())));
+        let (response, _) = send_and_wait_for_close(
+            client,
+            "HEAD /nonexistent HTTP/1.1\r\n\r\n\
+             GET /nonexistent HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let (head, get) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(head.contains("Content-Length: 4"));
+        assert!(get.starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(get.ends_with("\r\n\r\ngone"));
+    }
+
+    #[test]
+    fn test_precompressed_siblings() {
+        // The compressed bodies are not UTF-8, so only the head is compared as text
+        let request_with = |chain: &[CompressionStep], accept_encoding: &str| {
+            let mut client = connect(ServerConfig {
+                compression_chain: chain.to_vec(),
+                compress_min_bytes: 0,
+                ..ServerConfig::default()
+            });
+            let request = format!(
+                "GET /about HTTP/1.1\r\nAccept-Encoding: {}\r\nConnection: close\r\n\r\n",
+                accept_encoding
+            );
+            client.write_all(request.as_bytes()).unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            String::from_utf8_lossy(&response).into_owned()
+        };
+        let request =
+            |accept_encoding: &str| request_with(&DEFAULT_COMPRESSION_CHAIN, accept_encoding);
+        let length = |file: &str| std::fs::metadata(file).unwrap().len();
+
+        let response = request("gzip, br");
+        assert!(response.contains("Content-Encoding: br\r\n"));
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        let br_length = length("pages/about.html.br");
+        assert!(response.contains(&format!("Content-Length: {}\r\n", br_length)));
+
+        let response = request("gzip");
+        assert!(response.contains("Content-Encoding: gzip\r\n"));
+        let gz_length = length("pages/about.html.gz");
+        assert!(response.contains(&format!("Content-Length: {}\r\n", gz_length)));
+
+        let response = request("identity");
+        assert!(!response.contains("Content-Encoding"));
+        assert!(response.contains("<h1>About</h1>"));
+
+        // Compressing on the fly is preferred over the siblings when it comes first
+        let response = request_with(&[CompressionStep::Gzip], "gzip, br");
+        assert!(response.contains("Content-Encoding: gzip\r\n"));
+        assert!(!response.contains(&format!("Content-Length: {}\r\n", gz_length)));
+        let response = request_with(&[CompressionStep::Br, CompressionStep::Gzip], "gzip, br");
+        assert!(response.contains("Content-Encoding: br\r\n"));
+        assert!(!response.contains(&format!("Content-Length: {}\r\n", br_length)));
+        let response = request_with(&[CompressionStep::GzipSibling], "gzip, br");
+        assert!(response.contains(&format!("Content-Length: {}\r\n", gz_length)));
+        let response = request_with(&[], "gzip, br");
+        assert!(response.contains("<h1>About</h1>"));
+    }
+
+    #[test]
+    fn test_strict_vhost() {
+        let config = |strict_vhost| ServerConfig {
+            virtual_hosts: vec![String::from("example.com")],
+            strict_vhost,
+            ..ServerConfig::default()
+        };
+        let request = |host: &str| {
+            format!(
+                "GET /about HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                host
+            )
+        };
+
+        let (response, _) = send_and_wait_for_close(connect(config(true)), &request("other.org"));
+        assert!(response.starts_with("HTTP/1.1 421 MISDIRECTED REQUEST"));
+
+        let (response, _) =
+            send_and_wait_for_close(connect(config(true)), &request("Example.com:7878"));
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+        let (response, _) = send_and_wait_for_close(connect(config(false)), &request("other.org"));
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+        let client = connect(ServerConfig {
+            vhost_reject_status: config::VhostRejectStatus::try_from(404).unwrap(),
+            ..config(true)
+        });
+        let (response, _) = send_and_wait_for_close(client, &request("other.org"));
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND"));
+    }
+
+    #[test]
+    fn test_transport_security_only_when_secure() {
+        let config = || ServerConfig {
+            trust_proxy: true,
+            hsts: Some(HstsConfig {
+                max_age: Duration::from_secs(31_536_000),
+                include_subdomains: true,
+                preload: true,
+            }),
+            expect_ct: Some(ExpectCtConfig {
+                max_age: Duration::from_secs(86_400),
+                enforce: true,
+                report_uri: Some(String::from("https://example.com/report")),
+            }),
+            ..ServerConfig::default()
+        };
+
+        let (response, _) = send_and_wait_for_close(
+            connect(config()),
+            "GET /about HTTP/1.1\r\nX-Forwarded-Proto: https\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.contains(
+            "Strict-Transport-Security: max-age=31536000; includeSubDomains; preload\r\n"
+        ));
+        assert!(response.contains(
+            "Expect-CT: max-age=86400, enforce, report-uri=\"https://example.com/report\"\r\n"
+        ));
+
+        let (response, _) = send_and_wait_for_close(
+            connect(config()),
+            "GET /about HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(!response.contains("Strict-Transport-Security"));
+        assert!(!response.contains("Expect-CT"));
+    }
+
+    #[test]
+    fn test_bundle_route() {
+        let client = connect(ServerConfig {
+            bundles: vec![BundleConfig {
+                path: String::from("/bundle.html"),
+                files: vec![String::from("about.html"), String::from("index.html")],
+            }],
+            ..ServerConfig::default()
+        });
+
+        let (response, _) = send_and_wait_for_close(
+            client,
+            "GET /bundle.html HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let about = std::fs::read_to_string("pages/about.html").unwrap();
+        let index = std::fs::read_to_string("pages/index.html").unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("ETag: \""));
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        assert!(body.starts_with(&about) && body.ends_with(&index));
+    }
+
+    #[test]
+    fn test_method_not_allowed() {
+        let mut router = hello_router();
+        router.add_handler(
+            Method::Delete,
+            "/hello",
+            Box::new(|_| Response::new().status(204, "NO CONTENT")),
+        );
+        let config = ServerConfig {
+            method_not_allowed_body: Some(String::from("Use GET or DELETE")),
+            ..ServerConfig::default()
+        };
+        let client = connect_to(Arc::new(Server::new(router, config)));
+
+        let (response, _) = send_and_wait_for_close(
+            client,
+            "POST /hello HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+        );
+        assert!(response.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED"));
+        assert!(response.contains("Allow: DELETE, GET, HEAD\r\n"));
+        assert!(response.ends_with("\r\n\r\nUse GET or DELETE"));
+    }
+
+    #[test]
+    fn test_page_with_post_handler() {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.add_handler(
+            Method::Post,
+            "/about.html",
+            Box::new(|_| Response::new().body(b"Sent".to_vec())),
+        );
+        let server = Arc::new(Server::new(router, ServerConfig::default()));
+        let send =
+            |request: &str| send_and_wait_for_close(connect_to(Arc::clone(&server)), request).0;
+
+        let page = send("GET /about.html HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(page.starts_with("HTTP/1.1 200 OK"));
+        assert!(page.contains("Content-Type: text/html"));
+        let head = send("HEAD /about.html HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        let form =
+            send("POST /about.html HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        assert!(form.ends_with("\r\n\r\nSent"));
+
+        let response = send("DELETE /about.html HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED"));
+        assert!(response.contains("Allow: GET, HEAD, POST\r\n"));
+    }
+
+    #[test]
+    fn test_method_not_allowed_on_file() {
+        let client = connect(ServerConfig::default());
+
+        let (response, _) = send_and_wait_for_close(
+            client,
+            "DELETE /about HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED"));
+        assert!(response.contains("Allow: GET, HEAD\r\n"));
+
+        let client = connect(ServerConfig::default());
+        let (response, _) =
+            send_and_wait_for_close(client, "HEAD /about HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_unsupported_media_type() {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.add_handler(
+            Method::Post,
+            "/api/items",
+            Box::new(|request| Response::new().body(request.body.clone())),
+        );
+        let config = ServerConfig {
+            route_content_types: vec![(
+                String::from("/api"),
+                vec![String::from("application/json")],
+            )],
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(router, config));
+        let request = |content_type: &str| {
+            format!(
+                "POST /api/items HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: 2\r\n\
+                 Connection: close\r\n\r\n{{}}",
+                content_type
+            )
+        };
+
+        let (response, _) =
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), &request("text/plain"));
+        assert!(response.starts_with("HTTP/1.1 415 UNSUPPORTED MEDIA TYPE"));
+
+        let (response, _) = send_and_wait_for_close(
+            connect_to(server),
+            &request("application/json; charset=utf-8"),
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\n{}"));
+    }
+
+    #[test]
+    fn test_strong_and_weak_etags() {
+        let get = |headers: &str| {
+            let mut client = connect(ServerConfig::default());
+            let request = format!(
+                "GET /index.html HTTP/1.1\r\n{}Connection: close\r\n\r\n",
+                headers
+            );
+            client.write_all(request.as_bytes()).unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            String::from_utf8_lossy(&response).into_owned()
+        };
+        let etag = |response: &str| {
+            response
+                .lines()
+                .find_map(|line| line.strip_prefix("ETag: "))
+                .unwrap()
+                .to_string()
+        };
+
+        let strong = etag(&get(""));
+        assert!(strong.starts_with('"'));
+        let weak = etag(&get("Accept-Encoding: gzip\r\n"));
+        assert_eq!(weak, format!("W/{}", strong));
+
+        let response = get(&format!("If-None-Match: {}\r\n", strong));
+        assert!(response.starts_with("HTTP/1.1 304 NOT MODIFIED"));
+        let response = get(&format!(
+            "Accept-Encoding: gzip\r\nIf-None-Match: {}\r\n",
+            strong
+        ));
+        assert!(response.starts_with("HTTP/1.1 304 NOT MODIFIED"));
+
+        let response = get(&format!("If-Match: {}\r\n", strong));
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        let response = get(&format!("Accept-Encoding: gzip\r\nIf-Match: {}\r\n", weak));
+        assert!(response.starts_with("HTTP/1.1 412 PRECONDITION FAILED"));
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let pages = TempPages::new("modified");
+        let file = std::fs::File::create(pages.join("page.html")).unwrap();
+        (&file).write_all(b"Page").unwrap();
+        // The example date from RFC 7231, at 08:49:37
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(784_111_777))
+            .unwrap();
+        let server = Arc::new(Server::new(
+            Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap(),
+            ServerConfig::default(),
+        ));
+        let get = |headers: &str| {
+            let request = format!("GET /page HTTP/1.1\r\n{}Connection: close\r\n\r\n", headers);
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), &request).0
+        };
+
+        let response = get("");
+        assert!(response.contains("Last-Modified: Sun, 06 Nov 1994 08:49:37 GMT\r\n"));
+
+        for since in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 08:49:38 GMT",
+            "Sunday, 06-Nov-94 09:00:00 GMT",
+        ] {
+            let response = get(&format!("If-Modified-Since: {}\r\n", since));
+            assert!(
+                response.starts_with("HTTP/1.1 304 NOT MODIFIED"),
+                "{}",
+                since
+            );
+            assert!(response.contains("Last-Modified: Sun, 06 Nov 1994 08:49:37 GMT\r\n"));
+            assert!(response.ends_with("\r\n\r\n"));
+        }
+
+        // Modified since, an unreadable date, or an `ETag` to check instead
+        for headers in [
+            "If-Modified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n",
+            "If-Modified-Since: last tuesday\r\n",
+            "If-None-Match: \"other\"\r\nIf-Modified-Since: Sun, 06 Nov 1994 09:00:00 GMT\r\n",
+        ] {
+            let response = get(headers);
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", headers);
+            assert!(response.ends_with("\r\n\r\nPage"));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_route_read_error() {
+        // Opens as a regular file, but reading it from the start fails
+        let file = String::from("/proc/self/mem");
+        for stream_min_bytes in [u64::MAX, 0] {
+            let config = ServerConfig {
+                stream_min_bytes,
+                ..ServerConfig::default()
+            };
+            let response = handle_route(&file, None, &config);
+            assert_eq!(response.status_code(), 500);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_files_outside_pages_forbidden() {
+        let dir = TempPages::new("outside");
+        let pages = dir.join("pages");
+        dir.write("pages/docs/intro.html", "Intro");
+        dir.write("secret.txt", "Secret");
+        std::os::unix::fs::symlink("../../secret.txt", pages.join("docs/secret.txt")).unwrap();
+        // A precompressed sibling leading out of pages is not served in its place
+        dir.write("secret.gz", "Secret");
+        std::os::unix::fs::symlink("../../secret.gz", pages.join("docs/intro.html.gz")).unwrap();
+        dir.write("pages/docs/gone.html", "Gone");
+        let server = Arc::new(Server::new(
+            Router::with_root(pages.clone(), ScanLimit::default()).unwrap(),
+            ServerConfig::default(),
+        ));
+        let get = |path: &str| {
+            let request = format!(
+                "GET {} HTTP/1.1\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n",
+                path
+            );
+            send_and_wait_for_close(connect_to(Arc::clone(&server)), &request).0
+        };
+
+        let response = get("/docs/intro");
+        assert!(!response.contains("Content-Encoding"));
+        assert!(response.ends_with("\r\n\r\nIntro"));
+        assert!(get("/docs/intro.html.gz").starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(get("/docs/secret.txt").starts_with("HTTP/1.1 403 FORBIDDEN"));
+        // A file removed since the routes were built is not mistaken for one outside
+        std::fs::remove_file(pages.join("docs/gone.html")).unwrap();
+        assert!(get("/docs/gone").starts_with("HTTP/1.1 500 INTERNAL SERVER ERROR"));
+        // Dot segments are refused outright, encoded or not
+        assert!(get("/docs/../../secret.txt").starts_with("HTTP/1.1 400 BAD REQUEST"));
+        assert!(get("/docs/%2e%2e/%2e%2e/secret.txt").starts_with("HTTP/1.1 400 BAD REQUEST"));
+    }
+
+    #[test]
+    fn test_range_requests() {
+        let pages = TempPages::new("range");
+        pages.write("data.txt", "0123456789");
+        // Ranges of streamed files skip to the start instead of reading everything
+        for stream_min_bytes in [u64::MAX, 0] {
+            let server = Arc::new(Server::new(
+                Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap(),
+                ServerConfig {
+                    stream_min_bytes,
+                    ..ServerConfig::default()
+                },
+            ));
+            let get = |range: &str| {
+                let request = format!(
+                    "GET /data.txt HTTP/1.1\r\nRange: {}\r\nConnection: close\r\n\r\n",
+                    range
+                );
+                send_and_wait_for_close(connect_to(Arc::clone(&server)), &request).0
+            };
+
+            let response = get("bytes=3-6");
+            assert!(response.starts_with("HTTP/1.1 206 PARTIAL CONTENT"));
+            assert!(response.contains("Content-Range: bytes 3-6/10\r\n"));
+            assert!(response.contains("Content-Length: 4\r\n"));
+            assert!(response.ends_with("\r\n\r\n3456"));
+
+            let response = get("bytes=10-20");
+            assert!(response.starts_with("HTTP/1.1 416 RANGE NOT SATISFIABLE"));
+            assert!(response.contains("Content-Range: bytes */10\r\n"));
+
+            let response = get("bytes=6-3");
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+            assert!(response.contains("Accept-Ranges: bytes\r\n"));
+            assert!(response.ends_with("\r\n\r\n0123456789"));
+        }
+    }
+
+    #[test]
+    fn test_head_served_by_get_handler() {
+        let client = connect_to(Arc::new(Server::new(
+            hello_router(),
+            ServerConfig::default(),
+        )));
+
+        let (response, _) =
+            send_and_wait_for_close(client, "HEAD /hello HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("X-Greeting: hello\r\n"));
+        assert!(response.contains("Content-Length: 11\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_specialized_head_handler() {
+        let mut router = hello_router();
+        router.add_handler(
+            Method::Head,
+            "/hello",
+            Box::new(|_| Response::new().header("X-Greeting", "head")),
+        );
+        let client = connect_to(Arc::new(Server::new(router, ServerConfig::default())));
+
+        let (response, _) =
+            send_and_wait_for_close(client, "HEAD /hello HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.contains("X-Greeting: head\r\n"));
+        assert!(response.contains("Content-Length: 0\r\n"));
+    }
+
+    #[test]
+    fn test_head_from_get_disabled() {
+        let config = ServerConfig {
+            head_from_get: false,
+            ..ServerConfig::default()
+        };
+        let client = connect_to(Arc::new(Server::new(hello_router(), config)));
+
+        let (response, _) =
+            send_and_wait_for_close(client, "HEAD /hello HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED"));
+        assert!(response.contains("Allow: GET\r\n"));
+    }
+
+    #[test]
+    fn test_max_response_bytes() {
+        let mut router = hello_router();
+        router.add_handler(
+            Method::Get,
+            "/huge",
+            Box::new(|_| Response::new().body(vec![b'x'; 1024])),
+        );
+        let server = Arc::new(Server::new(
+            router,
+            ServerConfig {
+                max_response_bytes: Some(512),
+                ..ServerConfig::default()
+            },
+        ));
+
+        let (response, _) = send_and_wait_for_close(
+            connect_to(Arc::clone(&server)),
+            "GET /huge HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 500 INTERNAL SERVER ERROR"));
+        assert!(response.contains("Content-Length: 0\r\n"));
+
+        let (response, _) = send_and_wait_for_close(
+            connect_to(server),
+            "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("hello world"));
+    }
+
+    #[test]
+    fn test_early_hints() {
+        let client = connect_to(Arc::new(Server::new(
+            hello_router(),
+            ServerConfig {
+                early_hints: vec![(
+                    String::from("/hello"),
+                    vec![
+                        String::from("</style.css>; rel=preload; as=style"),
+                        String::from("</app.js>; rel=preload; as=script"),
+                    ],
+                )],
+                ..ServerConfig::default()
+            },
+        )));
+
+        let (response, _) =
+            send_and_wait_for_close(client, "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (hints, response) = response.split_once("\r\n\r\n").unwrap();
+        assert!(hints.starts_with("HTTP/1.1 103 EARLY HINTS\r\n"));
+        assert!(hints.ends_with(
+            "\r\nLink: </style.css>; rel=preload; as=style, </app.js>; rel=preload; as=script"
+        ));
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("hello world"));
+    }
+
+    #[test]
+    fn test_handler_sees_route_deadline() {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.add_handler(
+            Method::Get,
+            "/reports",
+            Box::new(|request| {
+                let remaining = request.time_remaining().unwrap();
+                Response::new().body(remaining.as_millis().to_string().into_bytes())
+            }),
+        );
+        let config = ServerConfig {
+            route_timeouts: vec![(String::from("/reports"), Duration::from_secs(60))],
+            ..ServerConfig::default()
+        };
+        let client = connect_to(Arc::new(Server::new(router, config)));
+
+        let (response, _) =
+            send_and_wait_for_close(client, "GET /reports HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let remaining: u128 = response.split("\r\n\r\n").nth(1).unwrap().parse().unwrap();
+        assert!(remaining > 59_000 && remaining <= 60_000);
+    }
+
+    /// Yields a few bytes and then fails, like a disk read error partway through a file
+    struct FailingReader {
+        sent: bool,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.sent {
+                return Err(io::Error::other("disk read failed"));
+            }
+            self.sent = true;
+            buf[..10].copy_from_slice(b"0123456789");
+            Ok(10)
+        }
+    }
+
+    #[test]
+    fn test_body_fails_mid_stream() {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.add_handler(
+            Method::Get,
+            "/large",
+            Box::new(|_| Response::new().stream(Box::new(FailingReader { sent: false }), 100)),
+        );
+        let config = ServerConfig {
+            keep_alive_timeout: LONG,
+            ..ServerConfig::default()
+        };
+        let client = connect_to(Arc::new(Server::new(router, config)));
+
+        // A keep-alive request, so only the abort closes the connection early
+        let (response, elapsed) = send_and_wait_for_close(client, "GET /large HTTP/1.1\r\n\r\n");
+        assert!(response.contains("Content-Length: 100\r\n"));
+        assert!(response.ends_with("\r\n\r\n0123456789"));
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    /// Yields zeroes forever, recording when the response lets go of it
+    struct EndlessReader {
+        read: Arc<AtomicUsize>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl Read for EndlessReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            buf.fill(0);
+            self.read.fetch_add(buf.len(), Ordering::SeqCst);
+            Ok(buf.len())
+        }
+    }
+
+    impl Drop for EndlessReader {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_client_disconnects_mid_stream() {
+        const LENGTH: u64 = 1024 * 1024 * 1024;
+        let read = Arc::new(AtomicUsize::new(0));
+        let dropped = Arc::new(AtomicBool::new(false));
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        let (reader_read, reader_dropped) = (Arc::clone(&read), Arc::clone(&dropped));
+        router.add_handler(
+            Method::Get,
+            "/endless",
+            Box::new(move |_| {
+                let reader = EndlessReader {
+                    read: Arc::clone(&reader_read),
+                    dropped: Arc::clone(&reader_dropped),
+                };
+                Response::new().stream(Box::new(reader), LENGTH)
+            }),
+        );
+        let mut client = connect_to(Arc::new(Server::new(router, ServerConfig::default())));
+
+        client.write_all(b"GET /endless HTTP/1.1\r\n\r\n").unwrap();
+        let mut buffer = [0; 1024];
+        client.read_exact(&mut buffer).unwrap();
+        drop(client);
+
+        let start = Instant::now();
+        while !dropped.load(Ordering::SeqCst) {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!((read.load(Ordering::SeqCst) as u64) < LENGTH);
+    }
+
+    #[test]
+    fn test_streamed_file() {
+        let client = connect(ServerConfig {
+            stream_min_bytes: 0,
+            ..ServerConfig::default()
+        });
+
+        let (response, _) =
+            send_and_wait_for_close(client, "GET /about HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let expected = std::fs::read_to_string("pages/about.html").unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains(&format!("Content-Length: {}\r\n", expected.len())));
+        assert!(response.ends_with(&expected));
+    }
+
+    #[test]
+    fn test_route_file_became_directory() {
+        let pages = TempPages::new("now-dir");
+        pages.write("page.html", "Page");
+        let server = Arc::new(Server::new(
+            Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap(),
+            ServerConfig::default(),
+        ));
+        std::fs::remove_file(pages.join("page.html")).unwrap();
+        std::fs::create_dir(pages.join("page.html")).unwrap();
+
+        let (response, _) = send_and_wait_for_close(
+            connect_to(server),
+            "GET /page HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 500 INTERNAL SERVER ERROR"));
+    }
+
+    #[test]
+    fn test_warm_cache() {
+        let pages = TempPages::new("warm");
+        pages.write("docs/index.html", "Docs");
+        pages.write("large.txt", vec![b'a'; 1000]);
+        let server = Server::new(
+            Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap(),
+            ServerConfig {
+                response_cache_max_bytes: 1 << 20,
+                warm_cache: true,
+                warm_cache_max_bytes: Some(100),
+                ..ServerConfig::default()
+            },
+        );
+        server.warm_cache();
+
+        // The background pool has one worker, so this runs once warming is done
+        let (done, warmed) = std::sync::mpsc::channel();
+        let background = server.background.as_ref().unwrap();
+        background.execute(move || done.send(()).unwrap()).unwrap();
+        warmed.recv_timeout(Duration::from_secs(5)).unwrap();
+        std::fs::remove_dir_all(&pages).unwrap();
+
+        let get_with = |path: &str, accept_encoding: &str| {
+            let raw = format!(
+                "GET {} HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+                path, accept_encoding
+            );
+            server
+                .cache
+                .get(&Request::parse_head(&mut raw.as_bytes()).unwrap())
+        };
+        let get = |path: &str| get_with(path, "identity");
+        for path in ["/docs/index.html", "/docs/index", "/docs"] {
+            let hit = get(path).unwrap_or_else(|| panic!("{:?} was not warmed", path));
+            assert_eq!(hit.response.get_body(), Some(&b"Docs"[..]));
+        }
+        // Warmed for whichever codings the client accepts
+        for accept_encoding in ["gzip", "br", "gzip, deflate, br"] {
+            assert!(get_with("/docs", accept_encoding).is_some());
+        }
+        // Past `warm_cache_max_bytes`
+        assert!(get("/large.txt").is_none());
+    }
+
+    #[test]
+    fn test_directory_without_index() {
+        use router::Resolution;
+
+        let pages = TempPages::new("no-index");
+        pages.write("docs/guide.html", "Guide");
+        let get = |config: ServerConfig, path: &str| {
+            let server = Arc::new(Server::new(
+                Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap(),
+                config,
+            ));
+            let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+            send_and_wait_for_close(connect_to(server), &request).0
+        };
+        let exact = || ServerConfig {
+            path_resolution: vec![Resolution::Exact],
+            ..ServerConfig::default()
+        };
+
+        let forbidden = get(exact(), "/docs");
+        let forbidden_slash = get(exact(), "/docs/");
+        let not_found = get(
+            ServerConfig {
+                directory_status: config::DirectoryStatus::try_from(404).unwrap(),
+                ..exact()
+            },
+            "/docs/",
+        );
+        let missing = get(exact(), "/doc");
+        let guide = get(exact(), "/docs/guide.html");
+
+        assert!(forbidden.starts_with("HTTP/1.1 403 FORBIDDEN"));
+        assert!(forbidden_slash.starts_with("HTTP/1.1 403 FORBIDDEN"));
+        assert!(not_found.starts_with("HTTP/1.1 404 NOT FOUND"));
+        // Only whole path segments are directories
+        assert!(missing.starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(guide.ends_with("\r\n\r\nGuide"));
+    }
+
+    #[test]
+    fn test_binary_file() {
+        let path = std::env::temp_dir().join(format!("web-server-{}.ico", std::process::id()));
+        let contents: Vec<u8> = (0..=255).chain([0xff, 0xfe, 0x00, 0x80]).collect();
+        fs::write(&path, &contents).unwrap();
+        let response = handle_route(
+            &path.to_str().unwrap().to_string(),
+            None,
+            &ServerConfig::default(),
+        );
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(response.get_body(), Some(&contents[..]));
+        let head = String::from_utf8(response.to_head_bytes()).unwrap();
+        assert!(head.contains(&format!("Content-Length: {}\r\n", contents.len())));
+    }
+
+    #[test]
+    fn test_streamed_file_etag() {
+        let path = std::env::temp_dir().join(format!("web-server-etag-{}.txt", std::process::id()));
+        fs::write(&path, b"streamed").unwrap();
+        let config = ServerConfig {
+            stream_min_bytes: 0,
+            ..ServerConfig::default()
+        };
+        let path_str = path.to_str().unwrap().to_string();
+        let etag = |modified| {
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+            let response = handle_route(&path_str, None, &config);
+            assert!(response.get_body().is_none());
+            response.get_header("ETag").unwrap().to_string()
+        };
+
+        let epoch = std::time::UNIX_EPOCH;
+        let before = etag(epoch + Duration::from_secs(1_700_000_000));
+        let touched = etag(epoch + Duration::from_secs(1_700_000_060));
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(before, "W/\"1700000000-8\"");
+        assert_ne!(before, touched);
+    }
+
+    #[test]
+    fn test_server_timing() {
+        let client = connect(ServerConfig {
+            server_timing: true,
+            ..ServerConfig::default()
+        });
+
+        let (response, _) =
+            send_and_wait_for_close(client, "GET /about HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let header = response
+            .lines()
+            .find_map(|line| line.strip_prefix("Server-Timing: "))
+            .unwrap();
+        let names: Vec<_> = header
+            .split(", ")
+            .map(|metric| metric.split(";dur=").next().unwrap())
+            .collect();
+        assert_eq!(names, ["route", "file"]);
+    }
+
+    #[test]
+    fn test_requests_during_reload() {
+        use router::Resolution;
+        use std::sync::atomic::AtomicBool;
+
+        let pages = TempPages::new("reload");
+        pages.write("about.html", "old");
+
+        // Once reloaded, `/about` resolves to the new directory index instead
+        let config = ServerConfig {
+            path_resolution: vec![Resolution::DirectoryIndex, Resolution::HtmlSibling],
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(
+            Router::with_root(pages.to_path_buf(), ScanLimit::default()).unwrap(),
+            config,
+        ));
+        let request = "GET /about HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let clients: Vec<_> = (0..4)
+            .map(|_| {
+                let server = Arc::clone(&server);
+                let stop = Arc::clone(&stop);
+                thread::spawn(move || {
+                    let mut bodies = Vec::new();
+                    while !stop.load(Ordering::SeqCst) {
+                        let (response, _) =
+                            send_and_wait_for_close(connect_to(Arc::clone(&server)), request);
+                        bodies.push(
+                            response
+                                .split_once("\r\n\r\n")
+                                .map(|(_, body)| body.to_string()),
+                        );
+                    }
+                    bodies
+                })
+            })
+            .collect();
+
+        thread::sleep(Duration::from_millis(50));
+        pages.write("about/index.html", "new");
+        for _ in 0..20 {
+            server.router.reload().unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+        stop.store(true, Ordering::SeqCst);
+
+        let bodies: Vec<_> = clients
+            .into_iter()
+            .flat_map(|client| client.join().unwrap())
+            .collect();
+        let (response, _) = send_and_wait_for_close(connect_to(server), request);
+
+        assert!(!bodies.is_empty());
+        for body in &bodies {
+            assert!(
+                matches!(body.as_deref(), Some("old") | Some("new")),
+                "Unexpected response body {:?}",
+                body
+            );
+        }
+        assert!(response.ends_with("\r\n\r\nnew"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        use std::os::unix::net::UnixStream;
+
+        let path =
+            std::env::temp_dir().join(format!("web-server-test-{}.sock", std::process::id()));
+        let (listener, socket_file) = bind_unix(&path).unwrap();
+        let server = Server::new(
+            Router::new(ScanLimit::default()).unwrap(),
+            ServerConfig::default(),
+        );
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &server);
+        });
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .write_all(b"GET /about HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        handle.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("<h1>About</h1>"));
+
+        drop(socket_file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,71 @@
+use serde::Deserialize;
+
+/// An HTTP request method
+///
+/// Methods are case-sensitive, so `get` is `Other("get")` and not `Get`
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(from = "String")]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Other(String),
+}
+
+impl Method {
+    /// Get the method's token, as it is written in a request line
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Other(method) => method,
+        }
+    }
+}
+
+impl From<&str> for Method {
+    fn from(method: &str) -> Self {
+        match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "PATCH" => Method::Patch,
+            "OPTIONS" => Method::Options,
+            _ => Method::Other(method.to_string()),
+        }
+    }
+}
+
+impl From<String> for Method {
+    fn from(method: String) -> Self {
+        Method::from(method.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for token in [
+            "GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS", "PURGE",
+        ] {
+            assert_eq!(Method::from(token).as_str(), token);
+        }
+        assert_eq!(Method::from("GET"), Method::Get);
+        assert_eq!(Method::from("get"), Method::Other(String::from("get")));
+    }
+}
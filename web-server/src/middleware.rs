@@ -0,0 +1,128 @@
+use log::{debug, info, warn};
+use std::{sync::Arc, time::Instant};
+
+use crate::{config::ServerConfig, request::Request, response::Response};
+
+/// A layer of cross-cutting logic around answering requests, see `Router::add_middleware`
+///
+/// It either answers the request itself, e.g. with a `401`, or calls `Next::run` to
+/// get the response of the layers after it and the route, which it may change
+///
+pub type Middleware = Box<dyn Fn(&Request, Next) -> Response + Send + Sync>;
+
+/// The Next struct
+///
+/// The Next struct is the rest of the middleware chain after the current layer,
+/// ending with answering the request
+///
+/// # Fields
+///
+/// * `chain` - The layers still to run, in order
+/// * `request` - The request being answered
+/// * `respond` - Answers the request once every layer passed it on
+///
+pub struct Next<'a> {
+    chain: &'a [Middleware],
+    request: &'a Request,
+    respond: Box<dyn FnOnce() -> Response + 'a>,
+}
+
+impl<'a> Next<'a> {
+    /// Create the chain of the layers in `chain` around `respond`
+    pub fn new(
+        chain: &'a [Middleware],
+        request: &'a Request,
+        respond: Box<dyn FnOnce() -> Response + 'a>,
+    ) -> Self {
+        Next {
+            chain,
+            request,
+            respond,
+        }
+    }
+
+    /// Pass the request on to the next layer, or answer it after the last one
+    pub fn run(self) -> Response {
+        match self.chain.split_first() {
+            Some((layer, chain)) => layer(self.request, Next { chain, ..self }),
+            None => (self.respond)(),
+        }
+    }
+}
+
+/// Log every request, except those on `quiet_paths`, and how long answering it took
+pub fn log_requests(config: Arc<ServerConfig>) -> Middleware {
+    Box::new(move |request, next| {
+        if config.is_quiet_path(&request.path) {
+            return next.run();
+        }
+        info!(
+            "Request: {:#?}, Client: {:#?}",
+            format!(
+                "{} {} {}",
+                request.method, request.raw_path, request.version
+            ),
+            request.client_addr.as_deref().unwrap_or_default()
+        );
+        let start = Instant::now();
+        let response = next.run();
+        debug!(
+            "Answered {:#?} with {} in {:?}",
+            request.path,
+            response.status_code(),
+            start.elapsed()
+        );
+        response
+    })
+}
+
+/// Answer requests without `key` in their `X-Api-Key` header with `401`
+pub fn require_api_key(key: String) -> Middleware {
+    Box::new(move |request, next| {
+        if request.header("x-api-key") == Some(key.as_str()) {
+            return next.run();
+        }
+        warn!("Rejecting {:#?} without a valid API key", request.path);
+        Response::new().status(401, "UNAUTHORIZED")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_chain() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let layer = |name: &'static str| -> Middleware {
+            let order = Arc::clone(&order);
+            Box::new(move |request, next| {
+                order.lock().unwrap().push(name);
+                if request.path == format!("/{}", name) {
+                    return Response::new().status(403, "FORBIDDEN");
+                }
+                next.run().header("X-Layer", name)
+            })
+        };
+        let chain = [layer("outer"), layer("inner")];
+        let answer = |path: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+            let request = Request::parse_head(&mut raw.as_bytes()).unwrap();
+            let respond = Box::new(|| Response::new().body(b"page".to_vec()));
+            Next::new(&chain, &request, respond).run()
+        };
+
+        // Layers run in order on the way in, and in reverse on the way out
+        let response = answer("/page");
+        assert_eq!(response.get_body(), Some(&b"page"[..]));
+        assert_eq!(response.get_header("X-Layer"), Some("outer"));
+        assert_eq!(*order.lock().unwrap(), ["outer", "inner"]);
+
+        // A layer answering itself skips the ones after it
+        order.lock().unwrap().clear();
+        let response = answer("/outer");
+        assert_eq!(response.status_code(), 403);
+        assert_eq!(*order.lock().unwrap(), ["outer"]);
+    }
+}
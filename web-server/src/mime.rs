@@ -0,0 +1,135 @@
+use std::path::Path;
+
+/// The MIME type used when nothing better is known
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Get the `Content-Type` for a served file
+///
+/// The extension mapping is consulted first, then (if `sniff` is set) the leading
+/// bytes of the file, falling back to `application/octet-stream`
+///
+/// # Arguments
+///
+/// * `path` - The path of the file
+/// * `contents` - The contents of the file
+/// * `sniff` - Whether to inspect the contents when the extension is not recognized
+///
+pub fn content_type(path: &Path, contents: &[u8], sniff: bool) -> &'static str {
+    content_type_for_extension(path)
+        .or_else(|| {
+            if sniff {
+                sniff_content_type(contents)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(DEFAULT_CONTENT_TYPE)
+}
+
+/// Whether a `Content-Type` matches one of `patterns`
+///
+/// Parameters such as `charset` are ignored and `type/*` matches a whole type
+///
+pub fn matches_any(content_type: &str, patterns: &[String]) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_suffix("/*") {
+            Some(main_type) => media_type.split('/').next() == Some(main_type),
+            None => media_type == pattern,
+        }
+    })
+}
+
+/// Map a file extension to its MIME type
+pub fn content_type_for_extension(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    let content_type = match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "woff2" => "font/woff2",
+        _ => return None,
+    };
+    Some(content_type)
+}
+
+/// Detect common file formats from their magic numbers
+pub fn sniff_content_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.starts_with(b"%PDF-") {
+        Some("application/pdf")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR";
+
+    #[test]
+    fn test_content_type_for_extension() {
+        let content_type = |path| content_type_for_extension(Path::new(path));
+        assert_eq!(content_type("index.html"), Some("text/html; charset=utf-8"));
+        assert_eq!(content_type("index.css"), Some("text/css; charset=utf-8"));
+        assert_eq!(
+            content_type("index.js"),
+            Some("text/javascript; charset=utf-8")
+        );
+        assert_eq!(content_type("data.JSON"), Some("application/json"));
+        assert_eq!(content_type("logo.png"), Some("image/png"));
+        assert_eq!(content_type("logo.svg"), Some("image/svg+xml"));
+        assert_eq!(content_type("favicon.ico"), Some("image/x-icon"));
+        assert_eq!(content_type("font.woff2"), Some("font/woff2"));
+        assert_eq!(content_type("archive.xyz"), None);
+        assert_eq!(content_type("README"), None);
+        assert_eq!(
+            super::content_type(Path::new("archive.xyz"), b"", false),
+            DEFAULT_CONTENT_TYPE
+        );
+    }
+
+    #[test]
+    fn test_sniff_png() {
+        assert_eq!(sniff_content_type(PNG), Some("image/png"));
+    }
+
+    #[test]
+    fn test_sniff_pdf() {
+        assert_eq!(sniff_content_type(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(sniff_content_type(b"plain text"), None);
+    }
+
+    #[test]
+    fn test_sniffing_is_opt_in() {
+        let path = Path::new("pages/logo");
+        assert_eq!(content_type(path, PNG, false), DEFAULT_CONTENT_TYPE);
+        assert_eq!(content_type(path, PNG, true), "image/png");
+        assert_eq!(
+            content_type(Path::new("pages/index.css"), PNG, true),
+            "text/css; charset=utf-8"
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use log::{error, warn};
+
+use crate::{etag, request::Request, response::Response};
+
+/// What a `Range` header asks of a body of a given length
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    /// The bytes from the first offset to the second, both included
+    Satisfiable(u64, u64),
+    /// A valid range that starts past the end of the body
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header asking for a single range of bytes
+///
+/// `bytes=0-1023` is the first 1024 bytes, `bytes=1024-` everything from offset 1024
+/// and `bytes=-100` the last 100 bytes. An end past the body stops at its last byte.
+///
+/// Returns `None` for anything else, including several ranges at once, in which case
+/// the whole body is sent
+///
+pub fn parse(header: &str, length: u64) -> Option<ByteRange> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    let (start, end) = spec.trim().split_once('-')?;
+    let number = |value: &str| {
+        if value.bytes().all(|b| b.is_ascii_digit()) {
+            value.parse::<u64>().ok()
+        } else {
+            None
+        }
+    };
+
+    let (start, end) = match (start, end) {
+        ("", suffix) => {
+            let suffix = number(suffix)?;
+            if suffix == 0 {
+                return Some(ByteRange::Unsatisfiable);
+            }
+            (length.saturating_sub(suffix), length.checked_sub(1))
+        }
+        (start, "") => (number(start)?, length.checked_sub(1)),
+        (start, end) => {
+            let (start, end) = (number(start)?, number(end)?);
+            if end < start {
+                return None;
+            }
+            (start, Some(end.min(length.saturating_sub(1))))
+        }
+    };
+    match end {
+        Some(end) if start <= end && start < length => Some(ByteRange::Satisfiable(start, end)),
+        _ => Some(ByteRange::Unsatisfiable),
+    }
+}
+
+/// Answer a `GET` with a `Range` header with the part of a `200` response it asks for
+///
+/// With `If-Range`, the range is only sent if the response still has the given strong
+/// `ETag` or `Last-Modified` date, otherwise the whole response is
+///
+pub fn apply(request: &Request, response: Response) -> Response {
+    if request.method != "GET" || response.status_code() != 200 {
+        return response;
+    }
+    let header = match request.header("range") {
+        Some(header) => header,
+        None => return response,
+    };
+    if let Some(if_range) = request.header("if-range") {
+        let unchanged = if if_range.starts_with('"') {
+            response
+                .get_header("ETag")
+                .is_some_and(|etag| etag::strong_match(etag, if_range))
+        } else {
+            response.get_header("Last-Modified") == Some(if_range)
+        };
+        if !unchanged {
+            return response;
+        }
+    }
+
+    let length = response.content_length();
+    match parse(header, length) {
+        Some(ByteRange::Satisfiable(start, end)) => {
+            let content_range = format!("bytes {}-{}/{}", start, end, length);
+            match response.byte_range(start, end) {
+                Ok(response) => response
+                    .status(206, "PARTIAL CONTENT")
+                    .header("Content-Range", &content_range),
+                Err(e) => {
+                    error!("Failed to skip to the start of {:#?}: {:#?}", header, e);
+                    Response::new().status(500, "INTERNAL SERVER ERROR")
+                }
+            }
+        }
+        Some(ByteRange::Unsatisfiable) => {
+            warn!("Range {:#?} is outside of {} bytes", header, length);
+            Response::new()
+                .status(416, "RANGE NOT SATISFIABLE")
+                .header("Content-Range", &format!("bytes */{}", length))
+        }
+        None => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        use ByteRange::*;
+
+        assert_eq!(parse("bytes=0-1023", 2000), Some(Satisfiable(0, 1023)));
+        assert_eq!(parse("bytes=1000-", 2000), Some(Satisfiable(1000, 1999)));
+        assert_eq!(parse("bytes=-100", 2000), Some(Satisfiable(1900, 1999)));
+        assert_eq!(parse("bytes=-5000", 2000), Some(Satisfiable(0, 1999)));
+        assert_eq!(
+            parse("bytes=1500-5000", 2000),
+            Some(Satisfiable(1500, 1999))
+        );
+
+        assert_eq!(parse("bytes=2000-", 2000), Some(Unsatisfiable));
+        assert_eq!(parse("bytes=3000-4000", 2000), Some(Unsatisfiable));
+        assert_eq!(parse("bytes=-0", 2000), Some(Unsatisfiable));
+        assert_eq!(parse("bytes=0-", 0), Some(Unsatisfiable));
+
+        for header in [
+            "bytes=5-1",
+            "bytes=0-10, 20-30",
+            "bytes=a-b",
+            "bytes=+1-2",
+            "bytes=-",
+            "items=0-10",
+            "0-10",
+        ] {
+            assert_eq!(parse(header, 2000), None, "{:?}", header);
+        }
+    }
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET / HTTP/1.1\r\n{}\r\n", headers);
+        Request::parse_head(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn response() -> Response {
+        Response::new()
+            .header("ETag", "\"abc\"")
+            .body(b"0123456789".to_vec())
+    }
+
+    #[test]
+    fn test_apply() {
+        let partial = apply(&request("Range: bytes=2-5\r\n"), response());
+        assert_eq!(partial.status_code(), 206);
+        assert_eq!(partial.get_header("Content-Range"), Some("bytes 2-5/10"));
+        assert_eq!(partial.get_body(), Some(&b"2345"[..]));
+
+        let unsatisfiable = apply(&request("Range: bytes=10-\r\n"), response());
+        assert_eq!(unsatisfiable.status_code(), 416);
+        assert_eq!(
+            unsatisfiable.get_header("Content-Range"),
+            Some("bytes */10")
+        );
+
+        let full = apply(&request("Range: bytes=oops\r\n"), response());
+        assert_eq!(full.status_code(), 200);
+        assert_eq!(full.content_length(), 10);
+    }
+
+    #[test]
+    fn test_streamed_range() {
+        let streamed = Response::new().stream(Box::new(&b"0123456789"[..]), 10);
+        let partial = apply(&request("Range: bytes=-3\r\n"), streamed);
+        assert_eq!(partial.content_length(), 3);
+        let mut written = Vec::new();
+        partial.write_to(&mut written, true).unwrap();
+        assert!(written.ends_with(b"\r\n\r\n789"));
+    }
+
+    #[test]
+    fn test_if_range() {
+        let matching = request("Range: bytes=0-0\r\nIf-Range: \"abc\"\r\n");
+        assert_eq!(apply(&matching, response()).status_code(), 206);
+
+        let changed = request("Range: bytes=0-0\r\nIf-Range: \"def\"\r\n");
+        assert_eq!(apply(&changed, response()).status_code(), 200);
+
+        // Dates are only compared with `Last-Modified`, which this response lacks
+        let date = request("Range: bytes=0-0\r\nIf-Range: Sun, 06 Nov 1994 08:49:37 GMT\r\n");
+        assert_eq!(apply(&date, response()).status_code(), 200);
+    }
+}
@@ -0,0 +1,673 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead},
+    time::{Duration, Instant},
+};
+
+/// The Request struct
+///
+/// The Request struct holds a parsed HTTP request
+///
+/// # Fields
+///
+/// * `method` - The request method, e.g. `GET`
+/// * `path` - The path of the request target, without the query string, in the decoded
+///   form routes are looked up by once `normalize_path` ran
+/// * `raw_path` - The path exactly as it was sent, still percent-encoded, for anything
+///   echoed back to the client
+/// * `version` - The HTTP version, e.g. `HTTP/1.1`
+/// * `headers` - The request headers, keyed by lowercase name
+/// * `body` - The request body
+/// * `query_string` - The raw part of the target after `?`, if there was one, only
+///   parsed when `query_params` is called
+/// * `matrix_params` - The matrix parameters of each path segment, filled by `strip_matrix_params`
+/// * `deadline` - When the response must be sent by, from the timeout that applies to the route
+/// * `client_addr` - The address of the client, behind any trusted proxies, once known
+///
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub raw_path: String,
+    pub version: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+    pub query_string: Option<String>,
+    pub matrix_params: Vec<HashMap<String, String>>,
+    pub deadline: Option<Instant>,
+    pub client_addr: Option<String>,
+}
+
+/// The reasons a request could not be read
+#[derive(Debug)]
+pub enum ParseError {
+    /// The connection was closed before a request line arrived
+    Closed,
+    /// The request is not valid HTTP
+    Malformed(String),
+    /// The body is larger than the configured limit
+    TooLarge,
+    /// Reading from the connection failed, including read timeouts
+    Io(io::Error),
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> Self {
+        ParseError::Io(e)
+    }
+}
+
+impl Request {
+    /// Create an `HTTP/1.1` request with no headers or body, e.g. to render a page
+    /// without a client asking for it
+    pub fn new(method: &str, path: &str) -> Self {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            raw_path: path.to_string(),
+            version: String::from("HTTP/1.1"),
+            headers: HashMap::new(),
+            body: Vec::new(),
+            query_string: None,
+            matrix_params: Vec::new(),
+            deadline: None,
+            client_addr: None,
+        }
+    }
+
+    /// Parse the request line and headers
+    ///
+    /// The body is left unread so the caller can switch timeouts before calling `read_body`
+    ///
+    pub fn parse_head<R: BufRead>(reader: &mut R) -> Result<Request, ParseError> {
+        let request_line = read_line(reader)?.ok_or(ParseError::Closed)?;
+
+        let mut split_iter = request_line.split_whitespace();
+        let (method, target, version) =
+            match (split_iter.next(), split_iter.next(), split_iter.next()) {
+                (Some(method), Some(target), Some(version)) => (method, target, version),
+                _ => {
+                    return Err(ParseError::Malformed(format!(
+                        "Invalid request line: {:?}",
+                        request_line
+                    )))
+                }
+            };
+
+        let mut headers = HashMap::new();
+        loop {
+            let line = read_line(reader)?
+                .ok_or_else(|| ParseError::Malformed("Unexpected end of headers".to_string()))?;
+            if line.is_empty() {
+                break;
+            }
+
+            match line.split_once(':') {
+                Some((name, value)) => {
+                    let name = name.trim().to_ascii_lowercase();
+                    let value = value.trim().to_string();
+                    // Repeated lengths that disagree are another way to frame the
+                    // body two ways, identical ones are allowed
+                    if name == "content-length"
+                        && headers.get(&name).is_some_and(|length| *length != value)
+                    {
+                        return Err(ParseError::Malformed(
+                            "Conflicting Content-Length headers".to_string(),
+                        ));
+                    }
+                    headers.insert(name, value);
+                }
+                None => {
+                    return Err(ParseError::Malformed(format!("Invalid header: {:?}", line)));
+                }
+            }
+        }
+
+        // Either header could be taken to frame the body, and a proxy that picks
+        // the other one can be smuggled a second request
+        if headers.contains_key("transfer-encoding") && headers.contains_key("content-length") {
+            return Err(ParseError::Malformed(
+                "Both Content-Length and Transfer-Encoding are set".to_string(),
+            ));
+        }
+        // Without chunked last the body only ends when the connection does, which
+        // can't be told apart from a following request
+        if headers
+            .get("transfer-encoding")
+            .is_some_and(|value| !ends_chunked(value))
+        {
+            return Err(ParseError::Malformed(
+                "Transfer-Encoding must end with chunked".to_string(),
+            ));
+        }
+
+        // Routes are matched on the bare path
+        let (path, query_string) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (target, None),
+        };
+
+        Ok(Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            raw_path: path.to_string(),
+            version: version.to_string(),
+            headers,
+            body: Vec::new(),
+            query_string,
+            matrix_params: Vec::new(),
+            deadline: None,
+            client_addr: None,
+        })
+    }
+
+    /// Read the body, decoding it if it was sent with `Transfer-Encoding: chunked`
+    ///
+    /// Otherwise the body is announced by the `Content-Length` header, and a missing
+    /// `Content-Length` means there is no body. Bodies larger than `max_bytes` are
+    /// rejected with `TooLarge`, chunked ones as soon as the decoded total passes it.
+    ///
+    pub fn read_body<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        max_bytes: usize,
+    ) -> Result<(), ParseError> {
+        if self.is_chunked() {
+            self.body = read_chunked(reader, max_bytes)?;
+            return Ok(());
+        }
+
+        let length = match self.header("content-length") {
+            Some(value) => content_length(value).ok_or_else(|| {
+                ParseError::Malformed(format!("Invalid Content-Length: {:?}", value))
+            })?,
+            None => 0,
+        };
+        if length > max_bytes {
+            return Err(ParseError::TooLarge);
+        }
+
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        self.body = body;
+        Ok(())
+    }
+
+    /// Whether the body is sent in chunks, chunked must be the final transfer coding
+    fn is_chunked(&self) -> bool {
+        self.header("transfer-encoding").is_some_and(ends_chunked)
+    }
+
+    /// Get a header value by case-insensitive name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    /// Get the query string, the part of the target after `?`
+    pub fn query(&self) -> Option<&str> {
+        self.query_string.as_deref()
+    }
+
+    /// Get the URL-decoded query parameters
+    ///
+    /// A repeated key keeps its last value and a key without a value maps to an
+    /// empty string. The query string is parsed on every call, so it is never parsed
+    /// for requests refused for its length.
+    ///
+    pub fn query_params(&self) -> HashMap<String, String> {
+        self.query().map(parse_query).unwrap_or_default()
+    }
+
+    /// Rewrite the path into the form routes are keyed by
+    ///
+    /// Percent-escapes are decoded, so `/my%20page` is `/my page`, and trailing slashes
+    /// are dropped, so `/about/` is `/about`. The root `/` is left as it is.
+    /// `raw_path` keeps the path as it was sent.
+    ///
+    /// # Errors
+    ///
+    /// Paths that only mean something else once decoded are refused with `Malformed`:
+    /// an encoded `/` (`%2F`), which would make `/a%2Fb` the route `/a/b`, control
+    /// characters such as an encoded CR/LF, and `..` segments
+    ///
+    pub fn normalize_path(&mut self) -> Result<(), ParseError> {
+        if self.path.to_ascii_lowercase().contains("%2f") {
+            return Err(ParseError::Malformed(format!(
+                "Encoded slash in path: {:?}",
+                self.raw_path
+            )));
+        }
+        let decoded = percent_decode(&self.path);
+        if decoded.chars().any(char::is_control) || decoded.split('/').any(|s| s == "..") {
+            return Err(ParseError::Malformed(format!(
+                "Invalid path: {:?}",
+                self.raw_path
+            )));
+        }
+        let trimmed = decoded.trim_end_matches('/');
+        self.path = if trimmed.is_empty() {
+            String::from("/")
+        } else {
+            trimmed.to_string()
+        };
+        Ok(())
+    }
+
+    /// Remove a prefix of whole segments from the path, `/app/about` becomes `/about`
+    /// and `/app` becomes `/`
+    ///
+    /// Returns false, leaving the path as it is, if it isn't under the prefix
+    ///
+    pub fn strip_prefix(&mut self, prefix: &str) -> bool {
+        let prefix = prefix.trim_end_matches('/');
+        match self.path.strip_prefix(prefix) {
+            Some("") => self.path = String::from("/"),
+            Some(rest) if rest.starts_with('/') => self.path = rest.to_string(),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Strip matrix parameters (`/users;role=admin/42`) from the path
+    ///
+    /// The path is rewritten without them so it can be routed, and the parameters of
+    /// each segment are kept in `matrix_params` in segment order. A parameter without
+    /// a value maps to an empty string.
+    ///
+    pub fn strip_matrix_params(&mut self) {
+        let mut segments = Vec::new();
+        let mut matrix_params = Vec::new();
+
+        for segment in self.path.split('/').skip(1) {
+            let mut parts = segment.split(';');
+            segments.push(parts.next().unwrap_or(""));
+
+            let params = parts
+                .filter(|param| !param.is_empty())
+                .map(|param| match param.split_once('=') {
+                    Some((key, value)) => (key.to_string(), value.to_string()),
+                    None => (param.to_string(), String::new()),
+                })
+                .collect();
+            matrix_params.push(params);
+        }
+
+        self.path = format!("/{}", segments.join("/"));
+        self.matrix_params = matrix_params;
+    }
+
+    /// Get the time left before the deadline, zero once it has passed
+    ///
+    /// Handlers doing slow work can check this to avoid starting what can't finish
+    ///
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Whether the client wants the connection kept open after this request
+    ///
+    /// HTTP/1.1 connections are persistent unless the client sends `Connection: close`,
+    /// HTTP/1.0 connections only when the client sends `Connection: keep-alive`
+    ///
+    pub fn keep_alive(&self) -> bool {
+        if self.has_connection_option("close") {
+            false
+        } else if self.has_connection_option("keep-alive") {
+            true
+        } else {
+            self.version == "HTTP/1.1"
+        }
+    }
+
+    /// Whether the comma separated `Connection` header lists an option, e.g. `upgrade`
+    pub fn has_connection_option(&self, option: &str) -> bool {
+        self.header("connection").is_some_and(|value| {
+            value
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case(option))
+        })
+    }
+}
+
+/// Read a single CRLF (or LF) terminated line, returning `None` at end of stream
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// Whether the final coding of a `Transfer-Encoding` value is `chunked`
+fn ends_chunked(transfer_encoding: &str) -> bool {
+    transfer_encoding
+        .rsplit(',')
+        .next()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Parse a `Content-Length` value, which must be all digits
+///
+/// A list of the same length repeated, e.g. `5, 5`, is accepted as that length,
+/// as it is what some proxies make of duplicated headers
+///
+fn content_length(value: &str) -> Option<usize> {
+    let mut lengths = value.split(',').map(|length| {
+        let length = length.trim();
+        if length.is_empty() || !length.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None;
+        }
+        length.parse::<usize>().ok()
+    });
+    let first = lengths.next()??;
+    lengths.all(|length| length == Some(first)).then_some(first)
+}
+
+/// Decode a chunked body, giving up once it grows past `max_bytes`
+///
+/// Chunk extensions and trailers are read and discarded
+///
+fn read_chunked<R: BufRead>(reader: &mut R, max_bytes: usize) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+
+    loop {
+        let line = read_line(reader)?
+            .ok_or_else(|| ParseError::Malformed("Unexpected end of chunked body".to_string()))?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| ParseError::Malformed(format!("Invalid chunk size: {:?}", line)))?;
+
+        if size == 0 {
+            while !read_line(reader)?.unwrap_or_default().is_empty() {}
+            return Ok(body);
+        }
+        if size > max_bytes - body.len() {
+            return Err(ParseError::TooLarge);
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+
+        if read_line(reader)?.as_deref() != Some("") {
+            return Err(ParseError::Malformed(
+                "Chunk is longer than its size".to_string(),
+            ));
+        }
+    }
+}
+
+/// Parse `key=value` pairs separated by `&`, URL-decoding both sides
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (url_decode(key), url_decode(value)),
+            None => (url_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+/// Decode a query string key or value, where `+` is a space
+fn url_decode(text: &str) -> String {
+    percent_decode(&text.replace('+', " "))
+}
+
+/// Decode `%XX` escapes, leaving malformed escapes as they are
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Request {
+        let mut reader = raw.as_bytes();
+        let mut request = Request::parse_head(&mut reader).unwrap();
+        request.read_body(&mut reader, 1024).unwrap();
+        request
+    }
+
+    #[test]
+    fn test_parse_headers_and_body() {
+        let raw = "POST /submit HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+        let request = parse(raw);
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/submit");
+        assert_eq!(request.header("HOST"), Some("localhost"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn test_content_length() {
+        // No Content-Length means no body, whatever follows is the next request
+        let mut reader = "POST / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n".as_bytes();
+        let mut request = Request::parse_head(&mut reader).unwrap();
+        request.read_body(&mut reader, 1024).unwrap();
+        assert!(request.body.is_empty());
+        assert_eq!(Request::parse_head(&mut reader).unwrap().method, "GET");
+
+        assert_eq!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 2, 2\r\n\r\nhi").body,
+            b"hi"
+        );
+        for value in ["abc", "-1", "+2", "2, 3", "", "1 1"] {
+            let raw = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nhi", value);
+            let mut reader = raw.as_bytes();
+            let mut request = Request::parse_head(&mut reader).unwrap();
+            assert!(
+                matches!(
+                    request.read_body(&mut reader, 1024),
+                    Err(ParseError::Malformed(_))
+                ),
+                "{:?}",
+                value
+            );
+        }
+
+        // A body cut short by the client closing the connection
+        let mut reader = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhi".as_bytes();
+        let mut request = Request::parse_head(&mut reader).unwrap();
+        assert!(matches!(
+            request.read_body(&mut reader, 1024),
+            Err(ParseError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn test_query() {
+        assert_eq!(
+            parse("GET /search?q=rust&page=2 HTTP/1.1\r\n\r\n").query(),
+            Some("q=rust&page=2")
+        );
+        assert_eq!(parse("GET /search? HTTP/1.1\r\n\r\n").query(), Some(""));
+        assert_eq!(parse("GET /search HTTP/1.1\r\n\r\n").query(), None);
+    }
+
+    #[test]
+    fn test_query_params() {
+        let request = parse("GET /search?q=rust+lang&page=2&page=3&debug HTTP/1.1\r\n\r\n");
+        assert_eq!(request.path, "/search");
+        let params = request.query_params();
+        assert_eq!(params.len(), 3);
+        assert_eq!(params["q"], "rust lang");
+        assert_eq!(params["page"], "3");
+        assert_eq!(params["debug"], "");
+
+        let request = parse("GET /search? HTTP/1.1\r\n\r\n");
+        assert_eq!(request.path, "/search");
+        assert!(request.query_params().is_empty());
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        let normalized = |path: &str| {
+            let mut request = parse(&format!("GET {} HTTP/1.1\r\n\r\n", path));
+            request.normalize_path().unwrap();
+            request.path
+        };
+        assert_eq!(normalized("/about/"), "/about");
+        assert_eq!(normalized("/about"), "/about");
+        assert_eq!(normalized("/contact/page//"), "/contact/page");
+        assert_eq!(normalized("/"), "/");
+        assert_eq!(normalized("//"), "/");
+        assert_eq!(normalized("/my%20page"), "/my page");
+        assert_eq!(normalized("/a+b%C3%A9/"), "/a+bé");
+
+        for path in [
+            "/a%2Fb",
+            "/a%2fb",
+            "/x%0d%0aSet-Cookie:%20a=1",
+            "/%00",
+            "/a/%2e%2e/b",
+        ] {
+            let mut request = parse(&format!("GET {} HTTP/1.1\r\n\r\n", path));
+            assert!(
+                matches!(request.normalize_path(), Err(ParseError::Malformed(_))),
+                "{:?}",
+                path
+            );
+            assert_eq!(request.raw_path, path);
+        }
+
+        // What was sent is kept for echoing back
+        let mut request = parse("GET /my%20page/ HTTP/1.1\r\n\r\n");
+        request.normalize_path().unwrap();
+        assert_eq!(request.raw_path, "/my%20page/");
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        let request = |path: &str| parse(&format!("GET {} HTTP/1.1\r\n\r\n", path));
+
+        for (path, prefix, stripped) in [
+            ("/app/about", "/app", "/about"),
+            ("/app/docs/intro", "/app/", "/docs/intro"),
+            ("/app", "/app", "/"),
+            ("/about", "/", "/about"),
+        ] {
+            let mut request = request(path);
+            assert!(request.strip_prefix(prefix));
+            assert_eq!(request.path, stripped);
+        }
+
+        for (path, prefix) in [("/application", "/app"), ("/about", "/app")] {
+            let mut request = request(path);
+            assert!(!request.strip_prefix(prefix));
+            assert_eq!(request.path, path);
+        }
+    }
+
+    #[test]
+    fn test_url_decode() {
+        assert_eq!(url_decode("caf%C3%A9%20au%2Blait"), "café au+lait");
+        assert_eq!(url_decode("100%"), "100%");
+        assert_eq!(url_decode("%zz%4"), "%zz%4");
+    }
+
+    #[test]
+    fn test_strip_matrix_params() {
+        let mut request = parse("GET /users;role=admin;active/42 HTTP/1.1\r\n\r\n");
+        request.strip_matrix_params();
+
+        assert_eq!(request.path, "/users/42");
+        assert_eq!(request.matrix_params.len(), 2);
+        assert_eq!(request.matrix_params[0]["role"], "admin");
+        assert_eq!(request.matrix_params[0]["active"], "");
+        assert!(request.matrix_params[1].is_empty());
+    }
+
+    #[test]
+    fn test_time_remaining() {
+        let mut request = parse("GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(request.time_remaining(), None);
+
+        request.deadline = Some(Instant::now() + Duration::from_secs(1));
+        let first = request.time_remaining().unwrap();
+        assert!(first <= Duration::from_secs(1));
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(request.time_remaining().unwrap() < first);
+
+        request.deadline = Some(Instant::now() - Duration::from_millis(1));
+        assert_eq!(request.time_remaining(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let request = parse("GET / HTTP/1.1\r\n\r\n");
+        assert!(request.keep_alive());
+
+        let request = parse("GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(!request.keep_alive());
+
+        let request = parse("GET / HTTP/1.0\r\n\r\n");
+        assert!(!request.keep_alive());
+    }
+
+    #[test]
+    fn test_connection_options() {
+        let request = parse("GET / HTTP/1.0\r\nConnection: Keep-Alive, Upgrade\r\n\r\n");
+        assert!(request.keep_alive());
+        assert!(request.has_connection_option("upgrade"));
+
+        let request = parse("GET / HTTP/1.1\r\nConnection: foo,close\r\n\r\n");
+        assert!(!request.keep_alive());
+        assert!(!request.has_connection_option("upgrade"));
+
+        let request = parse("GET / HTTP/1.1\r\nConnection: closed, keep-alive-ish\r\n\r\n");
+        assert!(request.keep_alive());
+        assert!(!request.has_connection_option("close"));
+    }
+
+    #[test]
+    fn test_read_chunked_body() {
+        let request = parse(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: yes\r\n\r\n",
+        );
+        assert_eq!(request.body, b"hello world");
+    }
+
+    #[test]
+    fn test_chunked_body_too_large() {
+        let mut raw = String::from("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+        for _ in 0..10 {
+            raw.push_str("a\r\n0123456789\r\n");
+        }
+        raw.push_str("0\r\n\r\n");
+
+        let mut reader = raw.as_bytes();
+        let mut request = Request::parse_head(&mut reader).unwrap();
+        assert!(matches!(
+            request.read_body(&mut reader, 95),
+            Err(ParseError::TooLarge)
+        ));
+        assert!(request.body.is_empty());
+    }
+}
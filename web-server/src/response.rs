@@ -0,0 +1,346 @@
+use std::{
+    io::{self, Read, Write},
+    time::SystemTime,
+};
+
+use log::error;
+
+use crate::date::http_date;
+
+/// The Response struct
+///
+/// The Response struct builds an HTTP response and serializes it for the wire
+///
+/// # Fields
+///
+/// * `status` - The status code
+/// * `reason` - The reason phrase sent after the status code
+/// * `headers` - The headers in the order they were added
+/// * `body` - The response body
+///
+pub struct Response {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+/// The body of a response
+enum Body {
+    Bytes(Vec<u8>),
+    /// A body copied from `reader` while writing, which must yield `length` bytes
+    Stream {
+        reader: Box<dyn Read + Send + Sync>,
+        length: u64,
+    },
+}
+
+/// The reasons writing a response can fail
+#[derive(Debug)]
+pub enum WriteError {
+    /// The connection could not be written to
+    Write(io::Error),
+    /// A streamed body failed after the headers were sent
+    Body(io::Error),
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Response {
+    /// Create a new `200 OK` response with only a `Date` header and an empty body
+    pub fn new() -> Self {
+        Response {
+            status: 200,
+            reason: String::from("OK"),
+            headers: vec![(String::from("Date"), http_date(SystemTime::now()))],
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    /// Set the status code and reason phrase
+    pub fn status(mut self, code: u16, reason: &str) -> Self {
+        self.status = code;
+        self.reason = reason.to_string();
+        self
+    }
+
+    /// Set a header, replacing any existing header with the same (case-insensitive) name
+    ///
+    /// A name or value with a CR or LF in it would end the header early and let the
+    /// rest be read as headers of its own, so it is left out with an error
+    ///
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        if [name, value].iter().any(|text| text.contains(['\r', '\n'])) {
+            error!("Refusing to send header {:?} with a line break in it", name);
+            return self;
+        }
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set the body
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = Body::Bytes(body);
+        self
+    }
+
+    /// Stream the body from `reader` while writing instead of holding it in memory
+    ///
+    /// `length` is sent as the `Content-Length`, so the reader must yield that many bytes
+    ///
+    pub fn stream(mut self, reader: Box<dyn Read + Send + Sync>, length: u64) -> Self {
+        self.body = Body::Stream { reader, length };
+        self
+    }
+
+    /// Keep only the bytes from `start` to `end` of the body, both included
+    ///
+    /// A streamed body is read up to `start` here and the rest streamed as before
+    ///
+    /// # Panics
+    ///
+    /// The `byte_range` function will panic if `end` is not within the body
+    ///
+    pub fn byte_range(mut self, start: u64, end: u64) -> io::Result<Self> {
+        assert!(start <= end && end < self.content_length());
+        self.body = match self.body {
+            Body::Bytes(bytes) => Body::Bytes(bytes[start as usize..=end as usize].to_vec()),
+            Body::Stream { mut reader, .. } => {
+                io::copy(&mut (&mut reader).take(start), &mut io::sink())?;
+                Body::Stream {
+                    reader: Box::new(reader.take(end - start + 1)),
+                    length: end - start + 1,
+                }
+            }
+        };
+        Ok(self)
+    }
+
+    /// Get the status code
+    pub fn status_code(&self) -> u16 {
+        self.status
+    }
+
+    /// Get the reason phrase
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// Get a header value by case-insensitive name
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Get the body, or `None` if it is streamed
+    pub fn get_body(&self) -> Option<&[u8]> {
+        match &self.body {
+            Body::Bytes(bytes) => Some(bytes),
+            Body::Stream { .. } => None,
+        }
+    }
+
+    /// Get the length of the body
+    pub fn content_length(&self) -> u64 {
+        match &self.body {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::Stream { length, .. } => *length,
+        }
+    }
+
+    /// Copy the response, or `None` if the body is streamed
+    pub fn try_clone(&self) -> Option<Response> {
+        Some(Response {
+            status: self.status,
+            reason: self.reason.clone(),
+            headers: self.headers.clone(),
+            body: Body::Bytes(self.get_body()?.to_vec()),
+        })
+    }
+
+    /// Serialize the response
+    ///
+    /// `Content-Length` is derived from the body, and left out for informational,
+    /// `204` and `304` responses which never carry one. A streamed body is not
+    /// included, use `write_to` to send it.
+    ///
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.to_head_bytes();
+        if let Some(body) = self.get_body() {
+            bytes.extend_from_slice(body);
+        }
+        bytes
+    }
+
+    /// Serialize only the status line and headers, as sent in answer to `HEAD`
+    ///
+    /// `Content-Length` still describes the body that a `GET` would have received
+    ///
+    pub fn to_head_bytes(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if self.status >= 200 && self.status != 204 && self.status != 304 {
+            head.push_str(&format!("Content-Length: {}\r\n", self.content_length()));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+
+    /// Write the response, streaming the body if needed
+    ///
+    /// A streamed body is written a chunk at a time and abandoned at the first failed
+    /// write, so a client that disconnects doesn't keep the rest being read
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Where to write the response
+    /// * `include_body` - Whether to send the body, false when answering `HEAD`
+    ///
+    pub fn write_to<W: Write>(self, writer: &mut W, include_body: bool) -> Result<(), WriteError> {
+        let head = self.to_head_bytes();
+        match self.body {
+            Body::Bytes(mut bytes) if include_body => {
+                let mut response = head;
+                response.append(&mut bytes);
+                writer.write_all(&response).map_err(WriteError::Write)
+            }
+            Body::Stream { reader, length } if include_body => {
+                writer.write_all(&head).map_err(WriteError::Write)?;
+                copy_body(reader.take(length), writer, length)
+            }
+            _ => writer.write_all(&head).map_err(WriteError::Write),
+        }
+    }
+}
+
+/// Copy exactly `length` bytes of a streamed body
+fn copy_body<R: Read, W: Write>(
+    mut reader: R,
+    writer: &mut W,
+    length: u64,
+) -> Result<(), WriteError> {
+    let mut buffer = [0; 8192];
+    let mut copied = 0;
+
+    while copied < length {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => {
+                return Err(WriteError::Body(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("Body ended after {} of {} bytes", copied, length),
+                )))
+            }
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(WriteError::Body(e)),
+        };
+
+        writer
+            .write_all(&buffer[..read])
+            .map_err(WriteError::Write)?;
+        copied += read as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    #[test]
+    fn test_to_bytes() {
+        let ok = Response::new()
+            .header("Date", DATE)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(b"<h1>Hi</h1>".to_vec());
+        assert_eq!(
+            ok.to_bytes(),
+            b"HTTP/1.1 200 OK\r\n\
+              Date: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+              Content-Type: text/html; charset=utf-8\r\n\
+              Content-Length: 11\r\n\
+              \r\n\
+              <h1>Hi</h1>"
+        );
+
+        let not_found = Response::new()
+            .status(404, "NOT FOUND")
+            .header("Date", DATE);
+        assert_eq!(
+            not_found.to_bytes(),
+            b"HTTP/1.1 404 NOT FOUND\r\n\
+              Date: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+              Content-Length: 0\r\n\
+              \r\n"
+        );
+    }
+
+    #[test]
+    fn test_no_content_length() {
+        for (code, reason) in [
+            (103, "EARLY HINTS"),
+            (204, "NO CONTENT"),
+            (304, "NOT MODIFIED"),
+        ] {
+            let head = Response::new().status(code, reason).to_head_bytes();
+            let head = String::from_utf8(head).unwrap();
+            assert!(!head.contains("Content-Length"), "{}", head);
+        }
+    }
+
+    #[test]
+    fn test_header_replaced() {
+        let response = Response::new()
+            .header("X-Count", "1")
+            .header("x-count", "2");
+        assert_eq!(response.get_header("X-COUNT"), Some("2"));
+        let head = String::from_utf8(response.to_head_bytes()).unwrap();
+        assert_eq!(head.to_ascii_lowercase().matches("x-count").count(), 1);
+    }
+
+    #[test]
+    fn test_header_line_break_refused() {
+        let response = Response::new()
+            .header("Location", "/x")
+            .header("Location", "/x\r\nSet-Cookie: evil=1")
+            .header("X-Bad\n", "1");
+        assert_eq!(response.get_header("Location"), Some("/x"));
+        let head = String::from_utf8(response.to_head_bytes()).unwrap();
+        assert!(!head.contains("Set-Cookie") && !head.contains("X-Bad"));
+    }
+
+    #[test]
+    fn test_write_streamed() {
+        let response = Response::new()
+            .header("Date", DATE)
+            .stream(Box::new(&b"streamed"[..]), 8);
+        let mut written = Vec::new();
+        response.write_to(&mut written, true).unwrap();
+        assert_eq!(
+            written,
+            b"HTTP/1.1 200 OK\r\n\
+              Date: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+              Content-Length: 8\r\n\
+              \r\n\
+              streamed"
+        );
+
+        // A body shorter than announced can't be completed
+        let short = Response::new().stream(Box::new(&b"short"[..]), 8);
+        assert!(matches!(
+            short.write_to(&mut Vec::new(), true),
+            Err(WriteError::Body(_))
+        ));
+    }
+}
@@ -1,152 +1,1087 @@
-use log::{debug, error, info};
-use std::{collections::HashMap, env, path::Path};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    env, fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{self, Path, PathBuf},
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    method::Method,
+    middleware::{Middleware, Next},
+    request::Request,
+    response::Response,
+};
+
+/// A dynamic route handler, building the response for a request
+pub type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync>;
+
+/// The methods the files in the pages directory are served for, as if each had a
+/// `GET` route, with `HEAD` answered from it
+pub const FILE_METHODS: [Method; 2] = [Method::Get, Method::Head];
+
+/// The page sent with `404` responses, when the pages directory has one
+const NOT_FOUND_PAGE: &str = "404.html";
+
+/// How many hex digits of the content hash go into a fingerprinted route
+const FINGERPRINT_LEN: usize = 10;
+
+/// A step in resolving a request path to a file, see `RouteTable::resolve`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    Exact,
+    HtmlSibling,
+    DirectoryIndex,
+}
+
+/// How many directory entries are read when building the routes, see `Router::read_path`
+///
+/// # Fields
+///
+/// * `per_dir` - The most entries read from one directory, `None` is unlimited
+/// * `total` - The most entries read from the whole pages directory, `None` is unlimited
+///
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanLimit {
+    pub per_dir: Option<usize>,
+    pub total: Option<usize>,
+}
+
+/// The RouteTable struct
+///
+/// The RouteTable struct is a snapshot of the files in the pages directory, it is
+/// replaced as a whole when the routes are reloaded
+///
+/// # Fields
+///
+/// * `routes` - A HashMap of the files in the pages directory, by their path under it
+/// * `precompressed` - The content codings with a sibling file (`.br`, `.gz`), by file path
+/// * `partial` - Whether reading the pages directory stopped at the scan limit
+/// * `generation` - How many times the routes had been reloaded when this table was built
+/// * `fingerprints` - The fingerprinted route of each asset, `None` unless enabled
+/// * `fingerprinted` - The fingerprinted routes, which are added to `routes` too
+/// * `outside` - The files that resolve to somewhere outside the pages directory, see
+///   `is_under_root`
+/// * `directories` - The directories with routes under them, without a trailing slash,
+///   see `is_directory`
+pub struct RouteTable {
+    routes: HashMap<String, String>,
+    precompressed: HashMap<String, Vec<&'static str>>,
+    partial: bool,
+    generation: u64,
+    fingerprints: Option<HashMap<String, String>>,
+    fingerprinted: HashSet<String>,
+    outside: HashSet<String>,
+    directories: HashSet<String>,
+}
+
+impl RouteTable {
+    /// Get the routes
+    pub fn get_routes(&self) -> &HashMap<String, String> {
+        &self.routes
+    }
+
+    /// Whether some files were left out because the scan limit was reached
+    pub fn is_partial(&self) -> bool {
+        self.partial
+    }
+
+    /// Get the number of reloads before this table, it goes up with every reload
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Find the file in the pages directory that serves a request path
+    ///
+    /// The steps of `order` are tried in turn and the first file found wins:
+    ///
+    /// * `Exact` - The file at the path, e.g. `/index.css` is `pages/index.css`
+    /// * `HtmlSibling` - The path with `.html` added, e.g. `/about` is `pages/about.html`
+    /// * `DirectoryIndex` - The `index.html` of the directory at the path, e.g. both
+    ///   `/contact` and `/contact/` are `pages/contact/index.html`
+    ///
+    pub fn resolve(&self, path: &str, order: &[Resolution]) -> Option<&String> {
+        order
+            .iter()
+            .find_map(|step| self.routes.get(&candidate(path, *step)?))
+    }
+
+    /// Get the request paths that `resolve` to a route's file, the reverse of `resolve`
+    ///
+    /// e.g. with every step, `/docs/index.html` is served for `/docs/index.html`,
+    /// `/docs/index` and `/docs`. Paths that resolve to another file first are left
+    /// out, and so are ones with a trailing slash other than `/`, which
+    /// `Request::normalize_path` trims before routing.
+    ///
+    pub fn request_paths(&self, route: &str, order: &[Resolution]) -> Vec<String> {
+        let file = match self.routes.get(route) {
+            Some(file) => file,
+            None => return Vec::new(),
+        };
+        let mut paths = Vec::new();
+        for step in order {
+            match step {
+                Resolution::Exact => paths.push(route.to_string()),
+                Resolution::HtmlSibling => {
+                    if let Some(path) = route.strip_suffix(".html") {
+                        paths.push(path.to_string());
+                    }
+                }
+                Resolution::DirectoryIndex => {
+                    if let Some(dir) = route.strip_suffix("/index.html") {
+                        paths.push(match dir {
+                            "" => String::from("/"),
+                            dir => dir.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+        paths.dedup();
+        paths.retain(|path| self.resolve(path, order) == Some(file));
+        paths
+    }
+
+    /// Find the file serving a request path in the first of `locales` that has one
+    ///
+    /// A locale's variant of a file has the locale before its extension, e.g. `/about`
+    /// in `fr` is `pages/about.fr.html`. Every step of `order` is tried for a locale
+    /// before moving on to the next one.
+    ///
+    /// # Returns
+    ///
+    /// The file and the locale it was found in
+    ///
+    pub fn resolve_localized<'t, 'l>(
+        &'t self,
+        path: &str,
+        order: &[Resolution],
+        locales: &'l [String],
+    ) -> Option<(&'t String, &'l str)> {
+        locales.iter().find_map(|locale| {
+            order.iter().find_map(|step| {
+                let file = localized(&candidate(path, *step)?, locale);
+                Some((self.routes.get(&file)?, locale.as_str()))
+            })
+        })
+    }
+
+    /// Whether a request path is a directory of the pages directory with files in it
+    ///
+    /// e.g. `/docs` and `/docs/` both are if there is a route under `/docs/`
+    ///
+    pub fn is_directory(&self, path: &str) -> bool {
+        self.directories.contains(path.trim_end_matches('/'))
+    }
+
+    /// Get the fingerprinted route of an asset, for linking to it from pages
+    ///
+    /// e.g. `/app.js` might be `/app.0123456789.js`, which changes whenever the
+    /// contents of `app.js` do. Returns `None` if fingerprinting is disabled or the
+    /// route isn't an asset.
+    ///
+    pub fn fingerprinted(&self, route: &str) -> Option<&str> {
+        self.fingerprints.as_ref()?.get(route).map(String::as_str)
+    }
+
+    /// Whether a route is the fingerprinted route of an asset, whose contents never
+    /// change for as long as it exists
+    pub fn is_fingerprinted(&self, route: &str) -> bool {
+        self.fingerprinted.contains(route)
+    }
+
+    /// Add a route for every asset with a hash of its contents before the extension
+    ///
+    /// HTML pages and files without an extension are left out, they are visited by
+    /// their own URL rather than linked to from other pages
+    ///
+    fn add_fingerprints(&mut self) {
+        let mut fingerprints = HashMap::new();
+        for (route, file) in &self.routes {
+            let extension = Path::new(route).extension().and_then(|ext| ext.to_str());
+            if extension.is_none_or(|ext| ext == "html") {
+                continue;
+            }
+            let contents = match fs::read(file) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    error!("Failed to fingerprint {:#?}: {:#?}", file, e);
+                    continue;
+                }
+            };
+            let hash: String = Sha1::digest(&contents)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            fingerprints.insert(route.clone(), localized(route, &hash[..FINGERPRINT_LEN]));
+        }
+
+        debug!("Fingerprinted routes: {:#?}", fingerprints);
+        for (route, fingerprinted) in &fingerprints {
+            let file = self.routes[route].clone();
+            self.routes.insert(fingerprinted.clone(), file);
+            self.fingerprinted.insert(fingerprinted.clone());
+        }
+        self.fingerprints = Some(fingerprints);
+    }
+
+    /// Whether a file of the routes really is in the pages directory, once symlinks are
+    /// resolved
+    ///
+    /// Files are resolved once when the routes are built, one that couldn't be, e.g. a
+    /// broken symlink, is not
+    ///
+    pub fn is_under_root(&self, file: &str) -> bool {
+        !self.outside.contains(file)
+    }
+
+    /// Find the sibling of a file precompressed in a content coding, `br` or `gzip`
+    ///
+    /// Which coding to serve is decided by `compression::choose`
+    ///
+    /// # Returns
+    ///
+    /// The path of the sibling, or `None` if the file has none in that coding
+    ///
+    pub fn precompressed_sibling(&self, file: &str, encoding: &str) -> Option<String> {
+        let encodings = self.precompressed.get(file)?;
+        PRECOMPRESSED
+            .iter()
+            .find(|(sibling, _)| *sibling == encoding && encodings.contains(sibling))
+            .map(|(_, extension)| format!("{}.{}", file, extension))
+    }
+}
 
 /// The Router struct
-/// 
+///
 /// The Router struct is responsible for initializing and storing the routes of the server
-/// 
+///
 /// # Fields
-/// 
-/// * `routes` - A HashMap of the routes
+///
+/// * `root` - The pages directory
+/// * `scan_limit` - How many entries of the pages directory are read
+/// * `table` - The current routes, requests keep the snapshot they started with
+/// * `handlers` - The dynamic handlers, by method and path
+/// * `not_found` - The handler for requests no route matches
+/// * `not_found_page` - The contents of `404.html` in the pages directory, read once
+///   when the Router is created
+/// * `middleware` - The layers every answered request goes through, outermost first
 pub struct Router {
-    routes: HashMap<String, String>,
+    root: PathBuf,
+    scan_limit: ScanLimit,
+    table: Arc<RwLock<Arc<RouteTable>>>,
+    handlers: HashMap<(Method, String), Handler>,
+    not_found: Option<Handler>,
+    not_found_page: Option<Vec<u8>>,
+    middleware: Vec<Middleware>,
 }
 
 impl Router {
     /// Create a new Router
     ///
-    /// The Router will initialize the routes from the pages directory
+    /// The Router will initialize the routes from the pages directory, reading at
+    /// most `scan_limit` of its entries
     ///
     /// # Returns
     ///
     /// A new Router instance
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// The `new` function will panic if the current directory cannot be found
-    /// or if the pages directory cannot be found
+    /// The `new` function will return an error if the current directory cannot be
+    /// found or if the pages directory cannot be read
     ///
-    pub fn new() -> Self {
-        let routes = Self::init_routes();
-        Router { routes }
+    pub fn new(scan_limit: ScanLimit) -> io::Result<Self> {
+        let current_dir = env::current_dir()?;
+        Self::with_root(current_dir.join("pages"), scan_limit)
     }
 
-    /// Get the routes
-    pub fn get_routes(&self) -> &HashMap<String, String> {
-        &self.routes
+    /// Create a new Router like `new`, that also reloads its routes when the pages
+    /// directory changes, checking every `interval`, see `watch`
+    ///
+    /// # Errors
+    ///
+    /// The `new_watching` function will return an error if the current directory
+    /// cannot be found or if the pages directory cannot be read
+    ///
+    pub fn new_watching(scan_limit: ScanLimit, interval: Duration) -> io::Result<Self> {
+        let router = Self::new(scan_limit)?;
+        router.watch(interval);
+        Ok(router)
+    }
+
+    /// Create a new Router serving the pages in `root`
+    ///
+    /// A relative `root` is resolved against the current directory once, here, so
+    /// the routes keep working if the current directory changes afterwards. Once
+    /// `scan_limit` is reached the rest of `root` is skipped with a warning, so a huge
+    /// directory can't hold up startup.
+    ///
+    /// # Errors
+    ///
+    /// The `with_root` function will return an error if the current directory cannot
+    /// be found or if `root`, or a directory in it, cannot be read
+    ///
+    pub fn with_root(root: PathBuf, scan_limit: ScanLimit) -> io::Result<Self> {
+        let root = path::absolute(root)?;
+        let table = Self::init_routes(&root, scan_limit)?;
+        let not_found_page = match fs::read(root.join(NOT_FOUND_PAGE)) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                error!("Failed to read {}: {:#?}", NOT_FOUND_PAGE, e);
+                None
+            }
+        };
+        Ok(Router {
+            root,
+            scan_limit,
+            table: Arc::new(RwLock::new(Arc::new(table))),
+            handlers: HashMap::new(),
+            not_found: None,
+            not_found_page,
+            middleware: Vec::new(),
+        })
+    }
+
+    /// Check the pages directory for changes every `interval` on a background thread,
+    /// reloading the routes when files were added, removed or modified
+    ///
+    /// The thread stops once the Router is dropped
+    ///
+    pub fn watch(&self, interval: Duration) {
+        let table = Arc::downgrade(&self.table);
+        let root = self.root.clone();
+        let scan_limit = self.scan_limit;
+        let mut last = fingerprint(&root);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let table = match table.upgrade() {
+                Some(table) => table,
+                None => return,
+            };
+            let current = fingerprint(&root);
+            if current != last && !root.is_dir() {
+                warn!(
+                    "Pages directory {:#?} is gone, keeping the old routes",
+                    root
+                );
+            } else if current != last {
+                info!("Pages changed, reloading routes");
+                match Self::init_routes(&root, scan_limit) {
+                    Ok(routes) => {
+                        swap_in(&table, routes);
+                        last = current;
+                    }
+                    // Tried again on the next check
+                    Err(e) => error!("Failed to reload routes, keeping the old ones: {:#?}", e),
+                }
+            }
+        });
+    }
+
+    /// Get the absolute path of the pages directory
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Get the current routes
+    ///
+    /// The snapshot stays the same for as long as it is held, even if the routes are
+    /// reloaded meanwhile
+    ///
+    pub fn table(&self) -> Arc<RouteTable> {
+        Arc::clone(&self.table.read().unwrap())
+    }
+
+    /// Rebuild the routes from the pages directory
+    ///
+    /// The new routes are built before taking the lock, which is only held to swap
+    /// them in, so requests never wait for the rebuild or see a partial table. If the
+    /// pages directory can't be read the old routes are kept.
+    ///
+    // The server reloads through `watch`, only tests reload whenever they need to
+    #[cfg(test)]
+    pub fn reload(&self) -> io::Result<()> {
+        swap_in(&self.table, Self::init_routes(&self.root, self.scan_limit)?);
+        Ok(())
+    }
+
+    /// Serve every asset under a fingerprinted route as well, see
+    /// `RouteTable::fingerprinted`
+    ///
+    /// Every asset is read to hash it, here and whenever the routes are reloaded
+    ///
+    pub fn fingerprint_assets(&self) -> io::Result<()> {
+        let mut table = Self::init_routes(&self.root, self.scan_limit)?;
+        table.add_fingerprints();
+        swap_in(&self.table, table);
+        Ok(())
+    }
+
+    /// Get the fingerprinted route of an asset in the current routes, see
+    /// `RouteTable::fingerprinted`
+    pub fn fingerprinted(&self, route: &str) -> Option<String> {
+        self.table().fingerprinted(route).map(String::from)
+    }
+
+    /// Register a dynamic handler for `method` requests on `path`
+    ///
+    /// Handlers take precedence over files in the pages directory
+    ///
+    pub fn add_handler(&mut self, method: Method, path: &str, handler: Handler) {
+        self.handlers.insert((method, path.to_string()), handler);
+    }
+
+    /// Register a `GET` handler on `path` that only builds the body of a `200 OK`
+    pub fn add_route<F>(&mut self, path: &str, body: F)
+    where
+        F: Fn(&Request) -> Vec<u8> + Send + Sync + 'static,
+    {
+        self.add_handler(
+            Method::Get,
+            path,
+            Box::new(move |request| Response::new().body(body(request))),
+        );
+    }
+
+    /// Find the handler for a `method` request on `path`
+    ///
+    /// When `head_from_get` is set, a `HEAD` request on a path without its own `HEAD`
+    /// handler is given the `GET` handler, whose body the caller must not send
+    ///
+    pub fn find_handler(
+        &self,
+        method: &Method,
+        path: &str,
+        head_from_get: bool,
+    ) -> Option<&Handler> {
+        let handler = |method: Method| self.handlers.get(&(method, path.to_string()));
+        match handler(method.clone()) {
+            Some(handler) => Some(handler),
+            None if *method == Method::Head && head_from_get => handler(Method::Get),
+            None => None,
+        }
+    }
+
+    /// Get the methods with a handler on `path`, sorted, as listed in an `Allow` header
+    ///
+    /// `HEAD` is included for paths with a `GET` handler when `head_from_get` is set.
+    /// Returns `None` if the path has no handlers at all.
+    ///
+    pub fn allowed_methods(&self, path: &str, head_from_get: bool) -> Option<Vec<Method>> {
+        let mut methods: Vec<Method> = self
+            .handlers
+            .keys()
+            .filter(|(_, handled)| handled == path)
+            .map(|(method, _)| method.clone())
+            .collect();
+        if methods.is_empty() {
+            return None;
+        }
+        if head_from_get && methods.contains(&Method::Get) && !methods.contains(&Method::Head) {
+            methods.push(Method::Head);
+        }
+        methods.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        Some(methods)
+    }
+
+    /// Register a middleware layer, which runs after the ones registered before it
+    ///
+    /// Every request answered with a response goes through the layers, including
+    /// ones for files and errors. WebSocket upgrades are handed over before, so
+    /// they don't.
+    ///
+    pub fn add_middleware(&mut self, middleware: Middleware) {
+        self.middleware.push(middleware);
+    }
+
+    /// Register a middleware layer that runs before all the others
+    pub fn add_outer_middleware(&mut self, middleware: Middleware) {
+        self.middleware.insert(0, middleware);
+    }
+
+    /// Answer a request through the middleware layers, with `respond` after the last
+    pub fn run_middleware<'a>(
+        &'a self,
+        request: &'a Request,
+        respond: impl FnOnce() -> Response + 'a,
+    ) -> Response {
+        Next::new(&self.middleware, request, Box::new(respond)).run()
+    }
+
+    /// Register the handler for requests that match no handler or page
+    ///
+    /// It decides the whole response, including the status, so it can return
+    /// e.g. a JSON error instead of the default `404`
+    ///
+    pub fn set_not_found_handler(&mut self, handler: Handler) {
+        self.not_found = Some(handler);
+    }
+
+    /// Get the handler for requests that match no handler or page
+    pub fn not_found_handler(&self) -> Option<&Handler> {
+        self.not_found.as_ref()
+    }
+
+    /// Get the contents of the pages directory's `404.html`, if it has one
+    pub fn not_found_page(&self) -> Option<&[u8]> {
+        self.not_found_page.as_deref()
     }
 
     /// Initialize the routes
     ///
     /// The routes are initialized by reading the pages directory
     ///
-    fn init_routes() -> HashMap<String, String> {
+    fn init_routes(root_dir: &Path, limit: ScanLimit) -> io::Result<RouteTable> {
         debug!("Initializing routes...");
-        let current_dir = env::current_dir().expect("Failed to get current directory");
-        let root_dir = current_dir.join("pages");
 
-        let mut routes = HashMap::new();
-        Self::read_path(&root_dir, &mut routes);
+        let mut scan = Scan {
+            limit,
+            scanned: 0,
+            partial: false,
+            routes: HashMap::new(),
+            precompressed: HashMap::new(),
+            root: fs::canonicalize(root_dir).unwrap_or_else(|_| root_dir.to_path_buf()),
+            outside: HashSet::new(),
+        };
+        Self::read_path(root_dir, root_dir, &mut scan)?;
+
+        // A compressed copy of another file is served in its place rather than being a
+        // page of its own, but one without its original, e.g. `archive.tar.gz`, is
+        let files: HashSet<String> = scan.routes.values().cloned().collect();
+        scan.routes.retain(|_, p| match precompressed_original(p) {
+            Some((file, encoding)) if files.contains(file) => {
+                if scan.outside.contains(p) {
+                    warn!("Not serving {:#?}, it is outside of pages", p);
+                    return false;
+                }
+                debug!("file: {:#?}, {} sibling: {:#?}", file, encoding, p);
+                scan.precompressed
+                    .entry(file.to_string())
+                    .or_default()
+                    .push(encoding);
+                false
+            }
+            _ => true,
+        });
 
-        info!("Routes: {:#?}", routes);
-        routes
+        // Every directory a route is in, e.g. `/docs/guide.html` is in `/docs` and the
+        // root, which is the empty string
+        let directories = scan
+            .routes
+            .keys()
+            .flat_map(|route| {
+                route
+                    .match_indices('/')
+                    .map(|(end, _)| route[..end].to_string())
+            })
+            .collect();
+
+        info!("Routes: {:#?}", scan.routes);
+        debug!("Precompressed files: {:#?}", scan.precompressed);
+        Ok(RouteTable {
+            routes: scan.routes,
+            precompressed: scan.precompressed,
+            partial: scan.partial,
+            generation: 0,
+            fingerprints: None,
+            fingerprinted: HashSet::new(),
+            outside: scan.outside,
+            directories,
+        })
     }
 
-    fn read_path(dir: &Path, map: &mut HashMap<String, String>) {
-        let root_path = dir.strip_prefix(env::current_dir().unwrap()).unwrap();
-        let root_page = remove_first_occurrence(root_path.to_str().unwrap(), "pages");
+    /// Add the files in `dir` to the routes, keyed by their path under `root`
+    ///
+    /// The files are stored by their absolute path, so serving them doesn't depend
+    /// on the current directory either. The type of each entry comes from reading the
+    /// directory, only symlinks cost an extra `stat` to find what they point to.
+    ///
+    /// Returns false once the total scan limit is reached, to stop reading altogether,
+    /// or the error reading `dir` or one of the directories in it
+    ///
+    fn read_path(root: &Path, dir: &Path, scan: &mut Scan) -> io::Result<bool> {
+        let entries = dir.read_dir()?;
+        for (index, entry) in entries.enumerate() {
+            if scan.limit.per_dir.is_some_and(|max| index >= max) {
+                warn!("Stopped reading {:#?} after {} entries", dir, index);
+                scan.partial = true;
+                break;
+            }
+            if scan.limit.total.is_some_and(|max| scan.scanned >= max) {
+                warn!("Stopped reading pages after {} entries", scan.scanned);
+                scan.partial = true;
+                return Ok(false);
+            }
+            scan.scanned += 1;
 
-        for entry in dir.read_dir().expect("Failed to read directory") {
-            let entry = entry.expect("Failed to get entry");
+            let entry = entry?;
             let path = entry.path();
-            if path.is_dir() {
+            let is_dir = match entry.file_type() {
+                Ok(file_type) if file_type.is_symlink() => path.is_dir(),
+                Ok(file_type) => file_type.is_dir(),
+                Err(_) => path.is_dir(),
+            };
+            if is_dir {
                 // Recursively read the directory
-                Self::read_path(&path, map);
+                if !Self::read_path(root, &path, scan)? {
+                    return Ok(false);
+                }
             } else {
                 // Add the file to the map
-                match path.strip_prefix(env::current_dir().unwrap()) {
-                    Ok(stripped_path) => {
-                        match stripped_path.to_str() {
-                            Some(p) => {
-                                if p.contains("index.html") {
-                                    // If the file is index.html, add it to the root page
-                                    debug!("page: {:#?}, path: {:#?}", root_page, p);
-                                    map.insert(
-                                        String::from(root_page.to_string()),
-                                        String::from(p.to_string()),
-                                    );
-                                } else {
-                                    // Otherwise, add it to the map
-                                    let page = if p.contains("html") {
-                                        path.strip_prefix(env::current_dir().unwrap())
-                                            .unwrap()
-                                            .with_extension("")
-                                    } else {
-                                        path.strip_prefix(env::current_dir().unwrap())
-                                            .unwrap()
-                                            .to_path_buf()
-                                    };
-                                    let page_str =
-                                        remove_first_occurrence(page.to_str().unwrap(), "pages");
-                                    debug!("page: {:#?}, path: {:#?}", page_str, p);
-                                    map.insert(page_str, String::from(p.to_string()));
-                                }
-                            }
-                            None => {
-                                error!("Failed to convert path to string");
-                            }
-                        };
-                    }
-                    Err(_) => {
-                        error!("Failed to strip prefix from path: {:#?}", path);
+                let (p, page) = match (path.to_str(), path.strip_prefix(root)) {
+                    (Some(p), Ok(stripped_path)) => (p, stripped_path.to_string_lossy()),
+                    _ => {
+                        error!("Failed to convert path to string: {:#?}", path);
+                        continue;
                     }
+                };
+                // The routes come from the pages directory, but a symlink in it may lead
+                // elsewhere
+                if !fs::canonicalize(&path).is_ok_and(|file| file.starts_with(&scan.root)) {
+                    scan.outside.insert(p.to_string());
                 }
+                // Precompressed siblings are told apart once every file is known, see
+                // `init_routes`
+                let page = format!("/{}", page);
+                debug!("page: {:#?}, path: {:#?}", page, p);
+                scan.routes.insert(page, p.to_string());
             }
         }
+        Ok(true)
     }
 }
 
-/// Remove the first occurrence of a pattern from a string
+/// Replace the routes behind `lock` with `table`, numbering it after the old one
 ///
-/// # Arguments
+/// The assets are fingerprinted again if the old routes had fingerprints
 ///
-/// * `input` - The input string
-/// * `pattern` - The pattern to remove
-///
-fn remove_first_occurrence(input: &str, pattern: &str) -> String {
-    if let Some(index) = input.find(pattern) {
-        let (_, rest) = input.split_at(index);
-        let result_str = rest.trim_start_matches(pattern);
-
-        if result_str.is_empty() {
-            "/".to_string()
-        } else {
-            result_str.to_string()
+fn swap_in(lock: &RwLock<Arc<RouteTable>>, mut table: RouteTable) {
+    let fingerprinting = lock.read().unwrap().fingerprints.is_some();
+    if fingerprinting && table.fingerprints.is_none() {
+        table.add_fingerprints();
+    }
+    let mut current = lock.write().unwrap();
+    table.generation = current.generation + 1;
+    *current = Arc::new(table);
+}
+
+/// Hash the paths, sizes and modification times of everything under `dir`, so any
+/// change to the pages directory changes the result
+fn fingerprint(dir: &Path) -> u64 {
+    fn visit(dir: &Path, hasher: &mut DefaultHasher) {
+        let mut paths: Vec<PathBuf> = match dir.read_dir() {
+            Ok(entries) => entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .collect(),
+            Err(_) => return,
+        };
+        paths.sort();
+        for path in paths {
+            path.hash(hasher);
+            if let Ok(metadata) = fs::metadata(&path) {
+                metadata.len().hash(hasher);
+                metadata.modified().ok().hash(hasher);
+                if metadata.is_dir() {
+                    visit(&path, hasher);
+                }
+            }
+        }
+    }
+
+    let mut hasher = DefaultHasher::new();
+    visit(dir, &mut hasher);
+    hasher.finish()
+}
+
+/// The progress of reading the pages directory, see `Router::read_path`
+struct Scan {
+    limit: ScanLimit,
+    scanned: usize,
+    partial: bool,
+    routes: HashMap<String, String>,
+    precompressed: HashMap<String, Vec<&'static str>>,
+    // The canonical path of the pages directory
+    root: PathBuf,
+    outside: HashSet<String>,
+}
+
+/// The route a resolution step looks up for a request path
+fn candidate(path: &str, step: Resolution) -> Option<String> {
+    match step {
+        Resolution::Exact => Some(path.to_string()),
+        Resolution::HtmlSibling if path.ends_with('/') => None,
+        Resolution::HtmlSibling => Some(format!("{}.html", path)),
+        Resolution::DirectoryIndex => Some(format!("{}/index.html", path.trim_end_matches('/'))),
+    }
+}
+
+/// Insert a locale, or another tag, before the extension of a route, e.g.
+/// `/about.html` becomes `/about.fr.html`
+fn localized(route: &str, locale: &str) -> String {
+    let name_start = route.rfind('/').map_or(0, |index| index + 1);
+    match route[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => {
+            let (stem, extension) = route.split_at(name_start + dot);
+            format!("{}.{}{}", stem, locale, extension)
         }
-    } else {
-        input.to_string()
+        _ => format!("{}.{}", route, locale),
     }
 }
 
+/// The content codings that can be served from sibling files
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// Split a precompressed sibling path such as `pages/about.html.br` into the
+/// original file path and its content coding
+fn precompressed_original(path: &str) -> Option<(&str, &'static str)> {
+    PRECOMPRESSED.iter().find_map(|(encoding, extension)| {
+        let file = path.strip_suffix(extension)?.strip_suffix('.')?;
+        Some((file, *encoding))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::temp_pages::TempPages;
     use simple_logger::SimpleLogger;
+    use std::{
+        fs,
+        time::{Duration, Instant},
+    };
 
     #[test]
     fn test_init_routes() {
         SimpleLogger::new().init().unwrap();
         info!("Testing init_routes");
 
-        let router = Router::new();
-        assert!(router.get_routes().len() > 0);
+        let router = Router::new(ScanLimit::default()).unwrap();
+        assert!(!router.table().get_routes().is_empty());
+    }
+
+    /// The absolute path of a file in the pages directory, as stored in the routes
+    fn page(file: &str) -> String {
+        let pages = env::current_dir().unwrap().join("pages");
+        pages.join(file).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_routes_survive_cwd_change() {
+        // The current directory is shared by every test thread, so it is only changed
+        // in a copy of the test binary running just this test
+        if env::var_os("ROUTER_CWD_CHANGE").is_none() {
+            let output = std::process::Command::new(env::current_exe().unwrap())
+                .args(["--exact", "router::tests::test_routes_survive_cwd_change"])
+                .env("ROUTER_CWD_CHANGE", "1")
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stdout)
+            );
+            return;
+        }
+
+        let router = Router::with_root(PathBuf::from("pages"), ScanLimit::default()).unwrap();
+        let about = page("about.html");
+        env::set_current_dir(env::temp_dir()).unwrap();
+
+        assert_eq!(router.root(), Path::new(&about).parent().unwrap());
+        let table = router.table();
+        assert_eq!(table.resolve("/about", &ORDER), Some(&about));
+        assert!(Path::new(&about).is_file());
+
+        router.reload().unwrap();
+        assert_eq!(router.table().resolve("/about", &ORDER), Some(&about));
+    }
+
+    #[test]
+    fn test_init_large_tree() {
+        let root = TempPages::new("large-tree");
+        for dir in 0..50 {
+            let dir = root.join(format!("section-{}", dir));
+            fs::create_dir_all(&dir).unwrap();
+            for file in 0..40 {
+                fs::write(dir.join(format!("page-{}.html", file)), "page").unwrap();
+            }
+        }
+
+        let started = Instant::now();
+        let router = Router::with_root(root.to_path_buf(), ScanLimit::default()).unwrap();
+        let elapsed = started.elapsed();
+
+        let table = router.table();
+        assert_eq!(table.get_routes().len(), 2000);
+        assert_eq!(
+            table.resolve("/section-49/page-39", &ORDER),
+            Some(
+                &root
+                    .join("section-49/page-39.html")
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            )
+        );
+        info!("Initialized 2000 routes in {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_scan_limit() {
+        let root = TempPages::new("scan-limit");
+        for dir in 0..3 {
+            let dir = root.join(format!("section-{}", dir));
+            fs::create_dir_all(&dir).unwrap();
+            for file in 0..5 {
+                fs::write(dir.join(format!("page-{}.html", file)), "page").unwrap();
+            }
+        }
+        let routes = |per_dir, total| {
+            let router =
+                Router::with_root(root.to_path_buf(), ScanLimit { per_dir, total }).unwrap();
+            let table = router.table();
+            (table.get_routes().len(), table.is_partial())
+        };
+
+        assert_eq!(routes(None, None), (15, false));
+        assert_eq!(routes(Some(2), None), (4, true));
+        // Two directories, all five files of the first and three of the second
+        let (count, partial) = routes(None, Some(10));
+        assert_eq!((count, partial), (8, true));
+    }
+
+    #[test]
+    fn test_watch() {
+        let root = TempPages::new("watch");
+        root.write("a.html", "a");
+        let router = Router::with_root(root.to_path_buf(), ScanLimit::default()).unwrap();
+        router.watch(Duration::from_millis(20));
+        assert_eq!(router.table().generation(), 0);
+
+        root.write("b.html", "b");
+        let start = Instant::now();
+        while router.table().resolve("/b", &ORDER).is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5), "Never reloaded");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(router.table().generation() >= 1);
+    }
+
+    #[test]
+    fn test_missing_root() {
+        let root = env::temp_dir().join(format!("web-server-missing-{}", std::process::id()));
+        let error = Router::with_root(root, ScanLimit::default()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_not_found_page() {
+        let root = TempPages::new("404");
+        let router = Router::with_root(root.to_path_buf(), ScanLimit::default()).unwrap();
+        assert_eq!(router.not_found_page(), None);
+
+        root.write("404.html", "<h1>Not here</h1>");
+        let router = Router::with_root(root.to_path_buf(), ScanLimit::default()).unwrap();
+        // Read once, later changes need a new Router
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(router.not_found_page(), Some(&b"<h1>Not here</h1>"[..]));
+    }
+
+    #[test]
+    fn test_fingerprint_assets() {
+        let root = TempPages::new("fingerprint");
+        root.write("app.js", "one");
+        root.write("index.html", "<h1>Home</h1>");
+        let router = Router::with_root(root.to_path_buf(), ScanLimit::default()).unwrap();
+        assert_eq!(router.table().fingerprinted("/app.js"), None);
+
+        router.fingerprint_assets().unwrap();
+        let table = router.table();
+        // The first digits of the SHA-1 of "one"
+        assert_eq!(table.fingerprinted("/app.js"), Some("/app.fe05bcdcdc.js"));
+        assert!(table.is_fingerprinted("/app.fe05bcdcdc.js"));
+        assert!(!table.is_fingerprinted("/app.js"));
+        assert_eq!(
+            table.resolve("/app.fe05bcdcdc.js", &ORDER),
+            table.resolve("/app.js", &ORDER)
+        );
+        assert_eq!(table.fingerprinted("/index.html"), None);
+
+        // New contents get a new fingerprint when the routes are reloaded
+        root.write("app.js", "two");
+        router.reload().unwrap();
+        let table = router.table();
+        assert_eq!(table.fingerprinted("/app.js"), Some("/app.ad782ecdac.js"));
+        assert!(table.resolve("/app.fe05bcdcdc.js", &ORDER).is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_is_under_root() {
+        let dir = TempPages::new("under-root");
+        let root = dir.join("pages");
+        dir.write("pages/docs/intro.html", "Intro");
+        dir.write("secret.txt", "Secret");
+        std::os::unix::fs::symlink("../../secret.txt", root.join("docs/secret.txt")).unwrap();
+        std::os::unix::fs::symlink("../missing.html", root.join("docs/broken.html")).unwrap();
+        let table = Router::with_root(root.clone(), ScanLimit::default())
+            .unwrap()
+            .table();
+        let file = |name: &str| root.join(name).to_str().unwrap().to_string();
+
+        let nested = file("docs/intro.html");
+        assert!(table.is_under_root(&nested));
+        assert!(!table.is_under_root(&file("docs/secret.txt")));
+        assert!(!table.is_under_root(&file("docs/broken.html")));
+        // Decided when the routes were built, without looking at the file again
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(table.is_under_root(&nested));
+    }
+
+    #[test]
+    fn test_precompressed_siblings() {
+        let root = TempPages::new("siblings");
+        for file in [
+            "about.html",
+            "about.html.gz",
+            "about.html.br",
+            "archive.tar.gz",
+        ] {
+            fs::write(root.join(file), file).unwrap();
+        }
+        let table = Router::with_root(root.to_path_buf(), ScanLimit::default())
+            .unwrap()
+            .table();
+
+        let about = root.join("about.html").to_str().unwrap().to_string();
+        assert_eq!(
+            table.precompressed_sibling(&about, "gzip"),
+            Some(format!("{}.gz", about))
+        );
+        assert!(table.precompressed_sibling(&about, "br").is_some());
+        assert!(table.resolve("/about.html.gz", &ORDER).is_none());
+        // Without `archive.tar` it is a file of its own
+        let archive = root.join("archive.tar.gz").to_str().unwrap().to_string();
+        assert_eq!(table.resolve("/archive.tar.gz", &ORDER), Some(&archive));
+    }
+
+    #[test]
+    fn test_add_route() {
+        let mut router = Router::new(ScanLimit::default()).unwrap();
+        router.add_route("/health", |_| b"ok".to_vec());
+
+        let request = Request::parse_head(&mut "GET /health HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+        let handler = router.find_handler(&Method::Get, "/health", true).unwrap();
+        let response = handler(&request);
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.get_body(), Some(&b"ok"[..]));
+        assert!(router
+            .find_handler(&Method::Post, "/health", true)
+            .is_none());
+        // Files are still routed alongside
+        assert!(router.table().resolve("/about", &ORDER).is_some());
+    }
+
+    #[test]
+    fn test_localized() {
+        assert_eq!(localized("/about.html", "fr"), "/about.fr.html");
+        assert_eq!(
+            localized("/docs.v2/index.html", "en"),
+            "/docs.v2/index.en.html"
+        );
+        assert_eq!(localized("/LICENSE", "de"), "/LICENSE.de");
+        assert_eq!(localized("/.hidden", "de"), "/.hidden.de");
+    }
+
+    const ORDER: [Resolution; 3] = [
+        Resolution::Exact,
+        Resolution::HtmlSibling,
+        Resolution::DirectoryIndex,
+    ];
+
+    #[test]
+    fn test_resolve_exact_file() {
+        let router = Router::new(ScanLimit::default()).unwrap().table();
+        assert_eq!(
+            router.resolve("/index.css", &ORDER).map(String::as_str),
+            Some(page("index.css").as_str())
+        );
+        assert_eq!(
+            router.resolve("/about.html", &ORDER).map(String::as_str),
+            Some(page("about.html").as_str())
+        );
+    }
+
+    #[test]
+    fn test_resolve_html_sibling() {
+        let router = Router::new(ScanLimit::default()).unwrap().table();
+        assert_eq!(
+            router.resolve("/about", &ORDER).map(String::as_str),
+            Some(page("about.html").as_str())
+        );
+        assert_eq!(
+            router
+                .resolve("/contact/page/test", &ORDER)
+                .map(String::as_str),
+            Some(page("contact/page/test.html").as_str())
+        );
+        assert_eq!(router.resolve("/about", &[Resolution::Exact]), None);
+    }
+
+    #[test]
+    fn test_resolve_directory_index() {
+        let router = Router::new(ScanLimit::default()).unwrap().table();
+        for path in ["/contact", "/contact/"] {
+            assert_eq!(
+                router.resolve(path, &ORDER).map(String::as_str),
+                Some(page("contact/index.html").as_str())
+            );
+        }
+        assert_eq!(
+            router.resolve("/", &ORDER).map(String::as_str),
+            Some(page("index.html").as_str())
+        );
+        assert_eq!(router.resolve("/about/", &ORDER), None);
+        assert_eq!(router.resolve("/contact", &[Resolution::HtmlSibling]), None);
+    }
+
+    #[test]
+    fn test_is_directory() {
+        let router = Router::new(ScanLimit::default()).unwrap().table();
+        assert!(router.is_directory("/contact"));
+        assert!(router.is_directory("/contact/"));
+        assert!(router.is_directory("/"));
+        assert!(!router.is_directory("/cont"));
+        assert!(!router.is_directory("/about.html"));
     }
 
     #[test]
-    fn test_remove_first_occurrence() {
-        let input = "pages/index.html";
-        let pattern = "pages";
-        let result = remove_first_occurrence(input, pattern);
-        assert_eq!(result, "/index.html");
+    fn test_request_paths() {
+        let router = Router::new(ScanLimit::default()).unwrap().table();
+        assert_eq!(
+            router.request_paths("/contact/index.html", &ORDER),
+            ["/contact/index.html", "/contact/index", "/contact"]
+        );
+        assert_eq!(
+            router.request_paths("/index.html", &ORDER),
+            ["/index.html", "/index", "/"]
+        );
+        assert_eq!(router.request_paths("/index.css", &ORDER), ["/index.css"]);
+        assert_eq!(
+            router.request_paths("/about.html", &[Resolution::Exact]),
+            ["/about.html"]
+        );
+        assert!(router.request_paths("/missing.html", &ORDER).is_empty());
     }
 }
@@ -0,0 +1,43 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once the process has been asked to stop
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Have `SIGINT` (Ctrl-C) and `SIGTERM` set `stop_requested` instead of killing the process
+///
+/// Only supported on Unix, elsewhere the signals keep their default behaviour
+///
+pub fn install() {
+    #[cfg(unix)]
+    // The handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Whether a stop signal has been received since `install`
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(_: libc::c_int) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sigint_requests_stop() {
+        install();
+        assert!(!stop_requested());
+        unsafe {
+            libc::raise(libc::SIGINT);
+        }
+        assert!(stop_requested());
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// The TempPages struct
+///
+/// The TempPages struct is a directory of pages for a test, created empty in the
+/// system's temporary directory and removed again when dropped, so a failing test
+/// doesn't leave it behind
+///
+/// # Fields
+///
+/// * `root` - The directory
+///
+pub struct TempPages {
+    root: PathBuf,
+}
+
+impl TempPages {
+    /// Create an empty directory named after `name` and the process
+    pub fn new(name: &str) -> Self {
+        let root = std::env::temp_dir().join(format!("web-server-{}-{}", name, std::process::id()));
+        // Left behind by a run that was killed
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        TempPages { root }
+    }
+
+    /// Write a file at a path relative to the directory, creating its parents
+    pub fn write(&self, file: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.root.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Deref for TempPages {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl AsRef<Path> for TempPages {
+    fn as_ref(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl Drop for TempPages {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
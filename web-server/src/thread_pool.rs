@@ -1,9 +1,19 @@
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
-use log::info;
+use log::{error, info};
+
+/// How often an idle worker checks the shutdown flag
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 /// A Worker struct
 ///
@@ -13,24 +23,124 @@ use log::info;
 ///
 /// * `id` - The id of the worker
 /// * `thread` - The thread of the worker
+/// * `counters` - What the worker has done so far, updated by its thread
+/// * `stop` - Set to have just this worker stop, once it is done with its current job
 ///
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
+    counters: Arc<WorkerCounters>,
+    stop: Arc<AtomicBool>,
+}
+
+/// The jobs a worker has run and the time it spent running them
+#[derive(Default)]
+struct WorkerCounters {
+    jobs: AtomicU64,
+    busy_nanos: AtomicU64,
+}
+
+/// The WorkerStat struct
+///
+/// The WorkerStat struct is a snapshot of what a worker has done, see
+/// `ThreadPool::worker_stats`
+///
+/// # Fields
+///
+/// * `id` - The id of the worker
+/// * `jobs` - The number of jobs the worker has finished, including panicked ones
+/// * `busy` - The total time the worker spent running jobs
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkerStat {
+    pub id: usize,
+    pub jobs: u64,
+    pub busy: Duration,
+}
+
+/// The PoolStats struct
+///
+/// The PoolStats struct is a snapshot of how loaded the pool is, see `ThreadPool::stats`
+///
+/// # Fields
+///
+/// * `queued` - The number of jobs waiting for a worker
+/// * `active` - The number of workers running a job
+/// * `workers` - The number of workers in the pool
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolStats {
+    pub queued: usize,
+    pub active: usize,
+    pub workers: usize,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// The ExecuteError enum
+///
+/// The ExecuteError enum holds the reasons a job was not accepted by the pool
+///
+/// # Variants
+///
+/// * `ShuttingDown` - The pool has been asked to stop
+///
+#[derive(Debug, PartialEq)]
+pub enum ExecuteError {
+    ShuttingDown,
+}
+
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    /// Get what the worker has done so far
+    fn stat(&self) -> WorkerStat {
+        WorkerStat {
+            id: self.id,
+            jobs: self.counters.jobs.load(Ordering::Acquire),
+            busy: Duration::from_nanos(self.counters.busy_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn new(
+        id: usize,
+        receiver: Receiver<Job>,
+        shutdown: Arc<AtomicBool>,
+        active: Arc<AtomicUsize>,
+    ) -> Worker {
+        let counters = Arc::new(WorkerCounters::default());
+        let stats = Arc::clone(&counters);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        // Every worker blocks on its own handle of the channel, so any idle worker
+        // can take the next job without waiting for the others
         let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
+            // Jobs left in the queue are taken by the other workers
+            if stopped.load(Ordering::SeqCst) {
+                info!("Worker {} was removed from the pool.", id);
+                break;
+            }
+            let message = receiver.recv_timeout(SHUTDOWN_POLL_INTERVAL);
 
             match message {
                 Ok(job) => {
                     info!("Worker {} got a job; executing.", id);
-                    job();
+                    active.fetch_add(1, Ordering::Relaxed);
+                    let started = Instant::now();
+                    // A panicking job would otherwise take the worker's thread with it.
+                    // Jobs share nothing with the worker, so it is fine to carry on.
+                    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                        error!(
+                            "Worker {} recovered from a panicking job: {}",
+                            id,
+                            panic_message(&payload)
+                        );
+                    }
+                    let busy = started.elapsed().as_nanos().min(u64::MAX as u128) as u64;
+                    stats.busy_nanos.fetch_add(busy, Ordering::Relaxed);
+                    stats.jobs.fetch_add(1, Ordering::Release);
+                    active.fetch_sub(1, Ordering::Relaxed);
                 }
+                // Queued jobs are finished first, the flag is only checked once idle
+                Err(RecvTimeoutError::Timeout) if !shutdown.load(Ordering::SeqCst) => {}
                 Err(_) => {
                     info!("Worker {} is shutting down.", id);
                     break;
@@ -41,22 +151,46 @@ impl Worker {
         Worker {
             id,
             thread: Some(thread),
+            counters,
+            stop,
         }
     }
 }
 
+/// Get the message a panic was raised with, if it was a string
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("unknown panic", String::as_str),
+    }
+}
+
 /// A ThreadPool struct
 ///
 /// The ThreadPool struct is responsible for holding the workers and the sender of the thread pool
 ///
 /// # Fields
 ///
-/// * `workers` - A vector of the workers
+/// * `workers` - A vector of the workers, behind a lock so the pool can be resized
+///   while it is shared
+/// * `removed` - What the workers removed by `set_size` did, kept for `worker_stats`
 /// * `sender` - The sender of the thread pool
+/// * `receiver` - The receiving end of the channel, handed to workers added later. As
+///   the pool holds it, sending a job can't fail while the pool exists.
+/// * `shutdown` - Set once the pool has been asked to stop
+/// * `next_id` - The id of the next worker added
+/// * `active` - The number of workers running a job, shared with them
 ///
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    workers: Mutex<Vec<Worker>>,
+    removed: Mutex<Vec<WorkerStat>>,
+    sender: Option<Sender<Job>>,
+    receiver: Receiver<Job>,
+    shutdown: Arc<AtomicBool>,
+    next_id: AtomicUsize,
+    active: Arc<AtomicUsize>,
 }
 
 impl ThreadPool {
@@ -70,35 +204,135 @@ impl ThreadPool {
     pub fn new(size: usize) -> ThreadPool {
         assert!(size > 0);
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let active = Arc::new(AtomicUsize::new(0));
 
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(
+                id,
+                receiver.clone(),
+                Arc::clone(&shutdown),
+                Arc::clone(&active),
+            ));
         }
 
         ThreadPool {
-            workers,
+            workers: Mutex::new(workers),
+            removed: Mutex::new(Vec::new()),
             sender: Some(sender),
+            receiver,
+            shutdown,
+            next_id: AtomicUsize::new(size),
+            active,
         }
     }
 
-    pub fn execute<F>(&self, f: F)
+    /// Grow or shrink the pool to `new_size` workers
+    ///
+    /// Removed workers finish the job they are running first, this waits for them
+    /// without holding up `execute`. Queued jobs are not lost, the remaining workers
+    /// take them.
+    ///
+    /// # Panics
+    ///
+    /// The `set_size` function will panic if `new_size` is zero
+    ///
+    pub fn set_size(&self, new_size: usize) {
+        assert!(new_size > 0);
+
+        let mut workers = self.workers.lock().unwrap();
+        while workers.len() < new_size {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let receiver = self.receiver.clone();
+            let shutdown = Arc::clone(&self.shutdown);
+            let active = Arc::clone(&self.active);
+            workers.push(Worker::new(id, receiver, shutdown, active));
+        }
+
+        let removed = workers.split_off(new_size);
+        drop(workers);
+        for worker in &removed {
+            worker.stop.store(true, Ordering::SeqCst);
+        }
+        for mut worker in removed {
+            info!("Removing worker {}", worker.id);
+            if let Some(thread) = worker.thread.take() {
+                thread.join().unwrap();
+            }
+            self.removed.lock().unwrap().push(worker.stat());
+        }
+    }
+
+    /// Queue a job for the next idle worker
+    ///
+    /// Once the pool is shutting down the job is dropped without running and an
+    /// error returned, so the caller can clean up whatever it was meant to handle
+    ///
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
     where
         F: FnOnce() + Send + 'static,
     {
+        if self.shutdown.load(Ordering::SeqCst) {
+            return Err(ExecuteError::ShuttingDown);
+        }
+
         let job = Box::new(f);
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        match &self.sender {
+            Some(sender) => {
+                // The pool's own receiver keeps the channel connected
+                sender.send(job).expect("the pool holds a receiver");
+                Ok(())
+            }
+            None => Err(ExecuteError::ShuttingDown),
+        }
+    }
+
+    /// Get what each worker has done so far, to spot jobs piling up on some of them
+    ///
+    /// Workers removed by `set_size` are included, by id along with the current ones
+    ///
+    pub fn worker_stats(&self) -> Vec<WorkerStat> {
+        let workers = self.workers.lock().unwrap();
+        let mut stats = self.removed.lock().unwrap().clone();
+        stats.extend(workers.iter().map(Worker::stat));
+        stats.sort_by_key(|stat| stat.id);
+        stats
+    }
+
+    /// Get how many jobs are waiting and how many workers are busy, for sizing the pool
+    ///
+    /// The queue length comes from the channel itself, so submitting a job costs
+    /// nothing extra
+    ///
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            queued: self.receiver.len(),
+            active: self.active.load(Ordering::Relaxed),
+            workers: self.workers.lock().unwrap().len(),
+        }
+    }
+
+    /// Ask the workers to stop once the queued jobs are done
+    ///
+    /// New jobs are rejected from then on. Unlike dropping the pool this doesn't wait
+    /// for the workers, who notice the request within `SHUTDOWN_POLL_INTERVAL` of
+    /// becoming idle.
+    ///
+    pub fn shutdown(&self) {
+        info!("Shutting down thread pool");
+        self.shutdown.store(true, Ordering::SeqCst);
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
         drop(self.sender.take());
 
-        for worker in &mut self.workers {
+        for worker in self.workers.get_mut().unwrap() {
             info!("Shutting down worker {}", worker.id);
 
             if let Some(thread) = worker.thread.take() {
@@ -107,3 +341,256 @@ impl Drop for ThreadPool {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{Barrier, Condvar, Mutex},
+        time::Instant,
+    };
+
+    #[test]
+    fn test_jobs_run_concurrently() {
+        let pool = ThreadPool::new(4);
+        // Every job waits for all the others, which only finishes if every worker
+        // picks up a job while the others are still running theirs
+        let barrier = Arc::new(Barrier::new(4));
+        let done = Arc::new((Mutex::new(0), Condvar::new()));
+
+        for _ in 0..4 {
+            let barrier = Arc::clone(&barrier);
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                barrier.wait();
+                let (count, condvar) = &*done;
+                *count.lock().unwrap() += 1;
+                condvar.notify_one();
+            })
+            .unwrap();
+        }
+
+        let (count, condvar) = &*done;
+        let (count, timeout) = condvar
+            .wait_timeout_while(count.lock().unwrap(), Duration::from_secs(5), |count| {
+                *count < 4
+            })
+            .unwrap();
+        assert!(!timeout.timed_out());
+        assert_eq!(*count, 4);
+    }
+
+    #[test]
+    fn test_dispatch_many_short_jobs() {
+        const JOBS: usize = 1_000;
+        let pool = ThreadPool::new(4);
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        for job in 0..JOBS {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(job).unwrap()).unwrap();
+        }
+        drop(sender);
+
+        // Bursts of short jobs are spread over the workers without losing or repeating any
+        let mut ran: Vec<usize> = receiver.iter().take(JOBS).collect();
+        assert_eq!(ran.len(), JOBS);
+        ran.sort();
+        assert!(ran.iter().copied().eq(0..JOBS));
+        drop(pool);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_worker_survives_panicking_job() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        pool.execute(|| panic!("job failed")).unwrap();
+        pool.execute(move || sender.send(()).unwrap()).unwrap();
+
+        // The only worker ran the second job after the first one panicked
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+        let workers = pool.workers.lock().unwrap();
+        assert!(!workers[0].thread.as_ref().unwrap().is_finished());
+    }
+
+    #[test]
+    fn test_worker_stats() {
+        const JOBS: u64 = 100;
+        let pool = ThreadPool::new(4);
+        for _ in 0..JOBS {
+            pool.execute(|| thread::sleep(Duration::from_micros(100)))
+                .unwrap();
+        }
+        pool.execute(|| panic!("job failed")).unwrap();
+
+        // The counters are updated after each job returns, so wait for the last ones
+        let start = Instant::now();
+        let total = || {
+            pool.worker_stats()
+                .iter()
+                .map(|stat| stat.jobs)
+                .sum::<u64>()
+        };
+        while total() < JOBS + 1 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Jobs never finished"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let stats = pool.worker_stats();
+        assert_eq!(stats.len(), 4);
+        assert_eq!(total(), JOBS + 1);
+        for (id, stat) in stats.iter().enumerate() {
+            assert_eq!(stat.id, id);
+        }
+        let busy: Duration = stats.iter().map(|stat| stat.busy).sum();
+        assert!(busy >= Duration::from_micros(100) * JOBS as u32);
+    }
+
+    #[test]
+    fn test_stats() {
+        let pool = ThreadPool::new(2);
+        let (release, blocked) = crossbeam_channel::unbounded::<()>();
+        for _ in 0..5 {
+            let blocked = blocked.clone();
+            pool.execute(move || {
+                let _ = blocked.recv();
+            })
+            .unwrap();
+        }
+
+        let wait_until = |expected: PoolStats| {
+            let start = Instant::now();
+            while pool.stats() != expected {
+                assert!(
+                    start.elapsed() < Duration::from_secs(5),
+                    "Stats stayed at {:?}",
+                    pool.stats()
+                );
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+        wait_until(PoolStats {
+            queued: 3,
+            active: 2,
+            workers: 2,
+        });
+
+        // Every blocked job returns once the sender is gone
+        drop(release);
+        wait_until(PoolStats {
+            queued: 0,
+            active: 0,
+            workers: 2,
+        });
+    }
+
+    /// Wait until `count` jobs sent through `receiver` have run
+    fn wait_for(receiver: &Receiver<()>, count: usize) {
+        for _ in 0..count {
+            assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_set_size() {
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let submit = |pool: &ThreadPool, count: usize| {
+            for _ in 0..count {
+                let sender = sender.clone();
+                pool.execute(move || sender.send(()).unwrap()).unwrap();
+            }
+        };
+
+        pool.set_size(5);
+        assert_eq!(pool.stats().workers, 5);
+        let ids: Vec<usize> = pool.worker_stats().iter().map(|stat| stat.id).collect();
+        assert_eq!(ids, [0, 1, 2, 3, 4]);
+        submit(&pool, 20);
+        wait_for(&receiver, 20);
+
+        // Jobs queued behind a slow one are still run after shrinking
+        pool.execute(|| thread::sleep(Duration::from_millis(50)))
+            .unwrap();
+        submit(&pool, 20);
+        pool.set_size(1);
+        assert_eq!(pool.stats().workers, 1);
+        wait_for(&receiver, 20);
+        submit(&pool, 5);
+        wait_for(&receiver, 5);
+
+        // New workers don't reuse the ids of removed ones, whose jobs still count
+        pool.set_size(2);
+        assert_eq!(pool.stats().workers, 2);
+        let stats = pool.worker_stats();
+        let ids: Vec<usize> = stats.iter().map(|stat| stat.id).collect();
+        assert_eq!(ids, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(stats.iter().map(|stat| stat.jobs).sum::<u64>(), 46);
+    }
+
+    #[test]
+    fn test_set_size_while_shared() {
+        let pool = Arc::new(ThreadPool::new(1));
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        // A job holding the only worker doesn't stop others being run by a new one
+        let (release, blocked) = crossbeam_channel::unbounded::<()>();
+        pool.execute(move || {
+            let _ = blocked.recv();
+        })
+        .unwrap();
+
+        let resized = Arc::clone(&pool);
+        thread::spawn(move || resized.set_size(2)).join().unwrap();
+        pool.execute(move || sender.send(()).unwrap()).unwrap();
+        wait_for(&receiver, 1);
+        drop(release);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_set_size_zero() {
+        ThreadPool::new(1).set_size(0);
+    }
+
+    #[test]
+    fn test_explicit_shutdown() {
+        let pool = ThreadPool::new(2);
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&ran);
+        pool.execute(move || flag.store(true, Ordering::SeqCst))
+            .unwrap();
+
+        pool.shutdown();
+
+        let start = Instant::now();
+        while !pool
+            .workers
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|worker| worker.thread.as_ref().unwrap().is_finished())
+        {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Workers didn't stop"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(ran.load(Ordering::SeqCst));
+
+        // Jobs submitted after shutdown are rejected and never run
+        let late = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&late);
+        assert_eq!(
+            pool.execute(move || flag.store(true, Ordering::SeqCst)),
+            Err(ExecuteError::ShuttingDown)
+        );
+        drop(pool);
+        assert!(!late.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    io::{self, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The ThrottledWriter struct
+///
+/// The ThrottledWriter struct caps the throughput of the wrapped writer by writing in
+/// small chunks and sleeping between them. It gives up with `TimedOut` once keeping to
+/// the rate would run past the deadline.
+///
+/// # Fields
+///
+/// * `inner` - The wrapped writer
+/// * `bytes_per_sec` - The throughput cap
+/// * `deadline` - When the write must be finished
+/// * `started` - When the first byte was written
+/// * `written` - How many bytes have been written so far
+///
+pub struct ThrottledWriter<W: Write> {
+    inner: W,
+    bytes_per_sec: u64,
+    deadline: Instant,
+    started: Instant,
+    written: u64,
+}
+
+impl<W: Write> ThrottledWriter<W> {
+    /// Create a new ThrottledWriter
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if `bytes_per_sec` is zero
+    pub fn new(inner: W, bytes_per_sec: u64, deadline: Instant) -> Self {
+        assert!(bytes_per_sec > 0);
+
+        ThrottledWriter {
+            inner,
+            bytes_per_sec,
+            deadline,
+            started: Instant::now(),
+            written: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for ThrottledWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Write about a tenth of a second's worth at a time so the rate stays smooth
+        let chunk = ((self.bytes_per_sec / 10).max(1) as usize).min(buf.len());
+        let written = self.inner.write(&buf[..chunk])?;
+        self.written += written as u64;
+
+        let due =
+            self.started + Duration::from_secs_f64(self.written as f64 / self.bytes_per_sec as f64);
+        if due > self.deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Throttled write would exceed the request deadline",
+            ));
+        }
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttles_to_rate() {
+        let mut output = Vec::new();
+        let start = Instant::now();
+        let deadline = start + Duration::from_secs(10);
+
+        ThrottledWriter::new(&mut output, 10_000, deadline)
+            .write_all(&[0; 3_000])
+            .unwrap();
+
+        assert_eq!(output.len(), 3_000);
+        assert!(start.elapsed() >= Duration::from_millis(290));
+    }
+
+    #[test]
+    fn test_respects_deadline() {
+        let mut output = Vec::new();
+        let deadline = Instant::now() + Duration::from_millis(100);
+
+        let result = ThrottledWriter::new(&mut output, 1_000, deadline).write_all(&[0; 10_000]);
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(output.len() < 10_000);
+    }
+}
@@ -0,0 +1,237 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::debug;
+use sha1::{Digest, Sha1};
+use std::io::{self, prelude::*};
+
+use crate::{connection::ConnectionReader, request::Request, response::Response};
+
+/// The GUID appended to the client key when computing `Sec-WebSocket-Accept` (RFC 6455)
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The largest message a handler will be given, larger messages close the connection
+const MAX_MESSAGE_BYTES: usize = 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// A handler that takes over a connection after the WebSocket handshake
+pub type WebSocketHandler = Box<dyn Fn(&mut WebSocket) + Send + Sync>;
+
+/// A complete WebSocket message
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Close,
+}
+
+/// The WebSocket struct
+///
+/// The WebSocket struct wraps an upgraded connection and speaks the WebSocket framing
+///
+/// # Fields
+///
+/// * `reader` - The buffered connection, which may already hold the first frames
+///
+pub struct WebSocket {
+    reader: ConnectionReader,
+}
+
+impl WebSocket {
+    pub fn new(reader: ConnectionReader) -> Self {
+        WebSocket { reader }
+    }
+
+    /// Read the next message, answering pings and reassembling fragmented messages
+    pub fn read_message(&mut self) -> io::Result<Message> {
+        let mut message_opcode = None;
+        let mut payload = Vec::new();
+
+        loop {
+            let (fin, opcode, mut data) = self.read_frame()?;
+            match opcode {
+                OPCODE_PING => self.write_frame(OPCODE_PONG, &data)?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => return Ok(Message::Close),
+                OPCODE_TEXT | OPCODE_BINARY if message_opcode.is_none() => {
+                    message_opcode = Some(opcode);
+                    payload.append(&mut data);
+                }
+                OPCODE_CONTINUATION if message_opcode.is_some() => payload.append(&mut data),
+                _ => return Err(protocol_error("Unexpected frame opcode")),
+            }
+
+            if payload.len() > MAX_MESSAGE_BYTES {
+                return Err(protocol_error("Message too large"));
+            }
+
+            match message_opcode {
+                Some(OPCODE_TEXT) if fin => {
+                    return String::from_utf8(payload)
+                        .map(Message::Text)
+                        .map_err(|_| protocol_error("Text message is not valid UTF-8"));
+                }
+                Some(_) if fin => return Ok(Message::Binary(payload)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Send a message as a single frame
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(OPCODE_TEXT, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(OPCODE_BINARY, data),
+            Message::Close => self.write_frame(OPCODE_CLOSE, &[]),
+        }
+    }
+
+    fn read_frame(&mut self) -> io::Result<(bool, u8, Vec<u8>)> {
+        let mut head = [0; 2];
+        self.reader.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        let masked = head[1] & 0x80 != 0;
+
+        let length = match head[1] & 0x7F {
+            126 => {
+                let mut length = [0; 2];
+                self.reader.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as usize
+            }
+            127 => {
+                let mut length = [0; 8];
+                self.reader.read_exact(&mut length)?;
+                u64::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        if length > MAX_MESSAGE_BYTES {
+            return Err(protocol_error("Frame too large"));
+        }
+
+        let mut mask = [0; 4];
+        if masked {
+            self.reader.read_exact(&mut mask)?;
+        }
+
+        let mut payload = vec![0; length];
+        self.reader.read_exact(&mut payload)?;
+        if masked {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+
+        Ok((fin, opcode, payload))
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            length if length < 126 => frame.push(length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+
+        self.reader.get_mut().write_all(&frame)
+    }
+}
+
+/// Whether the request asks to upgrade the connection to a WebSocket
+pub fn is_upgrade(request: &Request) -> bool {
+    request.has_connection_option("upgrade")
+        && request
+            .header("upgrade")
+            .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// Build the `101 Switching Protocols` response for a WebSocket upgrade
+///
+/// Returns `None` if the request is missing the key or asks for an unsupported version
+///
+pub fn handshake(request: &Request) -> Option<Response> {
+    if request.method != "GET" || request.header("sec-websocket-version") != Some("13") {
+        return None;
+    }
+    let key = request.header("sec-websocket-key")?;
+
+    Some(
+        Response::new()
+            .status(101, "SWITCHING PROTOCOLS")
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Accept", &accept_key(key)),
+    )
+}
+
+/// Compute the `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(HANDSHAKE_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// A handler that sends every message back to the client
+pub fn echo(socket: &mut WebSocket) {
+    loop {
+        let result = match socket.read_message() {
+            Ok(Message::Close) => {
+                let _ = socket.send(&Message::Close);
+                return;
+            }
+            Ok(message) => socket.send(&message),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            debug!("WebSocket connection ended: {:#?}", e);
+            return;
+        }
+    }
+}
+
+fn protocol_error(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // The example handshake from RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_is_upgrade() {
+        let upgrade = |connection: &str| {
+            let raw = format!(
+                "GET /ws HTTP/1.1\r\nConnection: {}\r\nUpgrade: websocket\r\n\r\n",
+                connection
+            );
+            is_upgrade(&Request::parse_head(&mut raw.as_bytes()).unwrap())
+        };
+
+        assert!(upgrade("Upgrade"));
+        assert!(upgrade("keep-alive, Upgrade"));
+        assert!(!upgrade("keep-alive"));
+    }
+}